@@ -1,3 +1,5 @@
+extern crate chrono;
+extern crate futures;
 extern crate gotham;
 #[macro_use]
 extern crate gotham_derive;
@@ -12,24 +14,54 @@ extern crate serde;
 extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
+extern crate url;
 
-use hyper::StatusCode;
+use futures::{Future, Stream};
+use hyper::{Body, StatusCode};
+use std::collections::hash_map::DefaultHasher;
 use std::env;
+use std::hash::{Hash, Hasher};
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
 
 use ipc_channel::ipc;
 
+use gotham::handler::{HandlerFuture, IntoHandlerError};
 use gotham::http::response::create_response;
 use gotham::router::Router;
 use gotham::router::builder::*;
-use gotham::state::State;
+use gotham::state::{FromState, State};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message {
     GetTaskCount,
+    GetMatrixMessageCount,
+    GetLastTask,
+    GetTasks,
+    GetLastTaskUpdate,
+    GetComments,
+    GetIfModifiedSince,
+    GetNotModifiedCount,
+    GetMarkedNotifications,
+    GetAuthorizations,
     AddReviewRequest,
+    SetPullRequestTitle(String),
+    SetPullRequestLabels(Vec<String>),
+    SetCiState(String),
+    SetRateLimitRemaining(u64),
+    /// Turns down that many pull request fetches with a secondary rate limit
+    RejectPullRequests(usize),
+    /// Turns down that many pull request fetches with a bare 403, which only
+    /// says it's from the secondary rate limit in its message
+    ForbidPullRequests(usize),
+    /// Fails that many matrix messages with a bad gateway
+    FailMatrixMessages(usize),
+    /// Closes every pull request, which also leaves them out of searches
+    ClosePullRequests,
+    /// Fails the search for the page at that index
+    FailSearchPage(usize),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -39,6 +71,16 @@ pub enum Response {
         sender: ipc::IpcSender<Message>,
     },
     TaskCountResponse(usize),
+    MatrixMessageCountResponse(usize),
+    LastTaskResponse(Option<String>),
+    TasksResponse(Vec<String>),
+    LastTaskUpdateResponse(Option<(String, String)>),
+    CommentsResponse(Vec<String>),
+    IfModifiedSinceResponse(Vec<String>),
+    NotModifiedCountResponse(usize),
+    MarkedNotificationsResponse(Vec<String>),
+    /// Which Authorization header each endpoint was called with
+    AuthorizationsResponse(Vec<(String, String)>),
 }
 
 lazy_static! {
@@ -46,15 +88,27 @@ lazy_static! {
 }
 
 header! { (XPollInterval, "X-Poll-Interval") => [u64] }
+header! { (XRateLimitRemaining, "X-RateLimit-Remaining") => [u64] }
+header! { (XRateLimitReset, "X-RateLimit-Reset") => [u64] }
+
+/// Sent as Last-Modified on every notifications response
+pub const NOTIFICATIONS_LAST_MODIFIED: &str = "Wed, 13 Jun 2018 10:00:00 GMT";
 
 fn notifications(state: State) -> (State, hyper::Response) {
+    record_authorization(&state, "notifications");
+
+    if let Some(since) = hyper::Headers::borrow_from(&state).get::<hyper::header::IfModifiedSince>() {
+        IF_MODIFIED_SINCE.lock().unwrap().push(since.to_string());
+    }
+
     let count = REVIEW_REQUEST_COUNT.load(Ordering::Relaxed);
 
     let pull_requests: Vec<serde_json::Value> = (0..count)
         .map(|i| {
-            let pr_url = format!("http://{}/github/pull_requests/{}", &*ADDR, i);
+            let pr_url = format!("http://{}/github/repos/renato-zannon/reviewist/pulls/{}", &*ADDR, i);
 
             json!({
+                "id": i.to_string(),
                 "reason": "review_requested",
                 "subject": {
                     "title": "Some important PR",
@@ -64,6 +118,7 @@ fn notifications(state: State) -> (State, hyper::Response) {
 
                 "repository": {
                     "name": "reviewist",
+                    "full_name": "renato-zannon/reviewist",
                 }
             })
         })
@@ -73,65 +128,574 @@ fn notifications(state: State) -> (State, hyper::Response) {
 
     let mut res = create_response(&state, StatusCode::Ok, Some((response_body, mime::APPLICATION_JSON)));
 
+    let last_modified = NOTIFICATIONS_LAST_MODIFIED.parse().unwrap();
     res.headers_mut().set(XPollInterval(1));
+    res.headers_mut().set(hyper::header::LastModified(last_modified));
+
+    if let Some(remaining) = *RATE_LIMIT_REMAINING.lock().unwrap() {
+        let reset = chrono::Utc::now() + chrono::Duration::hours(1);
+        res.headers_mut().set(XRateLimitRemaining(remaining));
+        res.headers_mut().set(XRateLimitReset(reset.timestamp() as u64));
+    }
 
     (state, res)
 }
 
+/// Answers the review requests search with one pull request per page, to
+/// exercise pagination
+fn search(mut state: State) -> Box<HandlerFuture> {
+    let body = Body::take_from(&mut state).concat2().then(|full_body| match full_body {
+        Ok(body) => {
+            let request: serde_json::Value = serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null);
+
+            let index = request["variables"]["after"]
+                .as_str()
+                .and_then(|cursor| cursor.parse::<usize>().ok())
+                .map(|cursor| cursor + 1)
+                .unwrap_or(0);
+
+            if *FAILING_SEARCH_PAGE.lock().unwrap() == Some(index) {
+                let res = create_response(&state, StatusCode::BadGateway, None);
+                return Ok((state, res));
+            }
+
+            let count = if PULL_REQUESTS_CLOSED.load(Ordering::Relaxed) {
+                0
+            } else {
+                REVIEW_REQUEST_COUNT.load(Ordering::Relaxed)
+            };
+
+            let nodes: Vec<serde_json::Value> = (index..count)
+                .take(1)
+                .map(|i| {
+                    json!({
+                        "number": i,
+                        "repository": { "nameWithOwner": "renato-zannon/reviewist" },
+                    })
+                })
+                .collect();
+
+            let response_json = json!({
+                "data": {
+                    "search": {
+                        "pageInfo": {
+                            "hasNextPage": index + 1 < count,
+                            "endCursor": index.to_string(),
+                        },
+                        "nodes": nodes,
+                    },
+                },
+            });
+
+            Ok(json_response(state, response_json))
+        }
+
+        Err(err) => Err((state, err.into_handler_error())),
+    });
+
+    Box::new(body)
+}
+
 fn get_pull_request(state: State) -> (State, hyper::Response) {
+    record_authorization(&state, "pull_request");
+
+    let rejected = REJECTED_PULL_REQUESTS
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(1))
+        .is_ok();
+
+    if rejected {
+        let mut res = create_response(&state, StatusCode::TooManyRequests, None);
+        res.headers_mut().set(hyper::header::RetryAfter::Delay(std::time::Duration::from_secs(1)));
+        return (state, res);
+    }
+
+    let forbidden = FORBIDDEN_PULL_REQUESTS
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(1))
+        .is_ok();
+
+    if forbidden {
+        let message = json!({
+            "message": "You have exceeded a secondary rate limit. Please wait a few minutes before you try again.",
+        });
+        let response_body = serde_json::to_vec(&message).unwrap();
+
+        let res = create_response(&state, StatusCode::Forbidden, Some((response_body, mime::APPLICATION_JSON)));
+        return (state, res);
+    }
+
+    let etag = {
+        let PullRequestParams { id } = state.borrow();
+        pull_request_etag(*id)
+    };
+
+    let not_modified = match hyper::Headers::borrow_from(&state).get::<hyper::header::IfNoneMatch>() {
+        Some(hyper::header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.strong_eq(&etag)),
+        _ => false,
+    };
+
+    if not_modified {
+        NOT_MODIFIED_COUNT.fetch_add(1, Ordering::Relaxed);
+        let res = create_response(&state, StatusCode::NotModified, None);
+        return (state, res);
+    }
+
     let response_body = {
         let PullRequestParams { id, .. } = state.borrow();
 
+        let closed_at = if PULL_REQUESTS_CLOSED.load(Ordering::Relaxed) {
+            Some(chrono::Utc::now().to_rfc3339())
+        } else {
+            None
+        };
+
+        let labels: Vec<serde_json::Value> = PULL_REQUEST_LABELS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|name| json!({ "name": name }))
+            .collect();
+
         let response_json = json!({
             "number": id,
-            "title": "Some important PR",
+            "title": *PULL_REQUEST_TITLE.lock().unwrap(),
+            "url": format!("http://{}/github/repos/renato-zannon/reviewist/pulls/{}", &*ADDR, id),
             "html_url": "https://example.com",
+            "body": "Makes everything faster",
 
-            "created_at": "2018-01-01T00:00:00Z",
+            "created_at": chrono::Utc::now().to_rfc3339(),
             "merged_at": null,
-            "closed_at": null,
+            "closed_at": closed_at,
+            "additions": 100,
+            "deletions": 20,
+            "changed_files": 4,
+            "labels": labels,
+            "requested_reviewers": [{ "login": "reviewist" }],
             "base": {
+                "ref": "master",
                 "repo": {
                     "name": "reviewist",
+                    "full_name": "renato-zannon/reviewist",
                 },
             },
+            "head": {
+                "sha": "6dcb09b5b57875f334f61aebed695e2e4193db5e",
+            },
         });
 
         serde_json::to_vec(&response_json).unwrap()
     };
 
-    let res = create_response(&state, StatusCode::Ok, Some((response_body, mime::APPLICATION_JSON)));
+    let mut res = create_response(&state, StatusCode::Ok, Some((response_body, mime::APPLICATION_JSON)));
+    res.headers_mut().set(hyper::header::ETag(etag));
 
     (state, res)
 }
 
+/// Changes whenever something reviewist looks at changes, like the title
+fn pull_request_etag(id: i32) -> hyper::header::EntityTag {
+    let mut hasher = DefaultHasher::new();
+    id.hash(&mut hasher);
+    PULL_REQUEST_TITLE.lock().unwrap().hash(&mut hasher);
+    PULL_REQUEST_LABELS.lock().unwrap().hash(&mut hasher);
+    PULL_REQUESTS_CLOSED.load(Ordering::Relaxed).hash(&mut hasher);
+
+    hyper::header::EntityTag::strong(format!("{:x}", hasher.finish()))
+}
+
 #[derive(Deserialize, StateData, StaticResponseExtender)]
 struct PullRequestParams {
     id: i32,
 }
 
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct CommitParams {
+    owner: String,
+    repo: String,
+    sha: String,
+}
+
 lazy_static! {
     static ref TASK_COUNT: AtomicUsize = AtomicUsize::new(0);
+    static ref MATRIX_MESSAGE_COUNT: AtomicUsize = AtomicUsize::new(0);
     static ref REVIEW_REQUEST_COUNT: AtomicUsize = AtomicUsize::new(0);
+    static ref NOT_MODIFIED_COUNT: AtomicUsize = AtomicUsize::new(0);
+    static ref REJECTED_PULL_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+    static ref FORBIDDEN_PULL_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+    static ref FAILED_MATRIX_MESSAGES: AtomicUsize = AtomicUsize::new(0);
+    static ref PULL_REQUESTS_CLOSED: AtomicBool = AtomicBool::new(false);
+    static ref FAILING_SEARCH_PAGE: Mutex<Option<usize>> = Mutex::new(None);
+    static ref LAST_TASK: Mutex<Option<String>> = Mutex::new(None);
+    static ref TASKS: Mutex<Vec<String>> = Mutex::new(vec![]);
+    static ref LAST_TASK_UPDATE: Mutex<Option<(String, String)>> = Mutex::new(None);
+    static ref PULL_REQUEST_TITLE: Mutex<String> = Mutex::new("Some important PR".to_string());
+    static ref PULL_REQUEST_LABELS: Mutex<Vec<String>> = Mutex::new(vec![]);
+    static ref CI_STATE: Mutex<String> = Mutex::new("success".to_string());
+    static ref COMMENTS: Mutex<Vec<String>> = Mutex::new(vec![]);
+    static ref IF_MODIFIED_SINCE: Mutex<Vec<String>> = Mutex::new(vec![]);
+    static ref RATE_LIMIT_REMAINING: Mutex<Option<u64>> = Mutex::new(None);
+    static ref MARKED_NOTIFICATIONS: Mutex<Vec<String>> = Mutex::new(vec![]);
+    static ref AUTHORIZATIONS: Mutex<Vec<(String, String)>> = Mutex::new(vec![]);
+}
+
+fn record_authorization(state: &State, endpoint: &str) {
+    let authorization = hyper::Headers::borrow_from(state)
+        .get::<hyper::header::Authorization<String>>()
+        .map(|authorization| authorization.0.clone())
+        .unwrap_or_default();
+
+    AUTHORIZATIONS
+        .lock()
+        .unwrap()
+        .push((endpoint.to_string(), authorization));
+}
+
+/// Hands out installation tokens for anything that looks like a JWT
+fn create_installation_token(state: State) -> (State, hyper::Response) {
+    record_authorization(&state, "installation_token");
+
+    let is_jwt = match hyper::Headers::borrow_from(&state).get::<hyper::header::Authorization<String>>() {
+        Some(authorization) => authorization.0.starts_with("Bearer ") && authorization.0.split('.').count() == 3,
+        None => false,
+    };
+
+    if !is_jwt {
+        let res = create_response(&state, StatusCode::Unauthorized, None);
+        return (state, res);
+    }
+
+    let expires_at = chrono::Utc::now() + chrono::Duration::hours(1);
+    json_response(
+        state,
+        json!({
+            "token": "installation-token",
+            "expires_at": expires_at.to_rfc3339(),
+        }),
+    )
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct ThreadParams {
+    id: String,
+}
+
+fn mark_thread(state: State) -> (State, hyper::Response) {
+    let thread_id = ThreadParams::borrow_from(&state).id.clone();
+    MARKED_NOTIFICATIONS.lock().unwrap().push(thread_id);
+
+    let res = create_response(&state, StatusCode::ResetContent, None);
+    (state, res)
+}
+
+fn create_task(mut state: State) -> Box<HandlerFuture> {
+    record_authorization(&state, "task");
+
+    let body = Body::take_from(&mut state).concat2().then(|full_body| match full_body {
+        Ok(body) => {
+            let task_id = 1000 + TASK_COUNT.fetch_add(1, Ordering::Relaxed);
+            let task = String::from_utf8_lossy(&body).into_owned();
+            *LAST_TASK.lock().unwrap() = Some(task.clone());
+            TASKS.lock().unwrap().push(task);
+
+            Ok(json_response(state, json!({ "id": task_id })))
+        }
+
+        Err(err) => Err((state, err.into_handler_error())),
+    });
+
+    Box::new(body)
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct TaskParams {
+    id: String,
+}
+
+fn update_task(mut state: State) -> Box<HandlerFuture> {
+    let body = Body::take_from(&mut state).concat2().then(|full_body| match full_body {
+        Ok(body) => {
+            let task_id = TaskParams::borrow_from(&state).id.clone();
+            let update = String::from_utf8_lossy(&body).into_owned();
+            *LAST_TASK_UPDATE.lock().unwrap() = Some((task_id, update));
+
+            let res = create_response(&state, StatusCode::NoContent, None);
+            Ok((state, res))
+        }
+
+        Err(err) => Err((state, err.into_handler_error())),
+    });
+
+    Box::new(body)
+}
+
+fn create_comment(mut state: State) -> Box<HandlerFuture> {
+    let body = Body::take_from(&mut state).concat2().then(|full_body| match full_body {
+        Ok(body) => {
+            let comment: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let content = comment["content"].as_str().unwrap_or("").to_string();
+            COMMENTS.lock().unwrap().push(content);
+
+            Ok(json_response(state, json!({ "id": 500 })))
+        }
+
+        Err(err) => Err((state, err.into_handler_error())),
+    });
+
+    Box::new(body)
+}
+
+fn json_response(state: State, value: serde_json::Value) -> (State, hyper::Response) {
+    let response_body = serde_json::to_vec(&value).unwrap();
+    let res = create_response(&state, StatusCode::Ok, Some((response_body, mime::APPLICATION_JSON)));
+
+    (state, res)
+}
+
+fn get_user(state: State) -> (State, hyper::Response) {
+    record_authorization(&state, "user");
+    json_response(state, json!({ "login": "reviewist" }))
+}
+
+fn get_pull_request_files(state: State) -> (State, hyper::Response) {
+    json_response(
+        state,
+        json!([
+            { "filename": "src/lib.rs" },
+            { "filename": "src/main.rs" },
+        ]),
+    )
 }
 
-fn create_task(state: State) -> (State, hyper::Response) {
-    TASK_COUNT.fetch_add(1, Ordering::Relaxed);
+fn get_commit_status(state: State) -> (State, hyper::Response) {
+    let response_json = {
+        let CommitParams { owner, repo, sha } = state.borrow();
+        let ci_state = CI_STATE.lock().unwrap().clone();
 
-    let res = create_response(&state, StatusCode::Ok, Some((b"OK".to_vec(), mime::TEXT_PLAIN)));
+        json!({
+            "sha": sha,
+            "repository": { "full_name": format!("{}/{}", owner, repo) },
+            "state": ci_state,
+            "statuses": [{ "context": "ci/build", "state": ci_state }],
+        })
+    };
+
+    json_response(state, response_json)
+}
+
+fn get_check_runs(state: State) -> (State, hyper::Response) {
+    json_response(
+        state,
+        json!({
+            "total_count": 1,
+            "check_runs": [{ "name": "lint", "status": "completed", "conclusion": "success" }],
+        }),
+    )
+}
+
+fn get_projects(state: State) -> (State, hyper::Response) {
+    json_response(
+        state,
+        json!([
+            { "id": 100, "name": "Inbox" },
+            { "id": 101, "name": "Code Review" },
+        ]),
+    )
+}
+
+fn get_sections(state: State) -> (State, hyper::Response) {
+    json_response(
+        state,
+        json!([
+            { "id": 200, "project_id": 101, "name": "Incoming" },
+        ]),
+    )
+}
+
+fn get_labels(state: State) -> (State, hyper::Response) {
+    json_response(
+        state,
+        json!([
+            { "id": 300, "name": "review" },
+            { "id": 301, "name": "waiting" },
+        ]),
+    )
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct AuthorizeParams {
+    redirect_uri: String,
+    state: String,
+}
+
+/// Authorizes right away, sending back a code named after the provider
+fn authorize(state: State, provider: &str) -> (State, hyper::Response) {
+    let location = {
+        let params = AuthorizeParams::borrow_from(&state);
+        let mut redirect = url::Url::parse(&params.redirect_uri).unwrap();
+        redirect
+            .query_pairs_mut()
+            .append_pair("code", &format!("{}-code", provider))
+            .append_pair("state", &params.state);
+
+        redirect.into_string()
+    };
+
+    let mut res = create_response(&state, StatusCode::Found, None);
+    res.headers_mut().set(hyper::header::Location::new(location));
+    (state, res)
+}
+
+fn github_authorize(state: State) -> (State, hyper::Response) {
+    authorize(state, "github")
+}
+
+fn todoist_authorize(state: State) -> (State, hyper::Response) {
+    authorize(state, "todoist")
+}
+
+/// Trades any code for a token named after it
+fn create_access_token(mut state: State) -> Box<HandlerFuture> {
+    let body = Body::take_from(&mut state).concat2().then(|full_body| match full_body {
+        Ok(body) => {
+            let field = |name: &str| {
+                url::form_urlencoded::parse(&body)
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.into_owned())
+            };
+
+            match (field("code"), field("refresh_token")) {
+                (Some(code), _) => Ok(json_response(
+                    state,
+                    json!({ "access_token": format!("{}-token", code) }),
+                )),
+                (None, Some(refresh_token)) => Ok(json_response(
+                    state,
+                    json!({
+                        "access_token": format!("{}-token", refresh_token),
+                        "expires_in": 28800,
+                        "refresh_token": format!("{}-next", refresh_token),
+                    }),
+                )),
+                (None, None) => Ok(json_response(state, json!({ "error": "bad_verification_code" }))),
+            }
+        }
+
+        Err(err) => Err((state, err.into_handler_error())),
+    });
+
+    Box::new(body)
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct MatrixEventParams {
+    room_id: String,
+    event_type: String,
+    txn_id: String,
+}
+
+fn send_matrix_event(state: State) -> (State, hyper::Response) {
+    let response_body = {
+        let MatrixEventParams {
+            room_id,
+            event_type,
+            txn_id,
+        } = state.borrow();
+
+        if event_type == "m.room.message" {
+            let failed = FAILED_MATRIX_MESSAGES
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(1))
+                .is_ok();
+
+            if failed {
+                let res = create_response(&state, StatusCode::BadGateway, None);
+                return (state, res);
+            }
+
+            MATRIX_MESSAGE_COUNT.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let response_json = json!({
+            "event_id": format!("${}-{}", room_id, txn_id),
+        });
+
+        serde_json::to_vec(&response_json).unwrap()
+    };
+
+    let res = create_response(&state, StatusCode::Ok, Some((response_body, mime::APPLICATION_JSON)));
     (state, res)
 }
 
 fn router() -> Router {
     build_simple_router(|route| {
-        route.get("/github/notifications").to(notifications);
+        // The REST API is also served under the path GitHub Enterprise Server
+        // has it at, with its GraphQL endpoint next to it
+        for api in &["/github", "/ghe/api/v3"] {
+            route.get(&format!("{}/notifications", api)).to(notifications);
+            route.get(&format!("{}/user", api)).to(get_user);
+
+            route
+                .patch(&format!("{}/notifications/threads/:id", api))
+                .with_path_extractor::<ThreadParams>()
+                .to(mark_thread);
+
+            route
+                .delete(&format!("{}/notifications/threads/:id", api))
+                .with_path_extractor::<ThreadParams>()
+                .to(mark_thread);
+
+            route
+                .get(&format!("{}/repos/:owner/:repo/pulls/:id", api))
+                .with_path_extractor::<PullRequestParams>()
+                .to(get_pull_request);
+
+            route
+                .post(&format!("{}/app/installations/:id/access_tokens", api))
+                .to(create_installation_token);
+
+            route
+                .get(&format!("{}/repos/:owner/:repo/pulls/:id/files", api))
+                .with_path_extractor::<PullRequestParams>()
+                .to(get_pull_request_files);
+
+            route
+                .get(&format!("{}/repos/:owner/:repo/commits/:sha/status", api))
+                .with_path_extractor::<CommitParams>()
+                .to(get_commit_status);
+
+            route
+                .get(&format!("{}/repos/:owner/:repo/commits/:sha/check-runs", api))
+                .with_path_extractor::<CommitParams>()
+                .to(get_check_runs);
+        }
+
+        route.post("/github/graphql").to(search);
+        route.post("/ghe/api/graphql").to(search);
 
         route
-            .get("/github/pull_requests/:id")
-            .with_path_extractor::<PullRequestParams>()
-            .to(get_pull_request);
+            .get("/github/login/oauth/authorize")
+            .with_query_string_extractor::<AuthorizeParams>()
+            .to(github_authorize);
+        route.post("/github/login/oauth/access_token").to(create_access_token);
+
+        route
+            .get("/todoist/oauth/authorize")
+            .with_query_string_extractor::<AuthorizeParams>()
+            .to(todoist_authorize);
+        route.post("/todoist/oauth/access_token").to(create_access_token);
 
         route.post("/todoist/API/v8/tasks").to(create_task);
+        route
+            .post("/todoist/API/v8/tasks/:id")
+            .with_path_extractor::<TaskParams>()
+            .to(update_task);
+        route.post("/todoist/API/v8/comments").to(create_comment);
+        route.get("/todoist/API/v8/projects").to(get_projects);
+        route.get("/todoist/API/v8/sections").to(get_sections);
+        route.get("/todoist/API/v8/labels").to(get_labels);
+
+        route
+            .put("/matrix/_matrix/client/r0/rooms/:room_id/send/:event_type/:txn_id")
+            .with_path_extractor::<MatrixEventParams>()
+            .to(send_matrix_event);
     })
 }
 
@@ -170,9 +734,90 @@ fn process_messages(receiver: ipc::IpcReceiver<Message>, sender: ipc::IpcSender<
                 sender.send(Response::TaskCountResponse(value)).ok();
             }
 
+            Message::GetMatrixMessageCount => {
+                let value = MATRIX_MESSAGE_COUNT.load(Ordering::Relaxed);
+                sender.send(Response::MatrixMessageCountResponse(value)).ok();
+            }
+
+            Message::GetLastTask => {
+                let value = LAST_TASK.lock().unwrap().clone();
+                sender.send(Response::LastTaskResponse(value)).ok();
+            }
+
+            Message::GetTasks => {
+                let value = TASKS.lock().unwrap().clone();
+                sender.send(Response::TasksResponse(value)).ok();
+            }
+
+            Message::GetLastTaskUpdate => {
+                let value = LAST_TASK_UPDATE.lock().unwrap().clone();
+                sender.send(Response::LastTaskUpdateResponse(value)).ok();
+            }
+
+            Message::GetComments => {
+                let value = COMMENTS.lock().unwrap().clone();
+                sender.send(Response::CommentsResponse(value)).ok();
+            }
+
+            Message::GetIfModifiedSince => {
+                let value = IF_MODIFIED_SINCE.lock().unwrap().clone();
+                sender.send(Response::IfModifiedSinceResponse(value)).ok();
+            }
+
+            Message::GetNotModifiedCount => {
+                let value = NOT_MODIFIED_COUNT.load(Ordering::Relaxed);
+                sender.send(Response::NotModifiedCountResponse(value)).ok();
+            }
+
+            Message::GetMarkedNotifications => {
+                let value = MARKED_NOTIFICATIONS.lock().unwrap().clone();
+                sender.send(Response::MarkedNotificationsResponse(value)).ok();
+            }
+
+            Message::GetAuthorizations => {
+                let value = AUTHORIZATIONS.lock().unwrap().clone();
+                sender.send(Response::AuthorizationsResponse(value)).ok();
+            }
+
             Message::AddReviewRequest => {
                 REVIEW_REQUEST_COUNT.fetch_add(1, Ordering::Relaxed);
             }
+
+            Message::SetPullRequestTitle(title) => {
+                *PULL_REQUEST_TITLE.lock().unwrap() = title;
+            }
+
+            Message::SetPullRequestLabels(labels) => {
+                *PULL_REQUEST_LABELS.lock().unwrap() = labels;
+            }
+
+            Message::SetCiState(ci_state) => {
+                *CI_STATE.lock().unwrap() = ci_state;
+            }
+
+            Message::SetRateLimitRemaining(remaining) => {
+                *RATE_LIMIT_REMAINING.lock().unwrap() = Some(remaining);
+            }
+
+            Message::RejectPullRequests(count) => {
+                REJECTED_PULL_REQUESTS.store(count, Ordering::Relaxed);
+            }
+
+            Message::ForbidPullRequests(count) => {
+                FORBIDDEN_PULL_REQUESTS.store(count, Ordering::Relaxed);
+            }
+
+            Message::FailMatrixMessages(count) => {
+                FAILED_MATRIX_MESSAGES.store(count, Ordering::Relaxed);
+            }
+
+            Message::ClosePullRequests => {
+                PULL_REQUESTS_CLOSED.store(true, Ordering::Relaxed);
+            }
+
+            Message::FailSearchPage(index) => {
+                *FAILING_SEARCH_PAGE.lock().unwrap() = Some(index);
+            }
         }
     }
 }
@@ -0,0 +1,2 @@
+DROP TABLE deliveries;
+ALTER TABLE review_requests DROP COLUMN finished_at;
@@ -0,0 +1,12 @@
+CREATE TABLE deliveries (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  review_request_id INTEGER NOT NULL REFERENCES review_requests (id),
+
+  sink VARCHAR(50) NOT NULL,
+  external_id VARCHAR(255),
+
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+ALTER TABLE review_requests
+  ADD COLUMN finished_at TIMESTAMP;
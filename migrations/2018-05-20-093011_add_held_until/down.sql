@@ -0,0 +1,2 @@
+ALTER TABLE review_requests DROP COLUMN held_until;
+ALTER TABLE review_requests DROP COLUMN api_url;
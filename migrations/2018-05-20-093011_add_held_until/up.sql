@@ -0,0 +1,5 @@
+ALTER TABLE review_requests
+  ADD COLUMN api_url VARCHAR(255) NOT NULL DEFAULT '';
+
+ALTER TABLE review_requests
+  ADD COLUMN held_until TIMESTAMP;
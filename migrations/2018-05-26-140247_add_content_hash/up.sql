@@ -0,0 +1,2 @@
+ALTER TABLE review_requests
+  ADD COLUMN content_hash TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,2 @@
+ALTER TABLE review_requests
+  ADD COLUMN ci_state VARCHAR(20);
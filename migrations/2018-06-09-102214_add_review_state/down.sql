@@ -0,0 +1,2 @@
+ALTER TABLE review_requests DROP COLUMN ci_failing_since;
+ALTER TABLE review_requests DROP COLUMN state;
@@ -0,0 +1,7 @@
+ALTER TABLE review_requests
+  ADD COLUMN state VARCHAR(20) NOT NULL DEFAULT 'delivered';
+
+ALTER TABLE review_requests
+  ADD COLUMN ci_failing_since TIMESTAMP;
+
+UPDATE review_requests SET state = 'held' WHERE held_until IS NOT NULL;
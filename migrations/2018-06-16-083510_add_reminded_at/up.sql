@@ -0,0 +1,2 @@
+ALTER TABLE review_requests
+  ADD COLUMN reminded_at TIMESTAMP;
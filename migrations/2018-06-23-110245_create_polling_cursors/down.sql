@@ -0,0 +1 @@
+DROP TABLE polling_cursors;
@@ -0,0 +1,6 @@
+CREATE TABLE polling_cursors (
+  source VARCHAR(50) PRIMARY KEY NOT NULL,
+
+  last_modified VARCHAR(50) NOT NULL,
+  polled_at TIMESTAMP NOT NULL
+);
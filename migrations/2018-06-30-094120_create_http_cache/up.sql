@@ -0,0 +1,15 @@
+-- Cached responses are only good for the credentials they were fetched with
+CREATE TABLE http_cache (
+  account VARCHAR(255) NOT NULL,
+  url VARCHAR(255) NOT NULL,
+
+  etag VARCHAR(255),
+  last_modified VARCHAR(50),
+  body TEXT NOT NULL,
+
+  fetched_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  PRIMARY KEY (account, url)
+);
+
+CREATE INDEX http_cache_fetched_at ON http_cache (fetched_at);
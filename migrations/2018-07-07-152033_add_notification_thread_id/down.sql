@@ -0,0 +1 @@
+ALTER TABLE review_requests DROP COLUMN notification_thread_id;
@@ -0,0 +1,2 @@
+ALTER TABLE review_requests
+  ADD COLUMN notification_thread_id VARCHAR(50);
@@ -0,0 +1,2 @@
+ALTER TABLE review_requests
+  ADD COLUMN account VARCHAR(50) NOT NULL DEFAULT 'default';
@@ -0,0 +1,10 @@
+CREATE TABLE users (
+  id INTEGER PRIMARY KEY NOT NULL,
+  name VARCHAR(50) NOT NULL UNIQUE,
+
+  -- Encrypted with SECRET_KEY, missing until the user provides them
+  github_token TEXT,
+  todoist_token TEXT,
+
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
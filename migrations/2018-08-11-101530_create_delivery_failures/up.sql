@@ -0,0 +1,10 @@
+-- The sinks a review request didn't make it to, until a later attempt does
+CREATE TABLE delivery_failures (
+  review_request_id INTEGER NOT NULL REFERENCES review_requests (id),
+  sink VARCHAR(50) NOT NULL,
+
+  error TEXT NOT NULL,
+  failed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  PRIMARY KEY (review_request_id, sink)
+);
@@ -0,0 +1 @@
+DROP INDEX review_requests_account_project_pr_number;
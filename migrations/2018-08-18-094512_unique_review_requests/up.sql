@@ -0,0 +1,20 @@
+-- The same pull request could be recorded twice when it was handled twice at
+-- once. The oldest review request is kept and takes over the deliveries of
+-- the others, whose failures are dropped.
+UPDATE deliveries
+SET review_request_id = (
+  SELECT MIN(kept.id)
+  FROM review_requests kept, review_requests recorded
+  WHERE recorded.id = deliveries.review_request_id
+    AND kept.account = recorded.account
+    AND kept.project = recorded.project
+    AND kept.pr_number = recorded.pr_number
+);
+
+DELETE FROM delivery_failures
+WHERE review_request_id NOT IN (SELECT MIN(id) FROM review_requests GROUP BY account, project, pr_number);
+
+DELETE FROM review_requests
+WHERE id NOT IN (SELECT MIN(id) FROM review_requests GROUP BY account, project, pr_number);
+
+CREATE UNIQUE INDEX review_requests_account_project_pr_number ON review_requests (account, project, pr_number);
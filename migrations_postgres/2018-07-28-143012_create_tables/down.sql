@@ -0,0 +1,6 @@
+DROP TABLE users;
+DROP TABLE http_cache;
+DROP TABLE polling_cursors;
+DROP TABLE delivery_failures;
+DROP TABLE deliveries;
+DROP TABLE review_requests;
@@ -0,0 +1,79 @@
+-- The schema the SQLite migrations had built up to. SQLite never enforced the
+-- lengths of its VARCHAR columns, so only user names keep theirs.
+
+CREATE TABLE review_requests (
+  id SERIAL PRIMARY KEY,
+
+  project TEXT NOT NULL,
+  pr_number TEXT NOT NULL,
+  pr_url TEXT NOT NULL,
+
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  pr_title TEXT NOT NULL DEFAULT '',
+  finished_at TIMESTAMP,
+  api_url TEXT NOT NULL DEFAULT '',
+  held_until TIMESTAMP,
+  content_hash TEXT NOT NULL DEFAULT '',
+  ci_state TEXT,
+  state TEXT NOT NULL DEFAULT 'delivered',
+  ci_failing_since TIMESTAMP,
+  reminded_at TIMESTAMP,
+  notification_thread_id TEXT,
+  account TEXT NOT NULL DEFAULT 'default',
+
+  UNIQUE (account, project, pr_number)
+);
+
+CREATE TABLE deliveries (
+  id SERIAL PRIMARY KEY,
+  review_request_id INTEGER NOT NULL REFERENCES review_requests (id),
+
+  sink TEXT NOT NULL,
+  external_id TEXT,
+
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE delivery_failures (
+  review_request_id INTEGER NOT NULL REFERENCES review_requests (id),
+  sink TEXT NOT NULL,
+
+  error TEXT NOT NULL,
+  failed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  PRIMARY KEY (review_request_id, sink)
+);
+
+CREATE TABLE polling_cursors (
+  source TEXT PRIMARY KEY,
+
+  last_modified TEXT NOT NULL,
+  polled_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE http_cache (
+  account TEXT NOT NULL,
+  url TEXT NOT NULL,
+
+  etag TEXT,
+  last_modified TEXT,
+  body TEXT NOT NULL,
+
+  fetched_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  PRIMARY KEY (account, url)
+);
+
+CREATE INDEX http_cache_fetched_at ON http_cache (fetched_at);
+
+CREATE TABLE users (
+  id SERIAL PRIMARY KEY,
+  name VARCHAR(50) NOT NULL UNIQUE,
+
+  -- Encrypted with SECRET_KEY, missing until the user provides them
+  github_token TEXT,
+  todoist_token TEXT,
+
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -9,5 +9,4 @@ export GITHUB_TOKEN=$(vault read -field=token $VAULT_SECRETS_BASE/github)
 
 unset VAULT_TOKEN
 
-reviewist_migrate
 exec reviewist
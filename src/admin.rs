@@ -0,0 +1,543 @@
+use chrono::{self, Duration, NaiveDateTime, Utc};
+use failure::Error;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use slog::Logger;
+use std::fmt;
+use std::str::FromStr;
+
+use Config;
+use github::{GithubClient, PullRequest};
+use review_handler::{
+    self, Delivery, DeliveryFailure, Recorded, RequestKey, ReviewFilter, ReviewHandler, ReviewRecord, StoredReview,
+    TrackedRequest,
+};
+use sinks::{self, Sink};
+use working_hours::WorkingHours;
+
+/// A pull request as given on the command line, like `reviewist#12`. The
+/// owner can be included, like `octocat/reviewist#12`, to tell apart
+/// repositories with the same name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReviewRef {
+    pub owner: Option<String>,
+    pub repo: String,
+    pub number: String,
+}
+
+impl ReviewRef {
+    /// Whether the stored review request is for this pull request. Without an
+    /// owner, any owner matches.
+    fn matches(&self, stored: &StoredReview) -> bool {
+        match self.owner {
+            Some(ref owner) => stored.owner() == Some(owner.as_str()),
+            None => true,
+        }
+    }
+}
+
+impl FromStr for ReviewRef {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<ReviewRef, Error> {
+        let invalid = || format_err!("Pull requests must be given like repo#123, got {:?}", value);
+
+        let separator = value.rfind('#').ok_or_else(invalid)?;
+        let (name, number) = (&value[..separator], &value[separator + 1..]);
+
+        let (owner, repo) = match name.find('/') {
+            Some(slash) => (Some(&name[..slash]), &name[slash + 1..]),
+            None => (None, name),
+        };
+
+        if owner == Some("") || repo.is_empty() || repo.contains('/') || number.parse::<u64>().is_err() {
+            return Err(invalid());
+        }
+
+        Ok(ReviewRef {
+            owner: owner.map(str::to_string),
+            repo: repo.to_string(),
+            number: number.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for ReviewRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.owner {
+            Some(ref owner) => write!(f, "{}/{}#{}", owner, self.repo, self.number),
+            None => write!(f, "{}#{}", self.repo, self.number),
+        }
+    }
+}
+
+/// A review request as stored, along with how its delivery went.
+#[derive(Debug, Clone)]
+pub struct TrackedReview {
+    pub account: String,
+    pub pull_request: ReviewRef,
+    pub title: String,
+    pub url: String,
+    /// Like `delivered`, `held` or `waiting_ci`
+    pub state: String,
+    pub ci_state: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub held_until: Option<NaiveDateTime>,
+    pub finished_at: Option<NaiveDateTime>,
+    pub reminded_at: Option<NaiveDateTime>,
+    pub deliveries: Vec<Delivery>,
+    /// The sinks that the review request didn't make it to on its last try,
+    /// which `retry` delivers to again
+    pub failures: Vec<DeliveryFailure>,
+    request: TrackedRequest,
+}
+
+impl TrackedReview {
+    fn new(stored: StoredReview, deliveries: Vec<Delivery>, failures: Vec<DeliveryFailure>) -> TrackedReview {
+        let owner = stored.owner().map(str::to_string);
+
+        TrackedReview {
+            request: stored.tracked_request(),
+            account: stored.account,
+            pull_request: ReviewRef {
+                owner,
+                repo: stored.project,
+                number: stored.pr_number,
+            },
+            title: stored.pr_title,
+            url: stored.pr_url,
+            state: stored.state,
+            ci_state: stored.ci_state,
+            created_at: stored.created_at,
+            held_until: stored.held_until,
+            finished_at: stored.finished_at,
+            reminded_at: stored.reminded_at,
+            deliveries,
+            failures,
+        }
+    }
+
+    /// How long the review request has been waiting, or waited until it was
+    /// finished.
+    pub fn age(&self) -> Duration {
+        let until = self.finished_at.unwrap_or_else(|| Utc::now().naive_utc());
+        until.signed_duration_since(self.created_at)
+    }
+}
+
+/// An open pull request the user was asked to review, but that has no review
+/// request stored, like one that was requested before reviewist ran.
+#[derive(Debug, Clone)]
+pub struct MissedReview {
+    pub account: String,
+    pub pull_request: ReviewRef,
+    pub title: String,
+    pub url: String,
+}
+
+impl MissedReview {
+    fn of(pr: &PullRequest) -> MissedReview {
+        MissedReview {
+            account: pr.account.clone(),
+            pull_request: ReviewRef {
+                owner: pr.full_repo_name().split('/').next().map(str::to_string),
+                repo: pr.repo().to_string(),
+                number: pr.number.to_string(),
+            },
+            title: pr.title.clone(),
+            url: pr.html_url.clone(),
+        }
+    }
+}
+
+/// How creating the missed review requests went.
+#[derive(Debug, Clone)]
+pub struct BackfillReport {
+    /// How many review requests were created
+    pub created: usize,
+    /// The pull requests whose review request couldn't be recorded, or didn't
+    /// make it to any sink. Recorded ones are left to `retry`.
+    pub failed: Vec<ReviewRef>,
+}
+
+/// The missed review requests found by `find_missed_reviews`, ready to be
+/// created.
+pub struct Backfill {
+    pub reviews: Vec<MissedReview>,
+    pull_requests: Vec<PullRequest>,
+    github_clients: Vec<GithubClient>,
+    handler: ReviewHandler,
+    sinks: Vec<Sink>,
+    working_hours: Option<WorkingHours>,
+    wait_for_ci: Option<chrono::Duration>,
+    logger: Logger,
+}
+
+impl Backfill {
+    /// Records the missed review requests and delivers them like any new
+    /// review request, holding them or waiting for CI as configured.
+    pub fn create(self) -> impl Future<Item = BackfillReport, Error = Error> {
+        let Backfill {
+            reviews,
+            pull_requests,
+            github_clients,
+            handler,
+            sinks,
+            working_hours,
+            wait_for_ci,
+            logger,
+        } = self;
+
+        let now = Utc::now();
+
+        future::lazy(move || {
+            let requests: Vec<_> = pull_requests
+                .into_iter()
+                .zip(reviews)
+                .map(|(pr, review)| {
+                    let client = ::client_for(&github_clients, &pr.account);
+                    let logger = logger.new(o!("pull_request" => pr.number));
+                    let error_logger = logger.clone();
+                    let state = ::initial_state(working_hours.as_ref(), wait_for_ci, now);
+
+                    ::request_review(client, &handler, &sinks, pr, state, wait_for_ci, logger).then(move |result| {
+                        let created = result.unwrap_or_else(|err| {
+                            error!(error_logger, "Error while creating review request"; "error" => %err);
+                            false
+                        });
+
+                        Ok((review, created))
+                    })
+                })
+                .collect();
+
+            future::join_all(requests).map(|results| {
+                let created = results.iter().filter(|result| result.1).count();
+                let failed = results
+                    .into_iter()
+                    .filter(|result| !result.1)
+                    .map(|result| result.0.pull_request)
+                    .collect();
+
+                BackfillReport { created, failed }
+            })
+        })
+    }
+}
+
+/// Searches every account for the open pull requests it was asked to review,
+/// keeping the ones without a stored review request. Notifications only go a
+/// week back, which leaves older review requests out on a first run.
+pub fn find_missed_reviews(config: &Config) -> impl Future<Item = Backfill, Error = Error> {
+    let (handler, github_clients, sinks) = match prepare_clients(config) {
+        Ok(prepared) => prepared,
+        Err(err) => return Either::A(future::err(err)),
+    };
+
+    let working_hours = config.working_hours.clone();
+    let wait_for_ci = config.wait_for_ci;
+    let logger = config.logger.clone();
+
+    let found = future::lazy(move || {
+        let recorded = handler.recorded_requests();
+        let clients = github_clients.clone();
+
+        ::refresh_auth(&github_clients)
+            .and_then(move |_| {
+                let searches: Vec<_> = clients
+                    .iter()
+                    .map(|client| client.search_review_requests().collect())
+                    .collect();
+
+                future::join_all(searches).join(recorded)
+            })
+            .map(move |(found, recorded)| {
+                let mut pull_requests: Vec<PullRequest> = vec![];
+
+                for pr in found.into_iter().flatten() {
+                    let key = RequestKey::of(&pr);
+                    let known =
+                        recorded.contains(&key) || pull_requests.iter().any(|other| RequestKey::of(other) == key);
+
+                    if pr.is_open() && !known {
+                        pull_requests.push(pr);
+                    }
+                }
+
+                Backfill {
+                    reviews: pull_requests.iter().map(MissedReview::of).collect(),
+                    pull_requests,
+                    github_clients,
+                    handler,
+                    sinks,
+                    working_hours,
+                    wait_for_ci,
+                    logger,
+                }
+            })
+    });
+
+    Either::B(found)
+}
+
+/// Loads the unfinished review requests, or all of them.
+pub fn list_reviews(config: &Config, include_finished: bool) -> impl Future<Item = Vec<TrackedReview>, Error = Error> {
+    let filter = if include_finished {
+        ReviewFilter::All
+    } else {
+        ReviewFilter::Unfinished
+    };
+
+    load_reviews(config, filter)
+}
+
+/// Loads the review requests for a pull request, one per account that
+/// tracks it.
+pub fn show_review(config: &Config, review: &ReviewRef) -> impl Future<Item = Vec<TrackedReview>, Error = Error> {
+    let filter = ReviewFilter::PullRequest {
+        project: review.repo.clone(),
+        pr_number: review.number.clone(),
+    };
+
+    let review = review.clone();
+    load_reviews(config, filter).map(move |reviews| {
+        reviews
+            .into_iter()
+            .filter(|tracked| review.owner.is_none() || tracked.pull_request.owner == review.owner)
+            .collect()
+    })
+}
+
+fn load_reviews(config: &Config, filter: ReviewFilter) -> impl Future<Item = Vec<TrackedReview>, Error = Error> {
+    let handler = match review_handler::new(config) {
+        Ok(handler) => handler,
+        Err(err) => return Either::A(future::err(err)),
+    };
+
+    let reviews = future::lazy(move || handler.stored_reviews(filter)).map(|reviews| {
+        reviews
+            .into_iter()
+            .map(|(stored, deliveries, failures)| TrackedReview::new(stored, deliveries, failures))
+            .collect()
+    });
+
+    Either::B(reviews)
+}
+
+/// Fails if an unfinished review request failed to make it to a sink since
+/// `since`, like when a delivery failed during a single run. Failures from
+/// earlier runs are left alone, as well as retrying them, which is up to
+/// `retry`.
+pub fn check_deliveries(handler: &ReviewHandler, since: NaiveDateTime) -> impl Future<Item = (), Error = Error> {
+    handler
+        .stored_reviews(ReviewFilter::Unfinished)
+        .and_then(move |reviews| {
+            let undelivered: Vec<_> = reviews
+                .into_iter()
+                .map(|(stored, deliveries, failures)| TrackedReview::new(stored, deliveries, failures))
+                .filter(|review| review.failures.iter().any(|failure| failure.failed_at >= since))
+                .map(|review| review.pull_request.to_string())
+                .collect();
+
+            if undelivered.is_empty() {
+                Ok(())
+            } else {
+                Err(format_err!(
+                    "Review requests weren't delivered to every sink, retry them with `reviewist retry`: {}",
+                    undelivered.join(", ")
+                ))
+            }
+        })
+}
+
+/// Deletes what is stored about a pull request, so that its review request is
+/// recorded and delivered anew the next time it's polled. Returns how many
+/// review requests were deleted. A pull request given without its owner must
+/// only be tracked for one repository of that name.
+pub fn forget_review(config: &Config, review: &ReviewRef) -> impl Future<Item = usize, Error = Error> {
+    let handler = match review_handler::new(config) {
+        Ok(handler) => handler,
+        Err(err) => return Either::A(future::err(err)),
+    };
+
+    let review = review.clone();
+    let filter = ReviewFilter::PullRequest {
+        project: review.repo.clone(),
+        pr_number: review.number.clone(),
+    };
+
+    let forgotten = future::lazy(move || handler.stored_reviews(filter).map(move |reviews| (handler, reviews)))
+        .and_then(move |(handler, reviews)| {
+            let matching: Vec<_> = reviews
+                .into_iter()
+                .map(|(stored, _, _)| stored)
+                .filter(|stored| review.matches(stored))
+                .collect();
+
+            let mut owners: Vec<_> = matching.iter().map(|stored| stored.owner().unwrap_or("")).collect();
+            owners.sort();
+            owners.dedup();
+
+            if owners.len() > 1 {
+                return Either::A(future::err(format_err!(
+                    "{} is tracked for several owners ({}), give it like owner/{}",
+                    review,
+                    owners.join(", "),
+                    review
+                )));
+            }
+
+            let ids = matching.iter().map(|stored| stored.id).collect();
+            Either::B(handler.forget_reviews(ids))
+        });
+
+    Either::B(forgotten)
+}
+
+/// Delivers the unfinished review requests again to the sinks they didn't
+/// make it to. Pull requests that were closed in the meantime are finished
+/// instead. Returns how many review requests were retried.
+pub fn retry_deliveries(config: &Config) -> impl Future<Item = usize, Error = Error> {
+    let logger = config.logger.clone();
+
+    with_clients(config, move |github_clients, handler, sinks, reviews| {
+        let retries: Vec<_> = reviews
+            .into_iter()
+            .map(|(stored, deliveries, failures)| TrackedReview::new(stored, deliveries, failures))
+            .filter(|review| !review.failures.is_empty())
+            .map(|review| {
+                let failed_sinks: Vec<_> = sinks
+                    .iter()
+                    .filter(|sink| review.failures.iter().any(|failure| failure.sink == sink.name()))
+                    .cloned()
+                    .collect();
+
+                let logger = logger.new(o!("review_request" => review.request.id));
+                let client = ::client_for(github_clients, &review.request.account);
+                retry_review(client, handler, sinks, failed_sinks, review.request, logger)
+            })
+            .collect();
+
+        let retried = retries.len();
+        future::join_all(retries).map(move |_| retried)
+    })
+}
+
+fn retry_review(
+    github_client: &GithubClient,
+    handler: &ReviewHandler,
+    sinks: &[Sink],
+    failed_sinks: Vec<Sink>,
+    request: TrackedRequest,
+    logger: Logger,
+) -> impl Future<Item = (), Error = Error> {
+    let client = github_client.clone();
+    let handler = handler.clone();
+    let sinks = sinks.to_vec();
+
+    ::fetch_tracked_pull_request(github_client, &request).then(move |result| {
+        let pr = match result {
+            Ok(pr) => pr,
+
+            Err(err) => {
+                error!(logger, "Unable to fetch pull request"; "error" => %err);
+                return Either::A(future::ok(()));
+            }
+        };
+
+        let logger = logger.new(o!("pull_request" => pr.number));
+
+        if !pr.is_open() {
+            return Either::B(Either::A(::finish_review(&handler, &sinks, pr, logger)));
+        }
+
+        info!(logger, "Retrying delivery");
+
+        let recorded = Recorded::Stored(request.id);
+        let delivery = ::deliver_review_request(&client, &handler, &failed_sinks, recorded, pr, None, logger);
+        Either::B(Either::B(delivery.map(|_| ())))
+    })
+}
+
+/// Checks every unfinished review request against its pull request once:
+/// closed pull requests are finished, and deliveries of changed ones are
+/// updated. Returns how many review requests were checked.
+pub fn sync_reviews(config: &Config) -> impl Future<Item = usize, Error = Error> {
+    let logger = config.logger.clone();
+
+    with_clients(config, move |github_clients, handler, sinks, reviews| {
+        let syncs: Vec<_> = reviews
+            .into_iter()
+            .map(|(stored, _, _)| {
+                let request = stored.tracked_request();
+                let logger = logger.new(o!("review_request" => request.id));
+                let client = ::client_for(github_clients, &request.account);
+                sync_review(client, handler, sinks, request, logger)
+            })
+            .collect();
+
+        let synced = syncs.len();
+        future::join_all(syncs).map(move |_| synced)
+    })
+}
+
+fn sync_review(
+    github_client: &GithubClient,
+    handler: &ReviewHandler,
+    sinks: &[Sink],
+    request: TrackedRequest,
+    logger: Logger,
+) -> impl Future<Item = (), Error = Error> {
+    let handler = handler.clone();
+    let sinks = sinks.to_vec();
+
+    ::fetch_tracked_pull_request(github_client, &request).then(move |result| {
+        let pr = match result {
+            Ok(pr) => pr,
+
+            Err(err) => {
+                error!(logger, "Unable to fetch pull request"; "error" => %err);
+                return Either::A(future::ok(()));
+            }
+        };
+
+        let logger = logger.new(o!("pull_request" => pr.number));
+
+        if pr.is_open() {
+            Either::B(Either::A(::sync_review(&handler, &sinks, pr, logger)))
+        } else {
+            Either::B(Either::B(::finish_review(&handler, &sinks, pr, logger)))
+        }
+    })
+}
+
+/// Runs `operation` with what's needed to act on the unfinished review
+/// requests: the GitHub clients, ready to make requests, and the sinks.
+fn with_clients<F, R>(config: &Config, operation: F) -> impl Future<Item = R::Item, Error = Error>
+where
+    F: FnOnce(&[GithubClient], &ReviewHandler, &[Sink], Vec<ReviewRecord>) -> R,
+    R: IntoFuture<Error = Error>,
+{
+    let (handler, github_clients, sinks) = match prepare_clients(config) {
+        Ok(prepared) => prepared,
+        Err(err) => return Either::A(future::err(err)),
+    };
+
+    let done = future::lazy(move || {
+        let reviews = handler.stored_reviews(ReviewFilter::Unfinished);
+
+        ::refresh_auth(&github_clients)
+            .join(reviews)
+            .and_then(move |(_, reviews)| operation(&github_clients, &handler, &sinks, reviews))
+    });
+
+    Either::B(done)
+}
+
+fn prepare_clients(config: &Config) -> Result<(ReviewHandler, Vec<GithubClient>, Vec<Sink>), Error> {
+    let handler = review_handler::new(config)?;
+    let github_clients = ::github_clients(config, &handler)?;
+    let sinks = sinks::from_config(config)?;
+
+    Ok((handler, github_clients, sinks))
+}
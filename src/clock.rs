@@ -0,0 +1,19 @@
+use chrono::{DateTime, Utc};
+
+/// Tells the current time where it decides what happens to review requests,
+/// like whether they're held or when their tasks are due. It can be fixed to
+/// a given moment, so that the outcome doesn't depend on when it runs.
+#[derive(Clone, Copy, Debug)]
+pub enum Clock {
+    System,
+    Fixed(DateTime<Utc>),
+}
+
+impl Clock {
+    pub fn now(&self) -> DateTime<Utc> {
+        match *self {
+            Clock::System => Utc::now(),
+            Clock::Fixed(at) => at,
+        }
+    }
+}
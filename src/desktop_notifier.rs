@@ -0,0 +1,131 @@
+use failure::Error;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use futures::sync::oneshot;
+use slog::Logger;
+use std::thread;
+
+use Config;
+use dry_run::DryRun;
+use github::PullRequest;
+use review_handler::OpenRequest;
+
+/// Shows freedesktop notifications over D-Bus. Each notification gets a
+/// thread of its own, which waits for the user to act on it and opens the
+/// pull request in the browser if asked to.
+#[derive(Clone)]
+pub struct DesktopNotifier {
+    logger: Logger,
+    dry_run: Option<DryRun>,
+}
+
+/// A notification as it would be shown, in a dry run
+#[derive(Serialize)]
+struct SkippedNotification<'a> {
+    summary: &'a str,
+    body: &'a str,
+    url: &'a str,
+}
+
+type ShowResult = oneshot::Sender<Result<(), Error>>;
+
+impl DesktopNotifier {
+    pub fn new(config: &Config) -> Result<DesktopNotifier, Error> {
+        if !cfg!(feature = "desktop-notifications") {
+            return Err(format_err!(
+                "Desktop notifications were requested, but reviewist was built without the desktop-notifications feature"
+            ));
+        }
+
+        Ok(DesktopNotifier {
+            logger: config.logger.clone(),
+            dry_run: config.dry_run.clone(),
+        })
+    }
+
+    pub fn notify_review_request(&self, pr: &PullRequest, note: Option<&str>) -> impl Future<Item = (), Error = Error> {
+        let summary = format!("Review requested: {}#{}", pr.repo(), pr.number);
+        let body = match note {
+            Some(note) => format!("{}\n{}", pr.title, note),
+            None => pr.title.clone(),
+        };
+
+        self.notify(summary, body, pr.html_url.clone())
+    }
+
+    pub fn notify_reminder(&self, request: &OpenRequest) -> impl Future<Item = (), Error = Error> {
+        let summary = format!("Review still pending: {}#{}", request.project, request.pr_number);
+        let body = format!(
+            "{}\nRequested on {}",
+            request.pr_title,
+            request.created_at.format("%Y-%m-%d")
+        );
+
+        self.notify(summary, body, request.pr_url.clone())
+    }
+
+    fn notify(&self, summary: String, body: String, url: String) -> impl Future<Item = (), Error = Error> {
+        if let Some(ref dry_run) = self.dry_run {
+            let notification = SkippedNotification {
+                summary: &summary,
+                body: &body,
+                url: &url,
+            };
+
+            dry_run.record("show desktop notification", &notification);
+            return Either::A(future::ok(()));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let logger = self.logger.clone();
+
+        thread::spawn(move || show_and_wait(summary, body, url, logger, sender));
+
+        Either::B(receiver.map_err(Error::from).and_then(future::result))
+    }
+}
+
+#[cfg(feature = "desktop-notifications")]
+fn show_and_wait(summary: String, body: String, url: String, logger: Logger, sender: ShowResult) {
+    use notify_rust::Notification;
+    use std::process::Command;
+
+    const OPEN_ACTION: &str = "default";
+
+    let shown = Notification::new()
+        .appname("reviewist")
+        .summary(&summary)
+        .body(&body)
+        .action(OPEN_ACTION, "Open pull request")
+        .show();
+
+    let handle = match shown {
+        Ok(handle) => {
+            sender.send(Ok(())).ok();
+            handle
+        }
+
+        Err(err) => {
+            sender
+                .send(Err(format_err!("Error while showing desktop notification: {}", err)))
+                .ok();
+            return;
+        }
+    };
+
+    handle.wait_for_action(|action| {
+        if action != OPEN_ACTION {
+            return;
+        }
+
+        if let Err(err) = Command::new("xdg-open").arg(&url).spawn() {
+            warn!(logger, "Unable to open pull request"; "url" => &url, "error" => %err);
+        }
+    });
+}
+
+#[cfg(not(feature = "desktop-notifications"))]
+fn show_and_wait(_summary: String, _body: String, _url: String, _logger: Logger, sender: ShowResult) {
+    let error = format_err!("reviewist was built without desktop notification support");
+    sender.send(Err(error)).ok();
+}
@@ -0,0 +1,46 @@
+use serde::Serialize;
+use serde_json;
+use slog::Logger;
+use std::sync::{Arc, Mutex};
+
+/// Stands in for the ids of what a dry run would have created, like tasks
+pub const PLACEHOLDER_ID: &str = "dry-run";
+
+/// Keeps a run from changing anything: the sinks, GitHub notifications and
+/// the database are only read from, while every change that would have been
+/// made is logged along with what would have been sent, and counted for a
+/// summary at the end.
+#[derive(Clone)]
+pub struct DryRun {
+    logger: Logger,
+    /// How many times each action was skipped, in the order they came up
+    actions: Arc<Mutex<Vec<(String, usize)>>>,
+}
+
+impl DryRun {
+    pub fn new(logger: &Logger) -> DryRun {
+        DryRun {
+            logger: logger.new(o!("dry_run" => true)),
+            actions: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Notes that `action`, like `create todoist task`, was skipped. The
+    /// payload is what would have been sent or stored.
+    pub fn record<T: Serialize>(&self, action: &str, payload: &T) {
+        let payload = serde_json::to_string(payload).unwrap_or_else(|err| format!("<{}>", err));
+        info!(self.logger, "Would {}", action; "payload" => payload);
+
+        let mut actions = self.actions.lock().unwrap();
+        match actions.iter_mut().find(|counted| counted.0 == action) {
+            Some(counted) => counted.1 += 1,
+            None => actions.push((action.to_string(), 1)),
+        }
+    }
+
+    /// The skipped actions along with how many times each came up, in the
+    /// order they first did.
+    pub fn summary(&self) -> Vec<(String, usize)> {
+        self.actions.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,76 @@
+use std::env;
+
+use github::auth::GithubApp;
+
+/// The account review requests belong to when only one is configured
+pub const DEFAULT_ACCOUNT: &str = "default";
+
+#[derive(Clone, Debug)]
+pub enum GithubCredentials {
+    Token(String),
+    App(GithubApp),
+}
+
+/// A GitHub identity whose review requests are tracked. Each account is
+/// polled on its own, and its review requests are kept apart from those of
+/// the other accounts, even for the same pull request.
+#[derive(Clone, Debug)]
+pub struct GithubAccount {
+    pub name: String,
+    pub credentials: GithubCredentials,
+    /// The Todoist project for this account's tasks, instead of the
+    /// configured one
+    pub todoist_project: Option<String>,
+}
+
+impl GithubAccount {
+    pub fn new(name: &str, credentials: GithubCredentials) -> GithubAccount {
+        GithubAccount {
+            name: name.to_string(),
+            credentials,
+            todoist_project: None,
+        }
+    }
+
+    /// Reads the accounts listed in `GITHUB_ACCOUNTS`, like `work,personal`,
+    /// each with its token in `GITHUB_TOKEN_<NAME>` and optionally a project
+    /// in `TODOIST_PROJECT_<NAME>`. Without a list, there is a single account
+    /// using the GitHub App if configured, or else `GITHUB_TOKEN` if set.
+    pub fn from_env() -> Vec<GithubAccount> {
+        let names = match env::var("GITHUB_ACCOUNTS") {
+            Ok(names) => names,
+            Err(_) => return default_account_from_env().into_iter().collect(),
+        };
+
+        let accounts: Vec<_> = names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                let suffix = name.to_uppercase();
+                let token_var = format!("GITHUB_TOKEN_{}", suffix);
+                let token = env::var(&token_var).unwrap_or_else(|_| panic!("{} must be set", token_var));
+
+                GithubAccount {
+                    todoist_project: env::var(format!("TODOIST_PROJECT_{}", suffix)).ok(),
+                    ..GithubAccount::new(name, GithubCredentials::Token(token))
+                }
+            })
+            .collect();
+
+        if accounts.is_empty() {
+            panic!("GITHUB_ACCOUNTS must list at least one account");
+        }
+
+        accounts
+    }
+}
+
+fn default_account_from_env() -> Option<GithubAccount> {
+    let credentials = match GithubApp::from_env() {
+        Some(app) => GithubCredentials::App(app),
+        None => GithubCredentials::Token(env::var("GITHUB_TOKEN").ok()?),
+    };
+
+    Some(GithubAccount::new(DEFAULT_ACCOUNT, credentials))
+}
@@ -0,0 +1,365 @@
+use base64;
+use chrono::{DateTime, Duration, Utc};
+use failure::Error;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use reqwest::header::{Accept, Authorization, qitem};
+use reqwest::unstable::async::Client;
+use serde_json;
+use slog::Logger;
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use url::Url;
+
+/// Media type needed for the GitHub Apps API while it's in preview
+const APPS_PREVIEW: &str = "application/vnd.github.machine-man-preview+json";
+/// How long the JWTs that authenticate as the app are valid. GitHub allows
+/// ten minutes at most.
+const JWT_LIFETIME_SECS: i64 = 9 * 60;
+/// Installation tokens are refreshed once they're this close to expiring, so
+/// that they don't expire between refreshes
+const TOKEN_REFRESH_MARGIN_SECS: i64 = 5 * 60;
+/// How long user-to-server tokens are valid, when GitHub doesn't say
+const DEFAULT_USER_TOKEN_LIFETIME_SECS: i64 = 8 * 60 * 60;
+
+/// Credentials of a GitHub App installed on the repositories under review,
+/// used instead of `GITHUB_TOKEN`
+#[derive(Clone, Debug)]
+pub struct GithubApp {
+    pub app_id: u64,
+    pub installation_id: u64,
+    /// The app's private key, in PEM
+    pub private_key: String,
+    /// How to act as the user whose reviews are tracked. The notifications
+    /// and the review requests search need to, which installation tokens
+    /// can't.
+    pub user: UserCredentials,
+}
+
+#[derive(Clone, Debug)]
+pub enum UserCredentials {
+    /// A user-to-server token that doesn't expire, for apps that opted out of
+    /// expiring user tokens
+    Token(String),
+    /// Expiring user-to-server tokens, exchanged with a refresh token
+    Refreshed(UserTokenRefresh),
+}
+
+/// What's needed to get new user-to-server tokens. GitHub hands out a new
+/// refresh token with each of them and invalidates the old one, so the
+/// refresh token is kept in a file that's rewritten every time.
+#[derive(Clone, Debug)]
+pub struct UserTokenRefresh {
+    pub client_id: String,
+    pub client_secret: String,
+    /// Where GitHub's OAuth endpoints are, like `https://github.com/`
+    pub oauth_base: Url,
+    pub refresh_token_path: PathBuf,
+}
+
+impl GithubApp {
+    /// Reads the app credentials, enabled by setting `GITHUB_APP_ID`.
+    pub fn from_env() -> Option<GithubApp> {
+        let app_id = env::var("GITHUB_APP_ID")
+            .ok()?
+            .parse()
+            .expect("GITHUB_APP_ID must be a number");
+
+        let installation_id = env::var("GITHUB_APP_INSTALLATION_ID")
+            .expect("GITHUB_APP_INSTALLATION_ID must be set")
+            .parse()
+            .expect("GITHUB_APP_INSTALLATION_ID must be a number");
+
+        let key_path = env::var("GITHUB_APP_PRIVATE_KEY_PATH").expect("GITHUB_APP_PRIVATE_KEY_PATH must be set");
+        let private_key = fs::read_to_string(&key_path).expect("GITHUB_APP_PRIVATE_KEY_PATH must be a readable file");
+
+        Some(GithubApp {
+            app_id,
+            installation_id,
+            private_key,
+            user: UserCredentials::from_env(),
+        })
+    }
+}
+
+impl UserCredentials {
+    /// Reads the refresh token settings if `GITHUB_USER_REFRESH_TOKEN_PATH`
+    /// is set, using the app's OAuth credentials in `GITHUB_CLIENT_ID` and
+    /// `GITHUB_CLIENT_SECRET`. Otherwise the token in `GITHUB_USER_TOKEN` is
+    /// used as is.
+    fn from_env() -> UserCredentials {
+        let refresh_token_path = match env::var("GITHUB_USER_REFRESH_TOKEN_PATH") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => {
+                let token = env::var("GITHUB_USER_TOKEN")
+                    .expect("GITHUB_USER_TOKEN or GITHUB_USER_REFRESH_TOKEN_PATH must be set for a GitHub App");
+                return UserCredentials::Token(token);
+            }
+        };
+
+        let oauth_base = env::var("GITHUB_OAUTH_URL").unwrap_or_else(|_| "https://github.com/".to_string());
+
+        UserCredentials::Refreshed(UserTokenRefresh {
+            client_id: env::var("GITHUB_CLIENT_ID").expect("GITHUB_CLIENT_ID must be set to refresh user tokens"),
+            client_secret: env::var("GITHUB_CLIENT_SECRET")
+                .expect("GITHUB_CLIENT_SECRET must be set to refresh user tokens"),
+            oauth_base: Url::parse(&oauth_base).expect("GITHUB_OAUTH_URL must be a valid URL"),
+            refresh_token_path,
+        })
+    }
+}
+
+/// How requests are authorized: with a fixed token, with installation
+/// tokens or with expiring user tokens. Those have to be refreshed before
+/// they expire, which is up to whoever calls `refresh`.
+#[derive(Clone)]
+pub enum Auth {
+    Token(String),
+    Installation(Rc<InstallationTokens>),
+    User(Rc<UserTokens>),
+}
+
+impl Auth {
+    pub fn installation(app: &GithubApp, http: &Client, host: &Url, logger: Logger) -> Result<Auth, Error> {
+        let token_url = host.join(&format!("app/installations/{}/access_tokens", app.installation_id))?;
+
+        let tokens = InstallationTokens {
+            app_id: app.app_id,
+            private_key: PKey::private_key_from_pem(app.private_key.as_bytes())?,
+            token_url,
+            http: http.clone(),
+            current: RefCell::new(None),
+            logger,
+        };
+
+        Ok(Auth::Installation(Rc::new(tokens)))
+    }
+
+    pub fn user(app: &GithubApp, http: &Client, logger: Logger) -> Result<Auth, Error> {
+        let refresh = match app.user {
+            UserCredentials::Token(ref token) => return Ok(Auth::Token(token.clone())),
+            UserCredentials::Refreshed(ref refresh) => refresh,
+        };
+
+        let tokens = UserTokens {
+            token_url: refresh.oauth_base.join("login/oauth/access_token")?,
+            refresh: refresh.clone(),
+            http: http.clone(),
+            current: RefCell::new(None),
+            logger,
+        };
+
+        Ok(Auth::User(Rc::new(tokens)))
+    }
+
+    /// The Authorization header for the next request. Fails if no token was
+    /// fetched yet.
+    pub fn authorization(&self) -> Result<Authorization<String>, Error> {
+        let token = match *self {
+            Auth::Token(ref token) => token.clone(),
+
+            Auth::Installation(ref tokens) => match *tokens.current.borrow() {
+                Some(ref current) => current.token.clone(),
+                None => return Err(format_err!("No installation token was fetched yet")),
+            },
+
+            Auth::User(ref tokens) => match *tokens.current.borrow() {
+                Some(ref current) => current.token.clone(),
+                None => return Err(format_err!("No user token was fetched yet")),
+            },
+        };
+
+        Ok(Authorization(format!("token {}", token)))
+    }
+
+    /// Exchanges a new token if there is none yet, or if the current one is
+    /// about to expire.
+    pub fn refresh(&self) -> impl Future<Item = (), Error = Error> {
+        match *self {
+            Auth::Installation(ref tokens) => Either::A(refresh_installation_token(tokens)),
+            Auth::User(ref tokens) => Either::B(Either::A(refresh_user_token(tokens))),
+            Auth::Token(_) => Either::B(Either::B(future::ok(()))),
+        }
+    }
+}
+
+pub struct InstallationTokens {
+    app_id: u64,
+    private_key: PKey,
+    token_url: Url,
+    http: Client,
+    current: RefCell<Option<InstallationToken>>,
+    logger: Logger,
+}
+
+#[derive(Deserialize)]
+struct InstallationToken {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct JwtClaims {
+    iat: i64,
+    exp: i64,
+    iss: u64,
+}
+
+fn refresh_installation_token(tokens: &Rc<InstallationTokens>) -> impl Future<Item = (), Error = Error> {
+    let refresh_at = Utc::now() + Duration::seconds(TOKEN_REFRESH_MARGIN_SECS);
+
+    match *tokens.current.borrow() {
+        Some(ref current) if current.expires_at > refresh_at => return Either::A(future::ok(())),
+        _ => {}
+    }
+
+    let jwt = match tokens.jwt() {
+        Ok(jwt) => jwt,
+        Err(err) => return Either::A(future::err(err)),
+    };
+
+    let mut request = tokens.http.post(tokens.token_url.clone());
+    request
+        .header(Authorization(format!("Bearer {}", jwt)))
+        .header(Accept(vec![qitem(APPS_PREVIEW.parse().unwrap())]));
+
+    let request = match request.build() {
+        Ok(request) => request,
+        Err(err) => return Either::A(future::err(Error::from(err))),
+    };
+
+    let tokens = tokens.clone();
+
+    let refresh = tokens
+        .http
+        .execute(request)
+        .and_then(|response| response.error_for_status())
+        .and_then(|mut response| response.json::<InstallationToken>())
+        .map_err(Error::from)
+        .map(move |token| {
+            debug!(tokens.logger, "Got a new installation token"; "expires_at" => %token.expires_at);
+
+            *tokens.current.borrow_mut() = Some(token);
+        });
+
+    Either::B(refresh)
+}
+
+pub struct UserTokens {
+    refresh: UserTokenRefresh,
+    token_url: Url,
+    http: Client,
+    current: RefCell<Option<UserToken>>,
+    logger: Logger,
+}
+
+struct UserToken {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// GitHub answers with an error in the body rather than the status when the
+/// refresh token is rejected
+#[derive(Deserialize)]
+struct UserTokenResponse {
+    access_token: Option<String>,
+    expires_in: Option<i64>,
+    refresh_token: Option<String>,
+    error: Option<String>,
+}
+
+fn refresh_user_token(tokens: &Rc<UserTokens>) -> impl Future<Item = (), Error = Error> {
+    let refresh_at = Utc::now() + Duration::seconds(TOKEN_REFRESH_MARGIN_SECS);
+
+    match *tokens.current.borrow() {
+        Some(ref current) if current.expires_at > refresh_at => return Either::A(future::ok(())),
+        _ => {}
+    }
+
+    let refresh_token = match fs::read_to_string(&tokens.refresh.refresh_token_path) {
+        Ok(refresh_token) => refresh_token.trim().to_string(),
+        Err(err) => return Either::A(future::err(Error::from(err))),
+    };
+
+    let form = [
+        ("client_id", tokens.refresh.client_id.clone()),
+        ("client_secret", tokens.refresh.client_secret.clone()),
+        ("grant_type", "refresh_token".to_string()),
+        ("refresh_token", refresh_token),
+    ];
+
+    let tokens = tokens.clone();
+
+    // GitHub answers with a form unless asked for JSON
+    let refresh = tokens
+        .http
+        .post(tokens.token_url.clone())
+        .header(Accept::json())
+        .form(&form)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|mut response| response.json::<UserTokenResponse>())
+        .map_err(Error::from)
+        .and_then(move |response| {
+            let lifetime = response.expires_in.unwrap_or(DEFAULT_USER_TOKEN_LIFETIME_SECS);
+
+            let (token, refresh_token) = match response {
+                UserTokenResponse {
+                    access_token: Some(token),
+                    refresh_token: Some(refresh_token),
+                    ..
+                } => (token, refresh_token),
+
+                UserTokenResponse { error, .. } => {
+                    return Err(format_err!(
+                        "No user token in the response, error: {}",
+                        error.unwrap_or_default()
+                    ))
+                }
+            };
+
+            // The refresh token that was just used is no good anymore
+            fs::write(&tokens.refresh.refresh_token_path, &refresh_token)?;
+
+            let expires_at = Utc::now() + Duration::seconds(lifetime);
+            debug!(tokens.logger, "Got a new user token"; "expires_at" => %expires_at);
+
+            *tokens.current.borrow_mut() = Some(UserToken { token, expires_at });
+            Ok(())
+        });
+
+    Either::B(refresh)
+}
+
+impl InstallationTokens {
+    /// A JWT that authenticates as the app itself, signed with its key.
+    fn jwt(&self) -> Result<String, Error> {
+        // Backdated a bit, in case GitHub's clock is behind
+        let issued_at = Utc::now().timestamp() - 60;
+
+        let header = r#"{"alg":"RS256","typ":"JWT"}"#;
+        let claims = JwtClaims {
+            iat: issued_at,
+            exp: issued_at + JWT_LIFETIME_SECS,
+            iss: self.app_id,
+        };
+
+        let message = format!(
+            "{}.{}",
+            base64::encode_config(header, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(&serde_json::to_vec(&claims)?, base64::URL_SAFE_NO_PAD)
+        );
+
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.private_key)?;
+        signer.update(message.as_bytes())?;
+        let signature = base64::encode_config(&signer.sign_to_vec()?, base64::URL_SAFE_NO_PAD);
+
+        Ok(format!("{}.{}", message, signature))
+    }
+}
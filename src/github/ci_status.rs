@@ -0,0 +1,117 @@
+use std::fmt;
+
+/// The overall CI state of a pull request's head commit, combining commit
+/// statuses and check runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CiState {
+    Pending,
+    Success,
+    Failure,
+}
+
+#[derive(Debug, Clone)]
+pub struct CiStatus {
+    pub state: CiState,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: String,
+    pub state: CiState,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CombinedStatus {
+    #[serde(default)]
+    statuses: Vec<CommitStatus>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CommitStatus {
+    context: String,
+    state: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CheckRuns {
+    #[serde(default)]
+    check_runs: Vec<CheckRun>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CheckRun {
+    name: String,
+    status: String,
+    conclusion: Option<String>,
+}
+
+impl CiState {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            CiState::Pending => "pending",
+            CiState::Success => "success",
+            CiState::Failure => "failure",
+        }
+    }
+}
+
+impl fmt::Display for CiState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl CiStatus {
+    /// Combines commit statuses and check runs. A commit without any of them
+    /// has nothing to wait for, and counts as successful.
+    pub fn combine(statuses: CombinedStatus, check_runs: CheckRuns) -> CiStatus {
+        let statuses = statuses.statuses.into_iter().map(|status| Check {
+            state: status_state(&status.state),
+            name: status.context,
+        });
+
+        let check_runs = check_runs.check_runs.into_iter().map(|run| Check {
+            state: check_run_state(&run.status, run.conclusion.as_ref().map(String::as_str)),
+            name: run.name,
+        });
+
+        let checks: Vec<Check> = statuses.chain(check_runs).collect();
+
+        let state = if checks.iter().any(|check| check.state == CiState::Failure) {
+            CiState::Failure
+        } else if checks.iter().any(|check| check.state == CiState::Pending) {
+            CiState::Pending
+        } else {
+            CiState::Success
+        };
+
+        CiStatus { state, checks }
+    }
+
+    pub fn failing_checks(&self) -> Vec<&Check> {
+        self.checks
+            .iter()
+            .filter(|check| check.state == CiState::Failure)
+            .collect()
+    }
+}
+
+fn status_state(state: &str) -> CiState {
+    match state {
+        "success" => CiState::Success,
+        "failure" | "error" => CiState::Failure,
+        _ => CiState::Pending,
+    }
+}
+
+fn check_run_state(status: &str, conclusion: Option<&str>) -> CiState {
+    if status != "completed" {
+        return CiState::Pending;
+    }
+
+    match conclusion {
+        Some("success") | Some("neutral") | Some("skipped") => CiState::Success,
+        _ => CiState::Failure,
+    }
+}
@@ -1,70 +1,253 @@
-use std::cell::Cell;
-use std::env;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime};
 
+use chrono::{NaiveDateTime, Utc};
 use failure::Error;
 use futures::future::Either;
 use futures::prelude::*;
+use futures::sync::mpsc;
 use futures::{future, stream};
-use reqwest::header::{self, Authorization, Headers};
+use reqwest::Method;
+use reqwest::header;
 use reqwest::unstable::async::Client;
 use slog::Logger;
 use tokio_timer::Delay;
 use url::Url;
 
-use github::notification::{PullRequest, ReviewRequest};
+use github::account::{GithubAccount, GithubCredentials};
+use github::auth::Auth;
+use github::ci_status::{CheckRuns, CiStatus, CombinedStatus};
+use github::http_cache::{CacheStore, HttpCache};
+use github::notification::{PullRequest, ReviewRequest, User};
 use github::notifications_polling;
 use github::notifications_response::{self, NotificationsResponse};
+use github::rate_limit::{Budget, RateLimit};
+use github::search_polling;
+use github::search_response::{self, FoundPullRequest};
 
 use Config;
+use dry_run::DryRun;
+
+/// Media type needed for the check runs API while it's in preview
+const CHECKS_PREVIEW: &str = "application/vnd.github.antiope-preview+json";
+
+#[derive(Deserialize)]
+struct PullRequestFile {
+    filename: String,
+}
+
+/// Where notification polling stopped, so that it can resume from there after
+/// a restart.
+#[derive(Debug, Clone)]
+pub struct PollingCursor {
+    pub account: String,
+    pub last_modified: String,
+    pub polled_at: NaiveDateTime,
+}
+
+/// What to do with a notification once its review request was delivered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NotificationMark {
+    Read,
+    /// Marks it as read and removes it from the inbox
+    Done,
+}
+
+/// Where review requests come from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReviewSource {
+    Notifications,
+    /// Searches for open pull requests the user was asked to review, which
+    /// also finds the ones whose notifications were filtered out or read
+    Search,
+    Both,
+}
 
 #[derive(Clone)]
 pub struct GithubClient {
+    account: String,
     http: Client,
+    auth: Auth,
+    user_auth: Auth,
+    /// Requests for repository data, made as the app installation if there
+    /// is one
+    rate_limit: RateLimit,
+    /// Requests that need to act as the user, like reading notifications
+    user_rate_limit: RateLimit,
+    /// GraphQL requests are limited separately from the REST API
+    graphql_rate_limit: RateLimit,
+    cache: HttpCache,
+    mark_notifications: Option<NotificationMark>,
+    review_source: ReviewSource,
+    dry_run: Option<DryRun>,
     last_poll_interval: Cell<Option<u64>>,
     notifications_last_modified: Cell<header::HttpDate>,
+    last_polled_at: Cell<Option<NaiveDateTime>>,
+    cursor_sender: Option<mpsc::UnboundedSender<PollingCursor>>,
+    viewer_login: Rc<RefCell<Option<String>>>,
     logger: Logger,
     host: Url,
+    graphql_url: Url,
 }
 
-pub fn new(config: &Config) -> Result<GithubClient, Error> {
-    let github_token = env::var("GITHUB_TOKEN")?;
-    let client = Client::builder()
-        .default_headers(default_headers(github_token))
+pub fn new(config: &Config, account: &GithubAccount, cache_store: Arc<dyn CacheStore>) -> Result<GithubClient, Error> {
+    let client = config
+        .http
+        .client_builder()?
         .timeout(Duration::from_secs(30))
-        .build(&config.core.handle())?;
+        .build(&config.handle)?;
+
+    let logger = config.logger.new(o!("account" => account.name.clone()));
+    let (auth, user_auth, rate_limit, user_rate_limit, graphql_rate_limit) = match account.credentials {
+        GithubCredentials::App(ref app) => {
+            let installation = Auth::installation(app, &client, &config.github_base, logger.clone())?;
+            let user = Auth::user(app, &client, logger.clone())?;
+
+            (
+                installation.clone(),
+                user.clone(),
+                RateLimit::new(installation, logger.clone()),
+                RateLimit::new(user.clone(), logger.clone()),
+                RateLimit::new(user, logger.clone()),
+            )
+        }
+
+        GithubCredentials::Token(ref token) => {
+            let token = Auth::Token(token.clone());
+            let rate_limit = RateLimit::new(token.clone(), logger.clone());
+
+            (
+                token.clone(),
+                token.clone(),
+                rate_limit.clone(),
+                rate_limit,
+                RateLimit::new(token, logger.clone()),
+            )
+        }
+    };
 
     let base_time = SystemTime::now() - Duration::from_secs(60 * 60 * 24 * 7);
     let base_time = header::HttpDate::from(base_time);
 
     Ok(GithubClient {
+        account: account.name.clone(),
         http: client,
+        auth,
+        user_auth,
+        rate_limit,
+        user_rate_limit,
+        graphql_rate_limit,
+        cache: HttpCache::new(cache_store, &account.name, logger.clone()),
+        mark_notifications: config.mark_notifications,
+        review_source: config.review_source,
+        dry_run: config.dry_run.clone(),
         last_poll_interval: Cell::new(None),
         notifications_last_modified: Cell::new(base_time),
-        logger: config.logger.clone(),
+        last_polled_at: Cell::new(None),
+        cursor_sender: None,
+        viewer_login: Rc::new(RefCell::new(None)),
+        logger,
         host: config.github_base.clone(),
+        graphql_url: graphql_url(config)?,
     })
 }
 
 impl GithubClient {
-    pub fn into_pull_request_stream(self) -> impl Stream<Item = (PullRequest, Logger), Error = Error> {
+    /// Polls for review requests forever, or only fetches a single batch of
+    /// them with `once`.
+    pub fn into_pull_request_stream(self, once: bool) -> impl Stream<Item = (PullRequest, Logger), Error = Error> {
+        let uses_notifications = self.review_source != ReviewSource::Search;
+        let uses_search = self.review_source != ReviewSource::Notifications;
+        let client_if = |used: bool| if used { Some(self.clone()) } else { None };
+
+        // Sources that aren't used are left as empty streams
+        let logger = self.logger.clone();
+        let notifications = stream::iter_ok::<_, Error>(client_if(uses_notifications && !once))
+            .map(move |client| notifications_polling::poll_notifications(client, logger.clone()))
+            .flatten();
+
+        let logger = self.logger.clone();
+        let notifications_once = stream::iter_ok::<_, Error>(client_if(uses_notifications && once))
+            .map(move |client| notifications_polling::poll_notifications_once(client, logger.clone()))
+            .flatten();
+
+        let logger = self.logger.clone();
+        let search = stream::iter_ok::<_, Error>(client_if(uses_search && !once))
+            .map(move |client| search_polling::poll_search(client, logger.clone()))
+            .flatten();
+
         let logger = self.logger.clone();
-        notifications_polling::poll_notifications(self, logger)
+        let search_once = stream::iter_ok::<_, Error>(client_if(uses_search && once))
+            .map(move |client| search_polling::search_once(client, logger.clone()))
+            .flatten();
+
+        notifications
+            .select(notifications_once)
+            .select(search.select(search_once))
+    }
+
+    /// Makes polling start from notifications modified after `since`.
+    pub fn poll_since(&self, since: SystemTime) {
+        self.notifications_last_modified.set(header::HttpDate::from(since));
+    }
+
+    /// Makes polling resume from a cursor reported by an earlier run.
+    pub fn resume_from(&self, cursor: &PollingCursor) -> Result<(), Error> {
+        let last_modified = cursor
+            .last_modified
+            .parse()
+            .map_err(|_| format_err!("Invalid Last-Modified in polling cursor: {}", cursor.last_modified))?;
+
+        self.notifications_last_modified.set(last_modified);
+        Ok(())
+    }
+
+    /// Makes the client report its polling cursors to `sender`. A cursor is
+    /// reported once the notifications polled before it have all been
+    /// handled.
+    pub fn track_cursor(&mut self, sender: mpsc::UnboundedSender<PollingCursor>) {
+        self.cursor_sender = Some(sender);
+    }
+
+    /// The name of the account the client acts for.
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+
+    /// Reports where polling is, if anything was polled yet.
+    pub fn report_cursor(&self) {
+        let (sender, polled_at) = match (&self.cursor_sender, self.last_polled_at.get()) {
+            (Some(sender), Some(polled_at)) => (sender, polled_at),
+            _ => return,
+        };
+
+        let cursor = PollingCursor {
+            account: self.account.clone(),
+            last_modified: self.notifications_last_modified.get().to_string(),
+            polled_at,
+        };
+
+        sender.unbounded_send(cursor).ok();
     }
 
     pub fn next_review_requests(
         &self,
     ) -> impl Future<Item = (impl Stream<Item = PullRequest, Error = Error>, Self), Error = Error> {
         let pages_stream = self.current_notifications();
+        let viewer_login = self.viewer_login();
+        let polled_at = Utc::now().naive_utc();
 
         let new_client = self.clone();
-        let http = self.http.clone();
+        let client = self.clone();
         let logger = self.logger.clone();
 
         pages_stream
             .into_future()
             .map_err(|(err, _)| err)
-            .and_then(move |(maybe_page, next_stream)| {
+            .join(viewer_login)
+            .and_then(move |((maybe_page, next_stream), login)| {
                 let response = match maybe_page {
                     Some(page) => page,
                     None => {
@@ -77,6 +260,8 @@ impl GithubClient {
                     new_client.notifications_last_modified.set(lm);
                 }
 
+                new_client.last_polled_at.set(Some(polled_at));
+
                 if let Some(p) = response.poll_interval {
                     new_client.last_poll_interval.set(Some(p));
                 }
@@ -87,12 +272,180 @@ impl GithubClient {
                     .flatten()
                     .filter_map(ReviewRequest::from_notification);
 
-                let pull_requests = notifications_to_pull_requests(http, complete_stream, logger.clone());
+                let pull_requests = notifications_to_pull_requests(client, complete_stream, logger.clone())
+                    .map(move |mut pr| {
+                        if let Some(ref login) = login {
+                            pr.mark_reviewer(login);
+                        }
+
+                        pr
+                    });
 
                 future::ok((pull_requests, new_client))
             })
     }
 
+    /// Searches for the open pull requests the user was asked to review, going
+    /// through every page of results. Errors end the stream, so a search that
+    /// was cut short can't pass for a complete one.
+    pub fn search_review_requests(&self) -> impl Stream<Item = PullRequest, Error = Error> {
+        let graphql_url = self.graphql_url.clone();
+        let http = self.http.clone();
+        let rate_limit = self.graphql_rate_limit.clone();
+
+        let pages = stream::unfold(Some(None), move |maybe_cursor| {
+            let cursor = maybe_cursor?;
+
+            let body = search_response::request_body(cursor);
+            let graphql_url = graphql_url.clone();
+            let page = rate_limit
+                .send(&http, move |http| {
+                    let mut request = http.post(graphql_url.clone());
+                    request.json(&body);
+                    request
+                })
+                .and_then(search_response::from_http)
+                .map(|page| (page.pull_requests, page.next_cursor.map(Some)));
+
+            Some(page)
+        });
+
+        let client = self.clone();
+        let pull_requests = pages
+            .map(stream::iter_ok)
+            .flatten()
+            .map(move |found| client.get_found_pull_request(found))
+            .buffer_unordered(10);
+
+        self.viewer_login()
+            .map(move |login| {
+                pull_requests.map(move |mut pr| {
+                    if let Some(ref login) = login {
+                        pr.mark_reviewer(login);
+                    }
+
+                    pr
+                })
+            })
+            .flatten_stream()
+    }
+
+    fn get_found_pull_request(&self, found: FoundPullRequest) -> impl Future<Item = PullRequest, Error = Error> {
+        let pr_path = format!("repos/{}/pulls/{}", found.repository.name_with_owner, found.number);
+
+        match self.host.join(&pr_path) {
+            Ok(pr_url) => Either::A(self.get_pull_request(pr_url.as_str())),
+            Err(err) => Either::B(future::err(Error::from(err))),
+        }
+    }
+
+    /// Fetches a single pull request by its API url.
+    pub fn get_pull_request(&self, url: &str) -> impl Future<Item = PullRequest, Error = Error> {
+        let account = self.account.clone();
+
+        self.cache
+            .get_json(&self.http, &self.rate_limit, url)
+            .map(move |mut pr: PullRequest| {
+                pr.account = account;
+                pr
+            })
+    }
+
+    /// Makes sure the credentials are valid for a while, fetching new
+    /// installation and user tokens if authenticated as a GitHub App.
+    pub fn refresh_auth(&self) -> impl Future<Item = (), Error = Error> {
+        self.auth.refresh().join(self.user_auth.refresh()).map(|_| ())
+    }
+
+    /// What's left of the GitHub rate limit, as of the latest response.
+    pub fn rate_limit_budget(&self) -> Budget {
+        self.rate_limit.budget()
+    }
+
+    /// How many pull request fetches were answered from the cache, from 0 to 1.
+    pub fn cache_hit_ratio(&self) -> Option<f64> {
+        self.cache.hit_ratio()
+    }
+
+    /// Marks the notification the pull request came from as configured, after
+    /// its review request was delivered.
+    pub fn mark_notification(&self, pr: &PullRequest) -> impl Future<Item = (), Error = Error> {
+        let (mark, thread_id) = match (self.mark_notifications, &pr.notification_thread_id) {
+            (Some(mark), Some(thread_id)) => (mark, thread_id),
+            _ => return Either::A(future::ok(())),
+        };
+
+        let thread_url = match self.host.join(&format!("notifications/threads/{}", thread_id)) {
+            Ok(url) => url,
+            Err(err) => return Either::A(future::err(Error::from(err))),
+        };
+
+        if let Some(ref dry_run) = self.dry_run {
+            dry_run.record("mark notification", &thread_url.as_str());
+            return Either::A(future::ok(()));
+        }
+
+        let request = move |http: &Client| match mark {
+            NotificationMark::Read => http.request(Method::Patch, thread_url.clone()),
+            NotificationMark::Done => http.delete(thread_url.clone()),
+        };
+
+        let marked = self.user_rate_limit
+            .send(&self.http, request)
+            .and_then(|response| response.error_for_status().map_err(Error::from))
+            .map(|_| ());
+
+        Either::B(marked)
+    }
+
+    /// Lists the paths of the files changed by the pull request. Only the
+    /// first hundred files are listed.
+    pub fn changed_files(&self, pr: &PullRequest) -> impl Future<Item = Vec<String>, Error = Error> {
+        let files_url = format!("{}/files?per_page=100", pr.url);
+
+        self.rate_limit
+            .send(&self.http, move |http| http.get(&files_url))
+            .and_then(|response| response.error_for_status().map_err(Error::from))
+            .and_then(|mut response| response.json::<Vec<PullRequestFile>>().map_err(Error::from))
+            .map(|files| files.into_iter().map(|file| file.filename).collect())
+    }
+
+    /// Fetches the commit statuses and check runs of the pull request's head
+    /// commit, and combines them into a single state.
+    pub fn ci_status(&self, pr: &PullRequest) -> impl Future<Item = CiStatus, Error = Error> {
+        let commit_path = format!("repos/{}/commits/{}", pr.full_repo_name(), pr.head_sha());
+
+        let urls = self.host
+            .join(&format!("{}/status", commit_path))
+            .and_then(|status_url| Ok((status_url, self.host.join(&format!("{}/check-runs", commit_path))?)));
+
+        let (status_url, check_runs_url) = match urls {
+            Ok(urls) => urls,
+            Err(err) => return Either::A(future::err(Error::from(err))),
+        };
+
+        let statuses = self.rate_limit
+            .send(&self.http, move |http| http.get(status_url.clone()))
+            .and_then(|response| response.error_for_status().map_err(Error::from))
+            .and_then(|mut response| response.json::<CombinedStatus>().map_err(Error::from));
+
+        let checks_preview = header::Accept(vec![header::qitem(CHECKS_PREVIEW.parse().unwrap())]);
+        let check_runs = self.rate_limit
+            .send(&self.http, move |http| {
+                let mut request = http.get(check_runs_url.clone());
+                request.header(checks_preview.clone());
+                request
+            })
+            .and_then(|response| response.error_for_status().map_err(Error::from))
+            .and_then(|mut response| response.json::<CheckRuns>().map_err(Error::from));
+
+        let status = statuses
+            .join(check_runs)
+            .map(|(statuses, check_runs)| CiStatus::combine(statuses, check_runs));
+
+        Either::B(status)
+    }
+
     pub fn wait_poll_interval(&self) -> impl Future<Item = (), Error = Error> {
         let interval = match self.last_poll_interval.get() {
             Some(interval) => interval,
@@ -110,6 +463,36 @@ impl GithubClient {
         Either::B(delay)
     }
 
+    /// Resolves to the login of the authenticated user. Failures are logged
+    /// and resolve to `None`, so that a missing login doesn't stop polling.
+    fn viewer_login(&self) -> impl Future<Item = Option<String>, Error = Error> {
+        if let Some(login) = self.viewer_login.borrow().clone() {
+            return Either::A(future::ok(Some(login)));
+        }
+
+        let user_url = self.host.join("user").unwrap();
+        let logger = self.logger.clone();
+        let viewer_login = self.viewer_login.clone();
+
+        let request = self.user_rate_limit
+            .send(&self.http, move |http| http.get(user_url.clone()))
+            .and_then(|response| response.error_for_status().map_err(Error::from))
+            .and_then(|mut response| response.json::<User>().map_err(Error::from))
+            .then(move |result| match result {
+                Ok(user) => {
+                    *viewer_login.borrow_mut() = Some(user.login.clone());
+                    Ok(Some(user.login))
+                }
+
+                Err(err) => {
+                    warn!(logger, "Unable to fetch the authenticated user"; "error" => %err);
+                    Ok(None)
+                }
+            });
+
+        Either::B(request)
+    }
+
     fn current_notifications(&self) -> impl Stream<Item = NotificationsResponse, Error = Error> {
         let notifications_url = self.host.join("notifications?all=true").unwrap();
         let url = Some(notifications_url.into_string());
@@ -117,11 +500,12 @@ impl GithubClient {
         let last_modified = self.notifications_last_modified.get();
         let logger = self.logger.clone();
         let client = self.http.clone();
+        let rate_limit = self.user_rate_limit.clone();
 
         stream::unfold(url, move |maybe_url| {
             let url = maybe_url?;
 
-            let result = get_notifications_page(client.clone(), last_modified, url, logger.clone());
+            let result = get_notifications_page(&client, &rate_limit, last_modified, url, logger.clone());
 
             let result = result.map(move |response| {
                 let next_page = response.next_page.clone();
@@ -134,7 +518,7 @@ impl GithubClient {
 }
 
 pub fn notifications_to_pull_requests<S>(
-    http: Client,
+    client: GithubClient,
     reviews: S,
     logger: Logger,
 ) -> impl Stream<Item = PullRequest, Error = Error>
@@ -144,9 +528,14 @@ where
     reviews
         .map(move |review_request| {
             let logger = logger.clone();
+            let thread_id = review_request.thread_id;
 
-            get_pr_for_review_request(http.clone(), review_request)
-                .map(Some)
+            client
+                .get_pull_request(&review_request.url)
+                .map(move |mut pr| {
+                    pr.notification_thread_id = Some(thread_id);
+                    Some(pr)
+                })
                 .or_else(move |err| {
                     warn!(logger, "Problem getting pull request"; "error" => %err);
                     return future::ok(None);
@@ -156,26 +545,27 @@ where
         .filter_map(|pr| pr)
 }
 
-fn get_pr_for_review_request(
-    http: Client,
-    review_request: ReviewRequest,
-) -> impl Future<Item = PullRequest, Error = Error> {
-    http.get(&review_request.url)
-        .send()
-        .and_then(|mut response| response.json::<PullRequest>())
-        .map_err(Error::from)
-}
+/// The GraphQL endpoint, unless configured, is `graphql` next to the REST
+/// API on github.com, and `/api/graphql` on GitHub Enterprise Server, whose
+/// REST API is under `/api/v3/`.
+fn graphql_url(config: &Config) -> Result<Url, Error> {
+    if let Some(ref url) = config.github_graphql_url {
+        return Ok(url.clone());
+    }
 
-fn default_headers(github_token: String) -> Headers {
-    let mut headers = Headers::new();
-    let auth_header = Authorization(format!("token {}", github_token));
-    headers.set(auth_header);
+    let base = &config.github_base;
+    let url = if base.path().ends_with("/api/v3/") {
+        base.join("../graphql")?
+    } else {
+        base.join("graphql")?
+    };
 
-    headers
+    Ok(url)
 }
 
 fn get_notifications_page(
-    client: Client,
+    client: &Client,
+    rate_limit: &RateLimit,
     last_modified: header::HttpDate,
     page_url: String,
     logger: Logger,
@@ -183,10 +573,13 @@ fn get_notifications_page(
     let logger = logger.new(o!("url" => page_url.to_string(), "last_modified" => last_modified.to_string()));
     debug!(logger, "Fetching notifications");
 
-    let if_modified_since = header::IfModifiedSince(last_modified);
+    let request = move |http: &Client| {
+        let mut request = http.get(&page_url);
+        request.header(header::IfModifiedSince(last_modified));
+        request
+    };
 
-    let request = client.get(&page_url).header(if_modified_since).send();
-    request
-        .map_err(Error::from)
+    rate_limit
+        .send(client, request)
         .and_then(move |response| notifications_response::from_http(response, logger))
 }
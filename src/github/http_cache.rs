@@ -0,0 +1,193 @@
+use chrono::{NaiveDateTime, Utc};
+use failure::Error;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use reqwest::StatusCode;
+use reqwest::header::{ETag, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch, LastModified};
+use reqwest::unstable::async::{Client, Response};
+use serde::de::DeserializeOwned;
+use serde_json;
+use slog::Logger;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use github::rate_limit::RateLimit;
+use review_handler::CachedResponse;
+
+/// Where cached responses are kept between runs.
+pub trait CacheStore {
+    fn cached_response(
+        &self,
+        account: &str,
+        url: &str,
+    ) -> Box<dyn Future<Item = Option<CachedResponse>, Error = Error>>;
+    /// Replaces the response cached for the same account and URL, if any.
+    fn store_cached_response(&self, response: CachedResponse) -> Box<dyn Future<Item = (), Error = Error>>;
+    /// Marks the response cached for the account and URL as fetched again at
+    /// `now`, when it turned out not to be modified.
+    fn touch_cached_response(
+        &self,
+        account: &str,
+        url: &str,
+        now: NaiveDateTime,
+    ) -> Box<dyn Future<Item = (), Error = Error>>;
+}
+
+/// Caches responses in the database, to make conditional requests for them
+/// later. GitHub doesn't count `304 Not Modified` responses against the rate
+/// limit, so unchanged resources are fetched for free. Responses are kept per
+/// account, since what one account may see says nothing about another.
+#[derive(Clone)]
+pub struct HttpCache {
+    store: Arc<dyn CacheStore>,
+    account: String,
+    hits: Arc<AtomicUsize>,
+    misses: Arc<AtomicUsize>,
+    logger: Logger,
+}
+
+impl HttpCache {
+    pub fn new(store: Arc<dyn CacheStore>, account: &str, logger: Logger) -> HttpCache {
+        HttpCache {
+            store,
+            account: account.to_string(),
+            hits: Arc::new(AtomicUsize::new(0)),
+            misses: Arc::new(AtomicUsize::new(0)),
+            logger,
+        }
+    }
+
+    /// How many of the responses so far came from the cache, from 0 to 1.
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let total = hits + self.misses.load(Ordering::Relaxed);
+
+        if total == 0 {
+            None
+        } else {
+            Some(hits as f64 / total as f64)
+        }
+    }
+
+    /// Fetches `url` as JSON, using the cached response if it wasn't modified.
+    /// Cache errors are logged and the request is made unconditionally.
+    pub fn get_json<T: DeserializeOwned>(
+        &self,
+        http: &Client,
+        rate_limit: &RateLimit,
+        url: &str,
+    ) -> impl Future<Item = T, Error = Error> {
+        let http = http.clone();
+        let rate_limit = rate_limit.clone();
+        let cache = self.clone();
+        let url = url.to_string();
+        let logger = self.logger.clone();
+
+        let cached = self.store.cached_response(&self.account, &url).or_else(move |err| {
+            warn!(logger, "Unable to read the HTTP cache"; "error" => %err);
+            Ok(None)
+        });
+
+        cached.and_then(move |cached| {
+            let etag: Option<EntityTag> = cached.as_ref().and_then(|cached| cached.etag.as_ref()?.parse().ok());
+            let last_modified: Option<HttpDate> = cached
+                .as_ref()
+                .and_then(|cached| cached.last_modified.as_ref()?.parse().ok());
+            let request_url = url.clone();
+
+            let request = move |http: &Client| {
+                let mut request = http.get(&request_url);
+
+                if let Some(ref etag) = etag {
+                    request.header(IfNoneMatch::Items(vec![etag.clone()]));
+                }
+
+                if let Some(date) = last_modified {
+                    request.header(IfModifiedSince(date));
+                }
+
+                request
+            };
+
+            rate_limit
+                .send(&http, request)
+                .and_then(move |response| match (response.status(), cached) {
+                    (StatusCode::NotModified, Some(cached)) => {
+                        cache.hits.fetch_add(1, Ordering::Relaxed);
+                        let touched = cache.touch(&url);
+                        Either::A(touched.and_then(move |_| serde_json::from_str(&cached.body).map_err(Error::from)))
+                    }
+
+                    _ => {
+                        cache.misses.fetch_add(1, Ordering::Relaxed);
+                        Either::B(cache.parse_and_store(url, response))
+                    }
+                })
+        })
+    }
+
+    /// Keeps a response that is still in use from being evicted.
+    fn touch(&self, url: &str) -> impl Future<Item = (), Error = Error> {
+        let logger = self.logger.clone();
+
+        self.store
+            .touch_cached_response(&self.account, url, Utc::now().naive_utc())
+            .or_else(move |err| {
+                warn!(logger, "Unable to update the HTTP cache"; "error" => %err);
+                Ok(())
+            })
+    }
+
+    fn parse_and_store<T: DeserializeOwned>(
+        &self,
+        url: String,
+        response: Response,
+    ) -> impl Future<Item = T, Error = Error> {
+        let response = match response.error_for_status() {
+            Ok(response) => response,
+            Err(err) => return Either::A(future::err(Error::from(err))),
+        };
+
+        let etag = response.headers().get::<ETag>().map(|etag| etag.to_string());
+        let last_modified = response.headers().get::<LastModified>().map(|date| date.to_string());
+
+        let store = self.store.clone();
+        let account = self.account.clone();
+        let logger = self.logger.clone();
+
+        let parsed = response
+            .into_body()
+            .concat2()
+            .map_err(Error::from)
+            .and_then(|body| {
+                let body = String::from_utf8(body.to_vec())?;
+                let parsed: T = serde_json::from_str(&body)?;
+                Ok((parsed, body))
+            })
+            .and_then(move |(parsed, body)| {
+                if etag.is_none() && last_modified.is_none() {
+                    return Either::A(future::ok(parsed));
+                }
+
+                let cached = CachedResponse {
+                    account,
+                    url,
+                    etag,
+                    last_modified,
+                    body,
+                };
+
+                let stored = store.store_cached_response(cached).then(move |result| {
+                    if let Err(err) = result {
+                        warn!(logger, "Unable to update the HTTP cache"; "error" => %err);
+                    }
+
+                    Ok(parsed)
+                });
+
+                Either::B(stored)
+            });
+
+        Either::B(parsed)
+    }
+}
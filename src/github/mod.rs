@@ -1,8 +1,19 @@
+mod account;
+mod auth;
+mod ci_status;
 mod client;
+mod http_cache;
 mod notification;
 mod notifications_polling;
 mod notifications_response;
+mod rate_limit;
+mod search_polling;
+mod search_response;
 
-pub use self::client::GithubClient;
+pub use self::account::{GithubAccount, GithubCredentials, DEFAULT_ACCOUNT};
+pub use self::auth::{GithubApp, UserCredentials, UserTokenRefresh};
+pub use self::ci_status::{CiState, CiStatus};
+pub use self::client::{GithubClient, NotificationMark, PollingCursor, ReviewSource};
 pub use self::client::new as new_client;
+pub use self::http_cache::CacheStore;
 pub use self::notification::PullRequest;
@@ -1,7 +1,10 @@
 use chrono::prelude::*;
+use openssl::sha::sha256;
+use serde_json;
 
 #[derive(Debug, Deserialize)]
 pub struct Notification {
+    pub id: String,
     pub reason: String,
     pub subject: Subject,
     pub repository: Repository,
@@ -18,10 +21,12 @@ pub struct Subject {
 #[derive(Debug, Deserialize, Clone)]
 pub struct Repository {
     pub name: String,
+    pub full_name: String,
 }
 
 #[derive(Debug, Clone)]
 pub struct ReviewRequest {
+    pub thread_id: String,
     pub pr_title: String,
     pub repository: String,
     pub url: String,
@@ -31,20 +36,66 @@ pub struct ReviewRequest {
 pub struct PullRequest {
     pub number: i64,
     pub title: String,
+    pub url: String,
     pub html_url: String,
+    pub body: Option<String>,
 
     pub created_at: DateTime<Local>,
     pub merged_at: Option<DateTime<Local>>,
     pub closed_at: Option<DateTime<Local>>,
 
+    #[serde(default)]
+    pub draft: bool,
+    #[serde(default)]
+    pub additions: u64,
+    #[serde(default)]
+    pub deletions: u64,
+    #[serde(default)]
+    pub changed_files: u64,
+    #[serde(default)]
+    pub labels: Vec<Label>,
+    #[serde(default)]
+    requested_reviewers: Vec<User>,
+
+    /// Whether the authenticated user was asked for a review directly, as
+    /// opposed to through one of their teams. `None` if the user is unknown.
+    #[serde(skip)]
+    pub direct_request: Option<bool>,
+
+    /// The notification thread the review request came from, if any
+    #[serde(skip)]
+    pub notification_thread_id: Option<String>,
+
+    /// The account the pull request was fetched for
+    #[serde(skip)]
+    pub account: String,
+
     base: PullRequestBase,
+    head: PullRequestHead,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 struct PullRequestBase {
+    #[serde(rename = "ref")]
+    branch: String,
     repo: Repository,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+struct PullRequestHead {
+    sha: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Label {
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct User {
+    pub login: String,
+}
+
 impl PullRequest {
     pub fn is_open(&self) -> bool {
         self.merged_at.is_none() && self.closed_at.is_none()
@@ -53,6 +104,46 @@ impl PullRequest {
     pub fn repo(&self) -> &str {
         &self.base.repo.name
     }
+
+    /// The repository name including its owner, like `octocat/hello-world`
+    pub fn full_repo_name(&self) -> &str {
+        &self.base.repo.full_name
+    }
+
+    pub fn head_sha(&self) -> &str {
+        &self.head.sha
+    }
+
+    pub fn base_branch(&self) -> &str {
+        &self.base.branch
+    }
+
+    pub fn changed_lines(&self) -> u64 {
+        self.additions + self.deletions
+    }
+
+    pub fn has_label(&self, name: &str) -> bool {
+        self.labels.iter().any(|label| label.name.eq_ignore_ascii_case(name))
+    }
+
+    /// A fingerprint of what reviewist shows about the pull request: its
+    /// title, draft flag and labels. Used to tell whether deliveries need to
+    /// be updated. It's stored, so it's made with SHA-256 to stay the same
+    /// across builds.
+    pub fn content_hash(&self) -> String {
+        let mut labels: Vec<_> = self.labels.iter().map(|label| label.name.to_lowercase()).collect();
+        labels.sort();
+
+        let content = serde_json::to_vec(&(&self.title, self.draft, labels)).expect("Unable to serialize PR content");
+        let digest = sha256(&content);
+
+        digest[..8].iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    pub fn mark_reviewer(&mut self, login: &str) {
+        let direct = self.requested_reviewers.iter().any(|user| user.login == login);
+        self.direct_request = Some(direct);
+    }
 }
 
 impl ReviewRequest {
@@ -62,6 +153,7 @@ impl ReviewRequest {
         }
 
         Some(ReviewRequest {
+            thread_id: n.id,
             pr_title: n.subject.title,
             repository: n.repository.name,
             url: n.subject.url,
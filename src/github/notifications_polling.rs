@@ -46,10 +46,33 @@ pub fn poll_notifications(
         .flatten()
 }
 
+/// Polls a single batch of notifications, going through all of its pages.
+/// The polling cursor is reported once every pull request in it was handled.
+pub fn poll_notifications_once(
+    client: GithubClient,
+    logger: Logger,
+) -> impl Stream<Item = (PullRequest, Logger), Error = Error> {
+    let batch_logger = logger.new(o!("batch_number" => 1));
+
+    get_next_batch(&client, logger)
+        .map(move |(batch, next_client)| {
+            let report_cursor = stream::poll_fn(move || {
+                next_client.report_cursor();
+                Ok(Async::Ready(None))
+            });
+
+            batch.map(move |item| (item, batch_logger.clone())).chain(report_cursor)
+        })
+        .flatten_stream()
+}
+
 fn get_next_batch(
     client: &GithubClient,
     logger: Logger,
 ) -> impl Future<Item = (impl Stream<Item = PullRequest, Error = Error>, GithubClient), Error = Error> {
+    // The previous batch was handled by now, so it's safe to move past it
+    client.report_cursor();
+
     let next_review_requests = client.next_review_requests();
 
     let stream_logger = logger.clone();
@@ -58,6 +81,10 @@ fn get_next_batch(
         .wait_poll_interval()
         .and_then(move |_| next_review_requests)
         .map(move |(stream, next_client)| {
+            let budget = next_client.rate_limit_budget();
+            debug!(stream_logger, "Fetched notifications"; "rate_limit_remaining" => ?budget.remaining);
+            debug!(stream_logger, "Pull request cache"; "hit_ratio" => ?next_client.cache_hit_ratio());
+
             let stream = stream.inspect_err(move |err| {
                 error!(stream_logger, "Error in notification stream"; "error" => %err);
             });
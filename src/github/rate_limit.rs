@@ -0,0 +1,254 @@
+use failure::Error;
+use futures::future::{self, Either, Loop};
+use futures::prelude::*;
+use reqwest::StatusCode;
+use reqwest::header::{Headers, RetryAfter};
+use reqwest::unstable::async::{Client, RequestBuilder, Response};
+use slog::Logger;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_timer::Delay;
+
+use github::auth::Auth;
+
+header! { (XRateLimitLimit, "X-RateLimit-Limit") => [u64] }
+header! { (XRateLimitRemaining, "X-RateLimit-Remaining") => [u64] }
+header! { (XRateLimitReset, "X-RateLimit-Reset") => [u64] }
+
+/// How many requests are kept in reserve. Requests wait for the rate limit to
+/// reset instead of using them up.
+const RESERVED_REQUESTS: u64 = 10;
+/// How long to back off after hitting a secondary rate limit that didn't say
+/// when to retry
+const SECONDARY_LIMIT_BACKOFF_SECS: u64 = 60;
+/// How many times a request turned down by the rate limit is sent again
+const MAX_RATE_LIMITED_RETRIES: u32 = 3;
+/// What GitHub says in the body of a 403 that hit a secondary rate limit,
+/// which doesn't always come with headers saying so
+const SECONDARY_LIMIT_MESSAGE: &str = "secondary rate limit";
+
+/// Tracks GitHub's rate limit across every request made with the same
+/// credentials, and holds requests back when the budget is about to run out
+/// or GitHub asked to slow down.
+#[derive(Clone)]
+pub struct RateLimit {
+    budget: Arc<Mutex<Budget>>,
+    auth: Auth,
+    logger: Logger,
+}
+
+/// The body of GitHub's error responses
+#[derive(Deserialize)]
+struct ErrorMessage {
+    message: String,
+}
+
+/// What is known about the rate limit, from the latest response
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Budget {
+    pub limit: Option<u64>,
+    pub remaining: Option<u64>,
+    pub reset_at: Option<SystemTime>,
+    pub blocked_until: Option<SystemTime>,
+}
+
+impl RateLimit {
+    pub fn new(auth: Auth, logger: Logger) -> RateLimit {
+        RateLimit {
+            budget: Arc::new(Mutex::new(Budget::default())),
+            auth,
+            logger,
+        }
+    }
+
+    pub fn budget(&self) -> Budget {
+        *self.budget.lock().unwrap()
+    }
+
+    /// Sends the request built by `request`, authorized, once the rate limit
+    /// allows it, updating the budget from the response. Requests that hit
+    /// the rate limit are built and sent again once it's lifted, a few times
+    /// at most, before the response is handed back.
+    pub fn send<F>(&self, http: &Client, request: F) -> impl Future<Item = Response, Error = Error>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        let http = http.clone();
+        let rate_limit = self.clone();
+
+        future::loop_fn(0, move |retries| {
+            let rate_limit = rate_limit.clone();
+            let logger = rate_limit.logger.clone();
+
+            let authorized = rate_limit.auth.authorization().and_then(|authorization| {
+                let mut request = request(&http).build()?;
+                request.headers_mut().set(authorization);
+                Ok(request)
+            });
+
+            let request = match authorized {
+                Ok(request) => request,
+                Err(err) => return Either::A(future::err(err)),
+            };
+
+            let http = http.clone();
+
+            let response = rate_limit
+                .acquire()
+                .and_then(move |_| http.execute(request).map_err(Error::from))
+                .and_then(move |response| rate_limit.check(response))
+                .map(move |(response, rate_limited)| {
+                    if rate_limited && retries < MAX_RATE_LIMITED_RETRIES {
+                        info!(logger, "Retrying request after the GitHub rate limit"; "retry" => retries + 1);
+                        Loop::Continue(retries + 1)
+                    } else {
+                        Loop::Break(response)
+                    }
+                });
+
+            Either::B(response)
+        })
+    }
+
+    /// Resolves once a request can be made, taking it from the budget. Waits
+    /// for the rate limit go through the budget again, so that requests that
+    /// waited together don't all go out at once.
+    fn acquire(&self) -> impl Future<Item = (), Error = Error> {
+        let rate_limit = self.clone();
+
+        future::loop_fn((), move |_| {
+            let wait = match rate_limit.take_from_budget() {
+                Some(wait) => wait,
+                None => return Either::A(future::ok(Loop::Break(()))),
+            };
+
+            info!(rate_limit.logger, "Waiting for the GitHub rate limit"; "seconds" => wait.as_secs());
+            let delay = Delay::new(Instant::now() + wait)
+                .map(|_| Loop::Continue(()))
+                .map_err(Error::from);
+
+            Either::B(delay)
+        })
+    }
+
+    /// Takes a request from the budget, or returns how long to wait before
+    /// there's one to take.
+    fn take_from_budget(&self) -> Option<Duration> {
+        let now = SystemTime::now();
+        let mut budget = self.budget.lock().unwrap();
+
+        let wait_until = match *budget {
+            Budget {
+                blocked_until: Some(until),
+                ..
+            } if until > now => Some(until),
+
+            Budget {
+                remaining: Some(remaining),
+                reset_at: Some(reset_at),
+                ..
+            } if remaining <= RESERVED_REQUESTS && reset_at > now => Some(reset_at),
+
+            _ => None,
+        };
+
+        match wait_until.and_then(|until| until.duration_since(now).ok()) {
+            Some(wait) => Some(wait),
+
+            None => {
+                budget.remaining = budget.remaining.map(|remaining| remaining.saturating_sub(1));
+                None
+            }
+        }
+    }
+
+    /// Updates the budget from the response, along with whether the request
+    /// was turned down by the rate limit. A 403 that doesn't say so in its
+    /// headers might still be from the secondary rate limit, which is told by
+    /// its message. Reading it leaves the response without a body, which is
+    /// fine for an error.
+    fn check(&self, mut response: Response) -> impl Future<Item = (Response, bool), Error = Error> {
+        let rate_limited = self.record(&response);
+        if rate_limited || response.status() != StatusCode::Forbidden {
+            return Either::A(future::ok((response, rate_limited)));
+        }
+
+        let rate_limit = self.clone();
+        let message = response.json::<ErrorMessage>().then(move |body| -> Result<_, Error> {
+            let secondary_limit = match body {
+                Ok(body) => body.message.to_lowercase().contains(SECONDARY_LIMIT_MESSAGE),
+                Err(_) => false,
+            };
+
+            if secondary_limit {
+                let now = SystemTime::now();
+                let until = now + Duration::from_secs(SECONDARY_LIMIT_BACKOFF_SECS);
+                rate_limit.block(&mut rate_limit.budget.lock().unwrap(), until, now);
+            }
+
+            Ok((response, secondary_limit))
+        });
+
+        Either::B(message)
+    }
+
+    /// Updates the budget from the response, returning whether the request
+    /// was turned down by the rate limit.
+    fn record(&self, response: &Response) -> bool {
+        let headers = response.headers();
+        let now = SystemTime::now();
+        let mut budget = self.budget.lock().unwrap();
+
+        if let Some(&XRateLimitLimit(limit)) = headers.get() {
+            budget.limit = Some(limit);
+        }
+
+        if let Some(&XRateLimitRemaining(remaining)) = headers.get() {
+            budget.remaining = Some(remaining);
+        }
+
+        if let Some(&XRateLimitReset(reset)) = headers.get() {
+            budget.reset_at = Some(UNIX_EPOCH + Duration::from_secs(reset));
+        }
+
+        let rate_limited = match response.status() {
+            StatusCode::Forbidden | StatusCode::TooManyRequests => {
+                let blocked_until = retry_after(headers, now)
+                    .or_else(|| match budget.remaining {
+                        Some(0) => budget.reset_at,
+                        _ => None,
+                    })
+                    .or_else(|| match response.status() {
+                        StatusCode::TooManyRequests => Some(now + Duration::from_secs(SECONDARY_LIMIT_BACKOFF_SECS)),
+                        _ => None,
+                    });
+
+                if let Some(until) = blocked_until {
+                    self.block(&mut budget, until, now);
+                }
+
+                blocked_until.is_some()
+            }
+
+            _ => false,
+        };
+
+        debug!(self.logger, "GitHub rate limit"; "remaining" => ?budget.remaining, "limit" => ?budget.limit);
+        rate_limited
+    }
+
+    /// Holds back requests until `until`.
+    fn block(&self, budget: &mut Budget, until: SystemTime, now: SystemTime) {
+        let seconds = until.duration_since(now).map(|wait| wait.as_secs()).unwrap_or(0);
+        warn!(self.logger, "Hit the GitHub rate limit, pausing requests"; "seconds" => seconds);
+
+        budget.blocked_until = Some(until);
+    }
+}
+
+fn retry_after(headers: &Headers, now: SystemTime) -> Option<SystemTime> {
+    match headers.get::<RetryAfter>()? {
+        RetryAfter::Delay(delay) => Some(now + *delay),
+        RetryAfter::DateTime(date) => Some(SystemTime::from(*date)),
+    }
+}
@@ -0,0 +1,62 @@
+use failure::Error;
+use futures::prelude::*;
+use futures::stream;
+use slog::Logger;
+use std::time::{Duration, Instant};
+use tokio_timer::Interval;
+
+use github::{GithubClient, PullRequest};
+
+/// How often to search for review requests
+const SEARCH_INTERVAL_SECS: u64 = 60;
+
+/// Searches for open pull requests the user was asked to review, once right
+/// away and then every minute. Pull requests that are still waiting for the
+/// review show up in every search. A search that fails is logged and left
+/// to the next one, without handing out what it found before failing.
+pub fn poll_search(client: GithubClient, logger: Logger) -> impl Stream<Item = (PullRequest, Logger), Error = Error> {
+    let mut search_number = 0;
+
+    Interval::new(Instant::now(), Duration::from_secs(SEARCH_INTERVAL_SECS))
+        .map_err(Error::from)
+        .and_then(move |_| {
+            search_number += 1;
+            let logger = logger.new(o!("search_number" => search_number));
+            debug!(logger, "Searching for review requests");
+
+            client.search_review_requests().collect().then(move |result| {
+                let pull_requests = match result {
+                    Ok(pull_requests) => pull_requests,
+
+                    Err(err) => {
+                        error!(logger, "Error while searching for review requests"; "error" => %err);
+                        vec![]
+                    }
+                };
+
+                let found = pull_requests
+                    .into_iter()
+                    .map(move |pull_request| (pull_request, logger.clone()));
+                Ok(stream::iter_ok(found))
+            })
+        })
+        .flatten()
+}
+
+/// Searches for review requests a single time. A failed search fails the
+/// stream before anything it found is handed out.
+pub fn search_once(client: GithubClient, logger: Logger) -> impl Stream<Item = (PullRequest, Logger), Error = Error> {
+    let logger = logger.new(o!("search_number" => 1));
+    debug!(logger, "Searching for review requests");
+
+    client
+        .search_review_requests()
+        .collect()
+        .map(move |pull_requests| {
+            let found = pull_requests
+                .into_iter()
+                .map(move |pull_request| (pull_request, logger.clone()));
+            stream::iter_ok(found)
+        })
+        .flatten_stream()
+}
@@ -0,0 +1,146 @@
+use failure::Error;
+use futures::future;
+use futures::prelude::*;
+use reqwest::unstable::async::Response;
+use serde_json::{self, Value};
+
+/// Open pull requests the authenticated user was asked to review, whether
+/// directly or through one of their teams
+const REVIEW_REQUESTS_QUERY: &str = "
+query($first: Int!, $after: String) {
+  search(query: \"is:open is:pr review-requested:@me\", type: ISSUE, first: $first, after: $after) {
+    pageInfo {
+      hasNextPage
+      endCursor
+    }
+    nodes {
+      ... on PullRequest {
+        number
+        repository {
+          nameWithOwner
+        }
+      }
+    }
+  }
+}
+";
+
+/// How many pull requests are asked for per page. That's the most GitHub
+/// allows.
+const PAGE_SIZE: u32 = 100;
+
+#[derive(Serialize)]
+pub struct SearchRequest {
+    query: &'static str,
+    variables: SearchVariables,
+}
+
+#[derive(Serialize)]
+struct SearchVariables {
+    first: u32,
+    after: Option<String>,
+}
+
+pub struct SearchPage {
+    pub pull_requests: Vec<FoundPullRequest>,
+    pub next_cursor: Option<String>,
+}
+
+/// A pull request found by the search, which has to be fetched from the REST
+/// API to get the rest of it
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FoundPullRequest {
+    pub number: i64,
+    pub repository: FoundRepository,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FoundRepository {
+    pub name_with_owner: String,
+}
+
+#[derive(Deserialize)]
+struct SearchResponse {
+    data: Option<SearchData>,
+    #[serde(default)]
+    errors: Vec<GraphqlError>,
+}
+
+#[derive(Deserialize)]
+struct SearchData {
+    search: SearchResults,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SearchResults {
+    page_info: PageInfo,
+    nodes: Vec<Option<Value>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PageInfo {
+    has_next_page: bool,
+    end_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GraphqlError {
+    message: String,
+}
+
+/// The body of the request for the page of results after `cursor`, or for
+/// the first page.
+pub fn request_body(cursor: Option<String>) -> SearchRequest {
+    SearchRequest {
+        query: REVIEW_REQUESTS_QUERY,
+        variables: SearchVariables {
+            first: PAGE_SIZE,
+            after: cursor,
+        },
+    }
+}
+
+pub fn from_http(response: Response) -> impl Future<Item = SearchPage, Error = Error> {
+    let response = response.error_for_status().map_err(Error::from);
+
+    future::result(response)
+        .and_then(|mut response| response.json::<SearchResponse>().map_err(Error::from))
+        .and_then(parse_response)
+}
+
+fn parse_response(response: SearchResponse) -> Result<SearchPage, Error> {
+    if let Some(error) = response.errors.first() {
+        return Err(format_err!("Search for review requests failed: {}", error.message));
+    }
+
+    let results = match response.data {
+        Some(data) => data.search,
+        None => return Err(format_err!("Search for review requests returned no data")),
+    };
+
+    // Nodes that aren't pull requests come back empty, and those that can't
+    // be accessed come back as null
+    let pull_requests = results
+        .nodes
+        .into_iter()
+        .filter_map(|node| serde_json::from_value(node?).ok())
+        .collect();
+
+    let next_cursor = match results.page_info {
+        PageInfo {
+            has_next_page: true,
+            end_cursor,
+        } => end_cursor,
+
+        _ => None,
+    };
+
+    Ok(SearchPage {
+        pull_requests,
+        next_cursor,
+    })
+}
@@ -0,0 +1,104 @@
+use failure::Error;
+use reqwest::unstable::async::{Client, ClientBuilder};
+use reqwest::{Certificate, Proxy};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use url::Url;
+
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+/// Settings shared by every HTTP client, for networks that need them
+#[derive(Clone, Debug, Default)]
+pub struct HttpSettings {
+    /// A PEM file with extra root certificates to trust, like the one of a
+    /// company CA that signed the GitHub Enterprise certificate
+    pub ca_bundle: Option<PathBuf>,
+    /// Every request goes through this proxy, when set
+    pub proxy: Option<Url>,
+    /// Hosts reached without the proxy, along with their subdomains, like the
+    /// internal ones on a GitHub Enterprise network. `*` skips it for every
+    /// host.
+    pub no_proxy: Vec<String>,
+}
+
+impl HttpSettings {
+    /// Reads the settings from `CA_BUNDLE`, `HTTPS_PROXY` and `NO_PROXY`.
+    pub fn from_env() -> HttpSettings {
+        let proxy = env::var("HTTPS_PROXY")
+            .or_else(|_| env::var("https_proxy"))
+            .ok()
+            .map(|proxy| Url::parse(&proxy).expect("HTTPS_PROXY must be a valid URL"));
+
+        let no_proxy = env::var("NO_PROXY")
+            .or_else(|_| env::var("no_proxy"))
+            .map(|hosts| no_proxy_hosts(&hosts))
+            .unwrap_or_default();
+
+        HttpSettings {
+            ca_bundle: env::var("CA_BUNDLE").ok().map(PathBuf::from),
+            proxy,
+            no_proxy,
+        }
+    }
+
+    /// A client builder with the certificates and proxy already set up.
+    pub fn client_builder(&self) -> Result<ClientBuilder, Error> {
+        let mut builder = Client::builder();
+
+        if let Some(ref path) = self.ca_bundle {
+            let bundle = fs::read_to_string(path)
+                .map_err(|err| format_err!("Unable to read CA bundle {}: {}", path.display(), err))?;
+
+            for certificate in pem_certificates(&bundle) {
+                builder.add_root_certificate(Certificate::from_pem(certificate.as_bytes())?);
+            }
+        }
+
+        if let Some(ref proxy) = self.proxy {
+            let proxy = proxy.clone();
+            let no_proxy = self.no_proxy.clone();
+
+            builder.proxy(Proxy::custom(move |url| {
+                if skips_proxy(&no_proxy, url) {
+                    None
+                } else {
+                    Some(proxy.clone())
+                }
+            }));
+        }
+
+        Ok(builder)
+    }
+}
+
+/// Splits `NO_PROXY`, like `ghe.example.com,.internal`, into its hosts.
+/// Leading dots are dropped, since subdomains match either way.
+fn no_proxy_hosts(hosts: &str) -> Vec<String> {
+    hosts
+        .split(',')
+        .map(|host| host.trim().trim_start_matches('.').to_lowercase())
+        .filter(|host| !host.is_empty())
+        .collect()
+}
+
+fn skips_proxy(no_proxy: &[String], url: &Url) -> bool {
+    let host = match url.host_str() {
+        Some(host) => host.to_lowercase(),
+        None => return false,
+    };
+
+    no_proxy
+        .iter()
+        .any(|skipped| skipped == "*" || host == *skipped || host.ends_with(&format!(".{}", skipped)))
+}
+
+/// Splits a PEM bundle into its certificates, since they can only be loaded
+/// one at a time.
+fn pem_certificates(bundle: &str) -> Vec<String> {
+    bundle
+        .split(PEM_CERTIFICATE_END)
+        .filter(|block| block.contains("-----BEGIN CERTIFICATE-----"))
+        .map(|block| format!("{}{}\n", block.trim_start(), PEM_CERTIFICATE_END))
+        .collect()
+}
@@ -1,11 +1,17 @@
+extern crate base64;
 extern crate chrono;
+extern crate chrono_tz;
 #[macro_use]
 extern crate diesel;
+extern crate diesel_migrations;
 #[macro_use]
 extern crate failure;
 extern crate futures;
 #[macro_use]
 extern crate hyper;
+#[cfg(feature = "desktop-notifications")]
+extern crate notify_rust;
+extern crate openssl;
 extern crate reqwest;
 extern crate serde;
 #[macro_use]
@@ -20,43 +26,207 @@ extern crate tokio_threadpool;
 extern crate tokio_timer;
 extern crate url;
 
+mod admin;
+mod clock;
+mod desktop_notifier;
+mod dry_run;
 mod github;
+mod http_settings;
+mod matrix_client;
+mod migrations;
+mod onboarding;
+mod reminders;
 mod review_handler;
 mod schema;
+mod secrets;
+mod server;
+mod sinks;
+mod storage;
+mod task_rules;
 mod todoist_client;
+mod working_hours;
 
+use chrono::{DateTime, NaiveDateTime, Utc};
 use failure::Error;
 use futures::future::{self, Either};
 use futures::prelude::*;
+use futures::sync::mpsc;
 use std::env;
-use tokio_core::reactor::Core as TokioCore;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio_core::reactor::{Core as TokioCore, Handle};
+use tokio_timer::Interval;
 use url::Url;
 
-use github::GithubClient;
-use review_handler::ReviewHandler;
-use todoist_client::TodoistClient;
+use github::{CiState, GithubClient, PollingCursor, PullRequest};
+use review_handler::{OpenRequest, Recorded, ReviewHandler, ReviewState, TrackedRequest};
+use sinks::Sink;
 
-pub struct Config<'a> {
+pub use admin::{
+    find_missed_reviews, forget_review, list_reviews, retry_deliveries, show_review, sync_reviews, Backfill,
+    BackfillReport, MissedReview, ReviewRef, TrackedReview,
+};
+pub use clock::Clock;
+pub use dry_run::DryRun;
+pub use github::{
+    GithubAccount, GithubApp, GithubCredentials, NotificationMark, ReviewSource, UserCredentials, UserTokenRefresh,
+    DEFAULT_ACCOUNT,
+};
+pub use http_settings::HttpSettings;
+pub use migrations::{built_in_migrations, migrate, migration_status, prepare_database, MigrationStatus};
+pub use onboarding::{OauthApp, OnboardingSettings};
+pub use reminders::Reminders;
+pub use review_handler::Delivery;
+pub use secrets::SecretKey;
+pub use server::{add_user, run_server};
+pub use task_rules::TaskRules;
+pub use todoist_client::TodoistSettings;
+pub use working_hours::WorkingHours;
+
+#[derive(Clone)]
+pub struct Config {
     pub logger: slog::Logger,
-    pub core: &'a TokioCore,
+    /// The reactor the HTTP clients run on
+    pub handle: Handle,
     pub todoist_base: Url,
+    pub todoist: TodoistSettings,
+    pub task_rules: TaskRules,
+    pub working_hours: Option<WorkingHours>,
+    /// What holding review requests and due dates go by
+    pub clock: Clock,
+    /// When set, review requests wait for CI to pass before being delivered,
+    /// unless it has been failing for longer than this.
+    pub wait_for_ci: Option<chrono::Duration>,
+    pub reminders: Option<Reminders>,
+    /// When set, a single batch of review requests is handled, instead of
+    /// polling forever. Fails if a review request is left undelivered.
+    pub once: bool,
+    /// When set, nothing is changed: review requests go through the whole
+    /// pipeline, but what would be delivered or stored is only logged.
+    pub dry_run: Option<DryRun>,
+    /// When set, notifications are polled starting this long ago, instead of
+    /// resuming from where the last run stopped.
+    pub look_back: Option<Duration>,
+    /// When set, notifications are marked like this once their review request
+    /// was delivered.
+    pub mark_notifications: Option<NotificationMark>,
+    pub review_source: ReviewSource,
+    /// The root of the REST API, like `https://ghe.example.com/api/v3/` for
+    /// GitHub Enterprise Server
     pub github_base: Url,
+    /// The GraphQL endpoint, when it can't be worked out from `github_base`
+    pub github_graphql_url: Option<Url>,
+    /// The GitHub identities whose review requests are tracked, at least one
+    pub github_accounts: Vec<GithubAccount>,
+    pub http: HttpSettings,
+    pub matrix_base: Option<Url>,
+    pub desktop_notifications: bool,
     pub database_url: String,
+    /// Encrypts the credentials of the users served in server mode
+    pub secret_key: Option<SecretKey>,
+    /// When set, server mode lets users sign up through OAuth
+    pub onboarding: Option<OnboardingSettings>,
 }
 
-impl<'a> Config<'a> {
+impl Config {
     pub fn defaults(logger: slog::Logger, core: &TokioCore) -> Config {
         Config {
             logger,
-            core,
+            handle: core.handle(),
             todoist_base: Url::parse("https://beta.todoist.com").unwrap(),
-            github_base: Url::parse("https://api.github.com").unwrap(),
+            todoist: TodoistSettings::from_env(),
+            task_rules: TaskRules::from_env(),
+            working_hours: WorkingHours::from_env(),
+            clock: Clock::System,
+            wait_for_ci: wait_for_ci_from_env(),
+            reminders: Reminders::from_env(),
+            once: false,
+            dry_run: None,
+            look_back: None,
+            mark_notifications: mark_notifications_from_env(),
+            review_source: review_source_from_env(),
+            github_base: github_base_from_env(),
+            github_graphql_url: env::var("GITHUB_GRAPHQL_URL")
+                .ok()
+                .map(|url| Url::parse(&url).expect("GITHUB_GRAPHQL_URL must be a valid URL")),
+            github_accounts: GithubAccount::from_env(),
+            http: HttpSettings::from_env(),
+            matrix_base: env::var("MATRIX_HOMESERVER")
+                .ok()
+                .map(|url| Url::parse(&url).expect("MATRIX_HOMESERVER must be a valid URL")),
+            desktop_notifications: env_flag("DESKTOP_NOTIFICATIONS"),
             database_url: env::var("DATABASE_URL").expect("DATABASE_URL must be set"),
+            secret_key: SecretKey::from_env(),
+            onboarding: OnboardingSettings::from_env(),
         }
     }
 }
 
+fn github_base_from_env() -> Url {
+    let base = env::var("GITHUB_API_URL").unwrap_or_else(|_| "https://api.github.com/".to_string());
+
+    // Paths are joined onto the base, which drops its last segment unless it
+    // ends with a slash
+    let base = if base.ends_with('/') { base } else { base + "/" };
+
+    Url::parse(&base).expect("GITHUB_API_URL must be a valid URL")
+}
+
+fn wait_for_ci_from_env() -> Option<chrono::Duration> {
+    if !env_flag("WAIT_FOR_CI") {
+        return None;
+    }
+
+    let threshold_minutes = env::var("CI_FAILURE_THRESHOLD_MINUTES")
+        .map(|minutes| {
+            minutes
+                .parse()
+                .expect("CI_FAILURE_THRESHOLD_MINUTES must be a number of minutes")
+        })
+        .unwrap_or(DEFAULT_CI_FAILURE_THRESHOLD_MINUTES);
+
+    Some(chrono::Duration::minutes(threshold_minutes))
+}
+
+fn mark_notifications_from_env() -> Option<NotificationMark> {
+    match env::var("MARK_NOTIFICATIONS").ok()?.as_str() {
+        "read" => Some(NotificationMark::Read),
+        "done" => Some(NotificationMark::Done),
+        _ => panic!("MARK_NOTIFICATIONS must be either read or done"),
+    }
+}
+
+fn review_source_from_env() -> ReviewSource {
+    let source = match env::var("REVIEW_SOURCE") {
+        Ok(source) => source,
+        Err(_) => return ReviewSource::Notifications,
+    };
+
+    match source.as_str() {
+        "notifications" => ReviewSource::Notifications,
+        "search" => ReviewSource::Search,
+        "both" => ReviewSource::Both,
+        _ => panic!("REVIEW_SOURCE must be one of notifications, search or both"),
+    }
+}
+
+fn env_flag(name: &str) -> bool {
+    match env::var(name) {
+        Ok(value) => value == "1" || value.eq_ignore_ascii_case("true"),
+        Err(_) => false,
+    }
+}
+
 pub fn run(config: Config) -> impl Future<Item = (), Error = Error> {
+    match review_handler::new(&config) {
+        Ok(handler) => Either::A(start(config, handler)),
+        Err(err) => Either::B(future::err(err)),
+    }
+}
+
+/// Runs the review requests of the configured accounts through the sinks,
+/// storing them with `handler`.
+fn start(config: Config, handler: ReviewHandler) -> impl Future<Item = (), Error = Error> {
     macro_rules! early_error {
         ($e:expr) => {
             match $e {
@@ -66,47 +236,1212 @@ pub fn run(config: Config) -> impl Future<Item = (), Error = Error> {
         };
     }
 
-    let main_future = build_main_future(State {
-        github_client: early_error!(github::new_client(&config)),
-        todoist_client: early_error!(TodoistClient::new(&config)),
-        handler: early_error!(review_handler::new(&config)),
-    });
+    let state = State {
+        github_clients: early_error!(github_clients(&config, &handler)),
+        sinks: early_error!(sinks::from_config(&config)),
+        handler,
+        working_hours: config.working_hours.clone(),
+        clock: config.clock,
+        wait_for_ci: config.wait_for_ci,
+        reminders: config.reminders.clone(),
+        review_source: config.review_source,
+        once: config.once,
+        logger: config.logger.clone(),
+    };
+
+    let look_back = config.look_back;
+    // Nothing is stored in a dry run, so there are no deliveries to check.
+    // Only the deliveries this run attempted are checked.
+    let delivery_check = if config.once && config.dry_run.is_none() {
+        Some((state.handler.clone(), Utc::now().naive_utc()))
+    } else {
+        None
+    };
+
+    let main_future = future::lazy(move || restore_polling_cursors(state, look_back))
+        .and_then(|state| refresh_auth(&state.github_clients).map(move |_| state))
+        .and_then(build_main_future)
+        .and_then(move |_| match delivery_check {
+            Some((handler, started_at)) => Either::A(admin::check_deliveries(&handler, started_at)),
+            None => Either::B(future::ok(())),
+        });
 
     Either::B(main_future)
 }
 
+/// One client per configured account, in the configured order.
+fn github_clients(config: &Config, handler: &ReviewHandler) -> Result<Vec<GithubClient>, Error> {
+    if config.github_accounts.is_empty() {
+        return Err(format_err!("At least one GitHub account must be configured"));
+    }
+
+    config
+        .github_accounts
+        .iter()
+        .map(|account| github::new_client(config, account, Arc::new(handler.clone())))
+        .collect()
+}
+
+/// Gets the clients ready to make requests, which GitHub Apps need an
+/// installation token for.
+fn refresh_auth(github_clients: &[GithubClient]) -> impl Future<Item = (), Error = Error> {
+    let refreshes: Vec<_> = github_clients.iter().map(GithubClient::refresh_auth).collect();
+    future::join_all(refreshes).map(|_| ())
+}
+
+/// Makes the clients poll from `look_back` ago if given, or else from where
+/// the last run stopped for their account. Without either, the client's
+/// default applies.
+fn restore_polling_cursors(state: State, look_back: Option<Duration>) -> impl Future<Item = State, Error = Error> {
+    if let Some(look_back) = look_back {
+        for client in &state.github_clients {
+            client.poll_since(SystemTime::now() - look_back);
+        }
+
+        return Either::A(future::ok(state));
+    }
+
+    let cursors: Vec<_> = state
+        .github_clients
+        .iter()
+        .map(|client| state.handler.polling_cursor(client.account()))
+        .collect();
+
+    let restored = future::join_all(cursors).map(move |cursors| {
+        for (client, maybe_cursor) in state.github_clients.iter().zip(cursors) {
+            let cursor = match maybe_cursor {
+                Some(cursor) => cursor,
+                None => continue,
+            };
+
+            let logger = state.logger.new(o!("account" => cursor.account.clone()));
+
+            match client.resume_from(&cursor) {
+                Ok(_) => info!(logger, "Resuming notification polling"; "polled_at" => %cursor.polled_at),
+                Err(err) => warn!(logger, "Ignoring stored polling cursor"; "error" => %err),
+            }
+        }
+
+        state
+    });
+
+    Either::B(restored)
+}
+
+/// The client of the account a review request belongs to. Requests of an
+/// account that is no longer configured fall back to the first client.
+fn client_for<'a>(github_clients: &'a [GithubClient], account: &str) -> &'a GithubClient {
+    github_clients
+        .iter()
+        .find(|client| client.account() == account)
+        .unwrap_or(&github_clients[0])
+}
+
 struct State {
-    github_client: GithubClient,
-    todoist_client: TodoistClient,
+    /// One client per account, in the configured order
+    github_clients: Vec<GithubClient>,
+    sinks: Vec<Sink>,
     handler: ReviewHandler,
+    working_hours: Option<WorkingHours>,
+    clock: Clock,
+    wait_for_ci: Option<chrono::Duration>,
+    reminders: Option<Reminders>,
+    review_source: ReviewSource,
+    once: bool,
+    logger: slog::Logger,
 }
 
+/// How often to check for held review requests that are due
+const RELEASE_INTERVAL_SECS: u64 = 60;
+/// How often to check the CI of review requests that wait for it, or whose
+/// CI was failing when they were delivered
+const CI_CHECK_INTERVAL_SECS: u64 = 120;
+/// How long CI can fail before review requests stop waiting for it
+const DEFAULT_CI_FAILURE_THRESHOLD_MINUTES: i64 = 4 * 60;
+/// How often to look for review requests that were left open for too long
+const REMINDER_INTERVAL_SECS: u64 = 15 * 60;
+/// How often to check whether the GitHub credentials need refreshing
+const AUTH_REFRESH_INTERVAL_SECS: u64 = 60;
+/// How often to drop cached responses that weren't fetched again for a while
+const CACHE_EVICTION_INTERVAL_SECS: u64 = 60 * 60;
+/// How long cached responses are kept after they were last fetched
+const CACHE_MAX_AGE_DAYS: i64 = 7;
+/// How often review requests found only by searching are checked for pull
+/// requests that were closed since
+const SEARCH_RECONCILE_INTERVAL_SECS: u64 = 5 * 60;
+
 fn build_main_future(state: State) -> impl Future<Item = (), Error = Error> {
     let State {
-        github_client,
-        todoist_client,
+        mut github_clients,
+        sinks,
         handler,
+        working_hours,
+        clock,
+        wait_for_ci,
+        reminders,
+        review_source,
+        once,
+        logger,
     } = state;
 
-    let stream = github_client.into_pull_request_stream();
+    let (cursor_sender, cursors) = mpsc::unbounded();
+    for client in &mut github_clients {
+        client.track_cursor(cursor_sender.clone());
+    }
+
+    let cursor_loop = save_polling_cursors(cursors, handler.clone(), logger.clone());
+    // Credentials are refreshed before starting, which lasts a single run
+    let auth_loop = if once {
+        Either::A(future::ok(()))
+    } else {
+        Either::B(refresh_github_auth(github_clients.clone(), logger.clone()))
+    };
+
+    // Requests held before holding was turned off are still released
+    let release_loop = release_held_requests(
+        github_clients.clone(),
+        handler.clone(),
+        sinks.clone(),
+        wait_for_ci,
+        clock,
+        once,
+        logger.clone(),
+    );
+
+    let waiting_loop = match wait_for_ci {
+        Some(threshold) => Either::A(watch_waiting_ci(
+            github_clients.clone(),
+            handler.clone(),
+            sinks.clone(),
+            threshold,
+            clock,
+            once,
+            logger.clone(),
+        )),
+
+        None => Either::B(idle(once)),
+    };
+
+    let reminder_loop = match reminders {
+        Some(reminders) => Either::A(remind_stale_requests(
+            handler.clone(),
+            sinks.clone(),
+            reminders,
+            working_hours.clone(),
+            clock,
+            once,
+            logger.clone(),
+        )),
+
+        None => Either::B(idle(once)),
+    };
+
+    let eviction_loop = evict_stale_cache(handler.clone(), once, logger.clone());
+
+    // Searches only find open pull requests, unlike notifications, which
+    // also come for the ones that were closed
+    let reconcile_loop = if review_source == ReviewSource::Search {
+        Either::A(reconcile_searched_reviews(
+            github_clients.clone(),
+            handler.clone(),
+            sinks.clone(),
+            once,
+            logger.clone(),
+        ))
+    } else {
+        Either::B(idle(once))
+    };
+
+    let ci_loop = if sinks.iter().any(Sink::wants_review_context) {
+        Either::A(watch_unsettled_ci(
+            github_clients.clone(),
+            handler.clone(),
+            sinks.clone(),
+            once,
+            logger,
+        ))
+    } else {
+        Either::B(idle(once))
+    };
+
+    // Each account is polled on its own, with its review requests handled one
+    // at a time as before, so that its polling cursor only moves past handled
+    // notifications
+    let accounts: Vec<_> = github_clients
+        .into_iter()
+        .map(|github_client| {
+            let client = github_client.clone();
+            let handler = handler.clone();
+            let sinks = sinks.clone();
+            let working_hours = working_hours.clone();
+
+            github_client
+                .into_pull_request_stream(once)
+                .for_each(move |(pull_request, logger)| {
+                    let record_logger = logger.new(o!("pull_request" => pull_request.number));
+
+                    if !pull_request.is_open() {
+                        return Either::A(finish_review(&handler, &sinks, pull_request, record_logger));
+                    }
+
+                    let state = initial_state(working_hours.as_ref(), wait_for_ci, clock.now());
+                    let requested = request_review(
+                        &client,
+                        &handler,
+                        &sinks,
+                        pull_request,
+                        state,
+                        wait_for_ci,
+                        record_logger,
+                    );
+                    Either::B(requested.map(|_| ()))
+                })
+        })
+        .collect();
+
+    let requests = future::join_all(accounts).map(|_| ());
+
+    // A single run handles its requests first and then makes each check in
+    // turn, so that they don't race each other over the same review
+    // requests. The cursors are saved last, once nothing polls anymore.
+    if once {
+        let run = requests
+            .and_then(|_| release_loop)
+            .and_then(|_| waiting_loop)
+            .and_then(|_| ci_loop)
+            .and_then(|_| reminder_loop)
+            .and_then(|_| reconcile_loop)
+            .and_then(|_| eviction_loop)
+            .and_then(|_| auth_loop)
+            .and_then(|_| cursor_loop);
+
+        return Either::A(run);
+    }
+
+    // Otherwise the loops only stop on errors
+    let background = release_loop
+        .join(ci_loop)
+        .join(waiting_loop)
+        .join(reminder_loop)
+        .join(cursor_loop)
+        .join(auth_loop)
+        .join(eviction_loop)
+        .join(reconcile_loop)
+        .map(|_| ());
+
+    Either::B(requests.select(background).map(|_| ()).map_err(|(err, _)| err))
+}
+
+/// Ticks every `period`, starting right away. With `once`, only ticks a
+/// single time, so that periodic checks make a single pass.
+fn ticks(period: Duration, once: bool) -> impl Stream<Item = Instant, Error = Error> {
+    let count = if once { 1 } else { std::u64::MAX };
+
+    Interval::new(Instant::now(), period).map_err(Error::from).take(count)
+}
+
+/// Stands in for a loop that isn't configured.
+fn idle(once: bool) -> impl Future<Item = (), Error = Error> {
+    if once {
+        Either::A(future::ok(()))
+    } else {
+        Either::B(future::empty())
+    }
+}
+
+/// The state review requests arriving at `now` start in. Requests held
+/// outside of working hours only start waiting for CI once released.
+fn initial_state(
+    working_hours: Option<&WorkingHours>,
+    wait_for_ci: Option<chrono::Duration>,
+    now: DateTime<Utc>,
+) -> ReviewState {
+    match working_hours {
+        Some(hours) if hours.hold_outside_hours && !hours.is_working_time(now) => {
+            return ReviewState::Held(hours.next_working_slot(now).naive_utc());
+        }
+
+        _ => {}
+    }
+
+    if wait_for_ci.is_some() {
+        ReviewState::WaitingCi
+    } else {
+        ReviewState::Delivered
+    }
+}
+
+/// Records a new review request and hands it over to the sinks, or sets it
+/// aside as `state` says. Resolves to whether it was recorded, and reached at
+/// least one sink if it was to be delivered right away.
+fn request_review(
+    github_client: &GithubClient,
+    handler: &ReviewHandler,
+    sinks: &[Sink],
+    pull_request: PullRequest,
+    state: ReviewState,
+    wait_for_ci: Option<chrono::Duration>,
+    logger: slog::Logger,
+) -> impl Future<Item = bool, Error = Error> {
+    let github_client = github_client.clone();
+    let handler = handler.clone();
+    let sinks = sinks.to_vec();
+    let known_pr = pull_request.clone();
+
+    handler
+        .record_in_task(pull_request, state, logger.clone())
+        .and_then(move |maybe_pr| {
+            let (recorded, pr) = match maybe_pr {
+                Some(recorded) => recorded,
+                None => return Either::A(sync_review(&handler, &sinks, known_pr, logger).map(|_| false)),
+            };
+
+            match (state, wait_for_ci, recorded) {
+                (ReviewState::Delivered, _, _) => {
+                    let delivery = deliver_review_request(&github_client, &handler, &sinks, recorded, pr, None, logger);
+                    Either::B(Either::A(delivery))
+                }
+
+                // A dry run doesn't keep track of CI for review requests it
+                // didn't store
+                (ReviewState::WaitingCi, Some(threshold), Recorded::Stored(review_request_id)) => {
+                    let check = check_waiting_ci(
+                        &github_client,
+                        &handler,
+                        &sinks,
+                        review_request_id,
+                        pr,
+                        threshold,
+                        logger,
+                    );
+                    Either::B(Either::B(Either::A(check.map(|_| true))))
+                }
+
+                _ => Either::B(Either::B(Either::B(future::ok(true)))),
+            }
+        })
+}
+
+/// Updates what was delivered for an already known review request, if the
+/// pull request changed since.
+fn sync_review(
+    handler: &ReviewHandler,
+    sinks: &[Sink],
+    pull_request: PullRequest,
+    logger: slog::Logger,
+) -> impl Future<Item = (), Error = Error> {
+    let sinks = sinks.to_vec();
+
+    handler.sync_review_request(&pull_request).then(move |result| {
+        let deliveries = match result {
+            Ok(Some(deliveries)) => deliveries,
+            Ok(None) => return Either::A(future::ok(())),
+
+            Err(err) => {
+                error!(logger, "Error while syncing review request"; "error" => %err);
+                return Either::A(future::ok(()));
+            }
+        };
+
+        info!(logger, "Pull request changed, updating deliveries");
+
+        let updates: Vec<_> = deliveries
+            .into_iter()
+            .filter_map(|delivery| {
+                let sink = sinks.iter().find(|sink| sink.name() == delivery.sink)?;
+                let logger = logger.new(o!("sink" => sink.name()));
+
+                let update = sink.review_updated(&pull_request, &delivery).or_else(move |err| {
+                    error!(logger, "Error while updating delivered review request"; "error" => %err);
+                    Ok(())
+                });
+
+                Some(update)
+            })
+            .collect();
+
+        Either::B(future::join_all(updates).map(|_| ()))
+    })
+}
+
+/// Delivers the review request to `sinks`, recording how each delivery went
+/// unless it was only recorded by a dry run. Resolves to whether any sink got
+/// it.
+fn deliver_review_request(
+    github_client: &GithubClient,
+    handler: &ReviewHandler,
+    sinks: &[Sink],
+    recorded: Recorded,
+    pr: PullRequest,
+    note: Option<String>,
+    logger: slog::Logger,
+) -> impl Future<Item = bool, Error = Error> {
+    let mark_client = github_client.clone();
+    let mark_logger = logger.clone();
+    let marked_pr = pr.clone();
+
+    let deliveries: Vec<_> = sinks
+        .iter()
+        .map(move |sink| {
+            let github_client = github_client.clone();
+            let handler = handler.clone();
+            let sink = sink.clone();
+            let logger = logger.new(o!("sink" => sink.name()));
+            let pr = pr.clone();
+
+            let requested = sink.review_requested(&pr, note.as_ref().map(String::as_str));
+
+            requested.then(move |result| {
+                if let Err(ref err) = result {
+                    error!(logger, "Error while delivering review request"; "error" => %err);
+                }
+
+                match (result, recorded) {
+                    (Ok(external_id), Recorded::Stored(review_request_id)) => {
+                        let context = match external_id {
+                            Some(ref external_id) if sink.wants_review_context() => Either::A(post_review_context(
+                                &github_client,
+                                &handler,
+                                &sink,
+                                review_request_id,
+                                external_id.clone(),
+                                pr,
+                                logger.clone(),
+                            )),
+
+                            _ => Either::B(future::ok(())),
+                        };
+
+                        let record_logger = logger.clone();
+                        let record = handler
+                            .record_delivery(review_request_id, sink.name(), external_id)
+                            .or_else(move |err| {
+                                error!(record_logger, "Error while recording delivery"; "error" => %err);
+                                Ok(())
+                            });
+
+                        Either::A(record.join(context).map(|_| true))
+                    }
+
+                    (Err(err), Recorded::Stored(review_request_id)) => {
+                        let failed = handler
+                            .record_delivery_failure(review_request_id, sink.name(), &err)
+                            .or_else(move |err| {
+                                error!(logger, "Error while recording delivery failure"; "error" => %err);
+                                Ok(())
+                            });
+
+                        Either::B(Either::A(failed.map(|_| false)))
+                    }
+
+                    // There's nothing stored to note how the delivery went
+                    (result, Recorded::DryRun) => Either::B(Either::B(future::ok(result.is_ok()))),
+                }
+            })
+        })
+        .collect();
+
+    future::join_all(deliveries).and_then(move |delivered| {
+        if !delivered.contains(&true) {
+            return Either::A(future::ok(false));
+        }
+
+        let marked = mark_client.mark_notification(&marked_pr).then(move |result| {
+            if let Err(err) = result {
+                error!(mark_logger, "Error while marking the notification"; "error" => %err);
+            }
+
+            Ok(true)
+        });
+
+        Either::B(marked)
+    })
+}
+
+/// Sends the pull request's changed files and CI status to a sink that was
+/// just delivered to, remembering the CI state to notice when it gets fixed.
+fn post_review_context(
+    github_client: &GithubClient,
+    handler: &ReviewHandler,
+    sink: &Sink,
+    review_request_id: i32,
+    external_id: String,
+    pr: PullRequest,
+    logger: slog::Logger,
+) -> impl Future<Item = (), Error = Error> {
+    let handler = handler.clone();
+    let sink = sink.clone();
+
+    let changed_files = github_client.changed_files(&pr);
+    let ci_status = github_client.ci_status(&pr);
+
+    changed_files
+        .join(ci_status)
+        .and_then(move |(changed_files, ci)| {
+            let record = handler.record_ci_state(review_request_id, ci.state);
+            let context = sink.review_context(&external_id, &pr, &changed_files, &ci);
+
+            context.join(record).map(|_| ())
+        })
+        .or_else(move |err| {
+            error!(logger, "Error while posting review context"; "error" => %err);
+            Ok(())
+        })
+}
+
+/// Periodically checks the CI of review requests that wait for it, delivering
+/// them once it passes. Pull requests closed in the meantime are only marked
+/// as finished.
+fn watch_waiting_ci(
+    github_clients: Vec<GithubClient>,
+    handler: ReviewHandler,
+    sinks: Vec<Sink>,
+    threshold: chrono::Duration,
+    clock: Clock,
+    once: bool,
+    logger: slog::Logger,
+) -> impl Future<Item = (), Error = Error> {
+    ticks(Duration::from_secs(CI_CHECK_INTERVAL_SECS), once).for_each(move |_| {
+        let github_clients = github_clients.clone();
+        let handler = handler.clone();
+        let sinks = sinks.clone();
+        let logger = logger.clone();
+
+        handler.waiting_ci_requests().then(move |result| {
+            let waiting_requests = match result {
+                Ok(waiting_requests) => waiting_requests,
+
+                Err(err) => {
+                    error!(logger, "Error while looking for review requests waiting for CI"; "error" => %err);
+                    return Either::A(future::ok(()));
+                }
+            };
+
+            let checks: Vec<_> = waiting_requests
+                .into_iter()
+                .map(|request| {
+                    let client = client_for(&github_clients, &request.account).clone();
+                    let handler = handler.clone();
+                    let sinks = sinks.clone();
+                    let logger = logger.new(o!("review_request" => request.id));
+                    let error_logger = logger.clone();
+
+                    fetch_tracked_pull_request(&client, &request).then(move |result| match result {
+                        Ok(pr) => {
+                            let logger = logger.new(o!("pull_request" => pr.number));
+
+                            if !pr.is_open() {
+                                return Either::A(finish_review(&handler, &sinks, pr, logger));
+                            }
+
+                            let check =
+                                check_waiting_ci(&client, &handler, &sinks, request.id, pr, threshold, clock, logger);
+                            Either::B(Either::A(check))
+                        }
+
+                        Err(err) => {
+                            error!(error_logger, "Unable to fetch pull request waiting for CI"; "error" => %err);
+                            Either::B(Either::B(future::ok(())))
+                        }
+                    })
+                })
+                .collect();
+
+            Either::B(future::join_all(checks).map(|_| ()))
+        })
+    })
+}
 
-    stream.for_each(move |(pull_request, logger)| {
-        let record_logger = logger.new(o!("pull_request" => pull_request.number));
+/// Delivers a review request that waits for CI if it passes, or if it has
+/// been failing for longer than `threshold`. In that case the delivery comes
+/// with a note about the failure.
+fn check_waiting_ci(
+    github_client: &GithubClient,
+    handler: &ReviewHandler,
+    sinks: &[Sink],
+    review_request_id: i32,
+    pr: PullRequest,
+    threshold: chrono::Duration,
+    clock: Clock,
+    logger: slog::Logger,
+) -> impl Future<Item = (), Error = Error> {
+    let client = github_client.clone();
+    let handler = handler.clone();
+    let sinks = sinks.to_vec();
+    let error_logger = logger.clone();
 
-        if !pull_request.is_open() {
-            debug!(record_logger, "Skipping closed pull request");
+    let check = github_client.ci_status(&pr).and_then(move |ci| match ci.state {
+        CiState::Pending => {
+            debug!(logger, "Still waiting for CI");
+            Either::A(handler.clear_ci_failure(review_request_id))
+        }
+
+        CiState::Success => {
+            info!(logger, "CI passed, delivering review request");
+            let release = release_waiting(&client, &handler, &sinks, review_request_id, pr, None, logger);
+            let cleared = handler.clear_ci_failure(review_request_id).and_then(move |_| release);
+            Either::B(Either::A(cleared))
+        }
+
+        CiState::Failure => {
+            let now = clock.now().naive_utc();
+
+            let release = handler.record_ci_failure(review_request_id, now).and_then(move |failing_since| {
+                if now.signed_duration_since(failing_since) < threshold {
+                    debug!(logger, "CI is failing, still waiting"; "failing_since" => %failing_since);
+                    return Either::A(future::ok(()));
+                }
+
+                info!(logger, "CI kept failing, delivering review request anyway"; "failing_since" => %failing_since);
+
+                let failing_checks: Vec<_> = ci.failing_checks().iter().map(|check| check.name.as_str()).collect();
+                let note = format!(
+                    "CI has been failing since {} UTC ({})",
+                    failing_since.format("%Y-%m-%d %H:%M"),
+                    failing_checks.join(", "),
+                );
+
+                let release = release_waiting(&client, &handler, &sinks, review_request_id, pr, Some(note), logger);
+                Either::B(release)
+            });
+
+            Either::B(Either::B(release))
+        }
+    });
+
+    check.or_else(move |err| {
+        error!(error_logger, "Error while checking CI"; "error" => %err);
+        Ok(())
+    })
+}
+
+fn release_waiting(
+    github_client: &GithubClient,
+    handler: &ReviewHandler,
+    sinks: &[Sink],
+    review_request_id: i32,
+    pr: PullRequest,
+    note: Option<String>,
+    logger: slog::Logger,
+) -> impl Future<Item = (), Error = Error> {
+    let github_client = github_client.clone();
+    let deliver_handler = handler.clone();
+    let sinks = sinks.to_vec();
+
+    handler.release_waiting_ci(review_request_id).and_then(move |released| {
+        if !released {
+            debug!(logger, "Review request was released elsewhere already");
             return Either::A(future::ok(()));
         }
 
-        let todoist_client = todoist_client.clone();
-        let result = handler
-            .record_in_task(pull_request, record_logger)
-            .and_then(move |maybe_pr| match maybe_pr {
-                Some(pr) => Either::A(todoist_client.create_task_for_pr(&pr)),
+        let delivery = deliver_review_request(
+            &github_client,
+            &deliver_handler,
+            &sinks,
+            Recorded::Stored(review_request_id),
+            pr,
+            note,
+            logger,
+        );
+        Either::B(delivery.map(|_| ()))
+    })
+}
+
+/// Periodically checks the CI of review requests that were delivered while
+/// it was pending or failing, and tells the sinks once it passes or fails.
+fn watch_unsettled_ci(
+    github_clients: Vec<GithubClient>,
+    handler: ReviewHandler,
+    sinks: Vec<Sink>,
+    once: bool,
+    logger: slog::Logger,
+) -> impl Future<Item = (), Error = Error> {
+    ticks(Duration::from_secs(CI_CHECK_INTERVAL_SECS), once).for_each(move |_| {
+        let github_clients = github_clients.clone();
+        let handler = handler.clone();
+        let sinks = sinks.clone();
+        let logger = logger.clone();
+
+        handler.unsettled_ci_requests().then(move |result| {
+            let unsettled_requests = match result {
+                Ok(unsettled_requests) => unsettled_requests,
+
+                Err(err) => {
+                    error!(logger, "Error while looking for review requests with unsettled CI"; "error" => %err);
+                    return Either::A(future::ok(()));
+                }
+            };
+
+            let checks: Vec<_> = unsettled_requests
+                .into_iter()
+                .map(|(request, last_state)| {
+                    let logger = logger.new(o!("review_request" => request.id));
+                    let client = client_for(&github_clients, &request.account);
+                    check_unsettled_ci(client, &handler, &sinks, request, last_state, logger)
+                })
+                .collect();
+
+            Either::B(future::join_all(checks).map(|_| ()))
+        })
+    })
+}
+
+/// Fetches the pull request of a review request tracked earlier, along with
+/// the notification and account it came from.
+fn fetch_tracked_pull_request(
+    github_client: &GithubClient,
+    request: &TrackedRequest,
+) -> impl Future<Item = PullRequest, Error = Error> {
+    let thread_id = request.notification_thread_id.clone();
+    let account = request.account.clone();
+
+    github_client.get_pull_request(&request.api_url).map(move |mut pr| {
+        pr.notification_thread_id = thread_id;
+        pr.account = account;
+        pr
+    })
+}
+
+fn check_unsettled_ci(
+    github_client: &GithubClient,
+    handler: &ReviewHandler,
+    sinks: &[Sink],
+    request: TrackedRequest,
+    last_state: CiState,
+    logger: slog::Logger,
+) -> impl Future<Item = (), Error = Error> {
+    let status_client = github_client.clone();
+    let handler = handler.clone();
+    let sinks = sinks.to_vec();
+    let error_logger = logger.clone();
+
+    let ci_status = github_client.get_pull_request(&request.api_url).and_then(move |pr| {
+        if pr.is_open() {
+            Either::A(status_client.ci_status(&pr).map(Some))
+        } else {
+            Either::B(future::ok(None))
+        }
+    });
+
+    let changed = ci_status.and_then(move |maybe_ci| {
+        let ci = match maybe_ci {
+            Some(ref ci) if ci.state != last_state => ci.clone(),
+            _ => return Either::A(future::ok(())),
+        };
+
+        info!(logger, "CI state changed"; "ci_state" => %ci.state);
+
+        let record = handler.record_ci_state(request.id, ci.state);
+
+        // Going back to pending is only worth a note once it settles again
+        if ci.state == CiState::Pending {
+            return Either::B(Either::A(record));
+        }
+
+        let notifications = handler.deliveries_for(request.id).and_then(move |deliveries| {
+            let notifications: Vec<_> = deliveries
+                .into_iter()
+                .filter_map(|delivery| {
+                    let sink = sinks.iter().find(|sink| sink.name() == delivery.sink)?;
+                    let logger = logger.new(o!("sink" => sink.name()));
+
+                    let notification = sink.ci_changed(&delivery, &ci).or_else(move |err| {
+                        error!(logger, "Error while notifying changed CI"; "error" => %err);
+                        Ok(())
+                    });
+
+                    Some(notification)
+                })
+                .collect();
+
+            future::join_all(notifications)
+        });
+
+        Either::B(Either::B(record.join(notifications).map(|_| ())))
+    });
+
+    changed.or_else(move |err| {
+        error!(error_logger, "Error while checking CI"; "error" => %err);
+        Ok(())
+    })
+}
+
+/// Periodically delivers the review requests that were held outside of
+/// working hours. Pull requests are fetched again, so that requests that
+/// were closed in the meantime are only marked as finished.
+fn release_held_requests(
+    github_clients: Vec<GithubClient>,
+    handler: ReviewHandler,
+    sinks: Vec<Sink>,
+    wait_for_ci: Option<chrono::Duration>,
+    clock: Clock,
+    once: bool,
+    logger: slog::Logger,
+) -> impl Future<Item = (), Error = Error> {
+    ticks(Duration::from_secs(RELEASE_INTERVAL_SECS), once).for_each(move |_| {
+        let github_clients = github_clients.clone();
+        let handler = handler.clone();
+        let sinks = sinks.clone();
+        let logger = logger.clone();
+
+        let now = clock.now().naive_utc();
+        let retry_at = now + chrono::Duration::seconds(RELEASE_INTERVAL_SECS as i64);
+
+        handler.take_due_held_requests(now).then(move |result| {
+            let held_requests = match result {
+                Ok(held_requests) => held_requests,
+
+                Err(err) => {
+                    error!(logger, "Error while looking for held review requests"; "error" => %err);
+                    return Either::A(future::ok(()));
+                }
+            };
+
+            let releases: Vec<_> = held_requests
+                .into_iter()
+                .map(|request| {
+                    let logger = logger.new(o!("review_request" => request.id));
+                    let client = client_for(&github_clients, &request.account);
+                    release_held_request(client, &handler, &sinks, request, retry_at, wait_for_ci, logger)
+                })
+                .collect();
+
+            Either::B(future::join_all(releases).map(|_| ()))
+        })
+    })
+}
+
+fn release_held_request(
+    github_client: &GithubClient,
+    handler: &ReviewHandler,
+    sinks: &[Sink],
+    request: TrackedRequest,
+    retry_at: NaiveDateTime,
+    wait_for_ci: Option<chrono::Duration>,
+    logger: slog::Logger,
+) -> impl Future<Item = (), Error = Error> {
+    let client = github_client.clone();
+    let handler = handler.clone();
+    let sinks = sinks.to_vec();
+
+    fetch_tracked_pull_request(github_client, &request).then(move |result| {
+        let pr = match result {
+            Ok(pr) => pr,
+
+            Err(err) => {
+                error!(logger, "Unable to fetch held pull request, holding it again"; "error" => %err);
+
+                let hold = handler
+                    .update_state(request.id, ReviewState::Held(retry_at))
+                    .or_else(move |err| {
+                        error!(logger, "Error while holding review request"; "error" => %err);
+                        Ok(())
+                    });
+
+                return Either::A(hold);
+            }
+        };
+
+        let logger = logger.new(o!("pull_request" => pr.number));
+
+        if !pr.is_open() {
+            return Either::B(Either::A(finish_review(&handler, &sinks, pr, logger)));
+        }
 
-                None => Either::B(future::ok(())),
+        info!(logger, "Releasing held review request");
+
+        let threshold = match wait_for_ci {
+            Some(threshold) => threshold,
+            None => {
+                let recorded = Recorded::Stored(request.id);
+                let delivery = deliver_review_request(&client, &handler, &sinks, recorded, pr, None, logger);
+                return Either::B(Either::B(Either::A(delivery.map(|_| ()))));
+            }
+        };
+
+        let check = check_waiting_ci(&client, &handler, &sinks, request.id, pr, threshold, logger.clone());
+        let waiting = handler
+            .update_state(request.id, ReviewState::WaitingCi)
+            .and_then(move |_| check);
+
+        Either::B(Either::B(Either::B(waiting)))
+    })
+}
+
+/// Stores the polling cursors reported by the client, so that the next run can
+/// resume from them.
+fn save_polling_cursors(
+    cursors: impl Stream<Item = PollingCursor, Error = ()>,
+    handler: ReviewHandler,
+    logger: slog::Logger,
+) -> impl Future<Item = (), Error = Error> {
+    cursors
+        .map_err(|_| format_err!("Polling cursor channel closed unexpectedly"))
+        .for_each(move |cursor| {
+            let logger = logger.clone();
+
+            handler.save_polling_cursor(cursor).or_else(move |err| {
+                error!(logger, "Error while saving polling cursor"; "error" => %err);
+                Ok(())
+            })
+        })
+}
+
+/// Periodically fetches the pull requests of unfinished review requests,
+/// finishing the ones whose pull request was closed or merged.
+fn reconcile_searched_reviews(
+    github_clients: Vec<GithubClient>,
+    handler: ReviewHandler,
+    sinks: Vec<Sink>,
+    once: bool,
+    logger: slog::Logger,
+) -> impl Future<Item = (), Error = Error> {
+    ticks(Duration::from_secs(SEARCH_RECONCILE_INTERVAL_SECS), once).for_each(move |_| {
+        let github_clients = github_clients.clone();
+        let handler = handler.clone();
+        let sinks = sinks.clone();
+        let logger = logger.clone();
+
+        handler.unfinished_requests().then(move |result| {
+            let unfinished_requests = match result {
+                Ok(unfinished_requests) => unfinished_requests,
+
+                Err(err) => {
+                    error!(logger, "Error while looking for unfinished review requests"; "error" => %err);
+                    return Either::A(future::ok(()));
+                }
+            };
+
+            let checks: Vec<_> = unfinished_requests
+                .into_iter()
+                .map(|request| {
+                    let client = client_for(&github_clients, &request.account);
+                    let handler = handler.clone();
+                    let sinks = sinks.clone();
+                    let logger = logger.new(o!("review_request" => request.id));
+
+                    fetch_tracked_pull_request(client, &request).then(move |result| match result {
+                        Ok(ref pr) if pr.is_open() => Either::A(future::ok(())),
+
+                        Ok(pr) => {
+                            let logger = logger.new(o!("pull_request" => pr.number));
+                            Either::B(finish_review(&handler, &sinks, pr, logger))
+                        }
+
+                        Err(err) => {
+                            error!(logger, "Unable to fetch pull request of review request"; "error" => %err);
+                            Either::A(future::ok(()))
+                        }
+                    })
+                })
+                .collect();
+
+            Either::B(future::join_all(checks).map(|_| ()))
+        })
+    })
+}
+
+/// Periodically drops the cached responses that weren't fetched again for
+/// `CACHE_MAX_AGE_DAYS`, which are mostly for pull requests nobody looks at
+/// anymore.
+fn evict_stale_cache(
+    handler: ReviewHandler,
+    once: bool,
+    logger: slog::Logger,
+) -> impl Future<Item = (), Error = Error> {
+    ticks(Duration::from_secs(CACHE_EVICTION_INTERVAL_SECS), once).for_each(move |_| {
+        let logger = logger.clone();
+        let fetched_before = Utc::now().naive_utc() - chrono::Duration::days(CACHE_MAX_AGE_DAYS);
+
+        handler.evict_cached_responses(fetched_before).then(move |result| {
+            match result {
+                Ok(evicted) => debug!(logger, "Evicted stale cached responses"; "count" => evicted),
+                Err(err) => error!(logger, "Error while evicting cached responses"; "error" => %err),
+            }
+
+            Ok(())
+        })
+    })
+}
+
+/// Keeps the GitHub credentials of every account fresh. Errors are logged,
+/// leaving the next check to try again before the current token expires.
+fn refresh_github_auth(
+    github_clients: Vec<GithubClient>,
+    logger: slog::Logger,
+) -> impl Future<Item = (), Error = Error> {
+    let interval = Interval::new(
+        Instant::now() + Duration::from_secs(AUTH_REFRESH_INTERVAL_SECS),
+        Duration::from_secs(AUTH_REFRESH_INTERVAL_SECS),
+    );
+
+    interval.map_err(Error::from).for_each(move |_| {
+        let refreshes: Vec<_> = github_clients
+            .iter()
+            .map(|client| {
+                let logger = logger.new(o!("account" => client.account().to_string()));
+
+                client.refresh_auth().or_else(move |err| {
+                    error!(logger, "Error while refreshing GitHub credentials"; "error" => %err);
+                    Ok(())
+                })
+            })
+            .collect();
+
+        future::join_all(refreshes).map(|_| ())
+    })
+}
+
+/// Periodically looks for delivered review requests that have been open for
+/// `reminders.after_days` working days, escalating their deliveries and
+/// sending a reminder through the reminder sink. Each request is only
+/// reminded about once.
+fn remind_stale_requests(
+    handler: ReviewHandler,
+    sinks: Vec<Sink>,
+    reminders: Reminders,
+    working_hours: Option<WorkingHours>,
+    clock: Clock,
+    once: bool,
+    logger: slog::Logger,
+) -> impl Future<Item = (), Error = Error> {
+    ticks(Duration::from_secs(REMINDER_INTERVAL_SECS), once).for_each(move |_| {
+        let handler = handler.clone();
+        let sinks = sinks.clone();
+        let reminders = reminders.clone();
+        let working_hours = working_hours.clone();
+        let logger = logger.clone();
+
+        // Working days are never fewer than calendar days, so this only
+        // narrows down the candidates
+        let now = clock.now();
+        let created_before = now.naive_utc() - chrono::Duration::days(i64::from(reminders.after_days));
+
+        handler.unreminded_requests(created_before).then(move |result| {
+            let open_requests = match result {
+                Ok(open_requests) => open_requests,
+
+                Err(err) => {
+                    error!(logger, "Error while looking for stale review requests"; "error" => %err);
+                    return Either::A(future::ok(()));
+                }
+            };
+
+            let reminders: Vec<_> = open_requests
+                .into_iter()
+                .filter(|request| match working_hours {
+                    Some(ref hours) => {
+                        let created_at = DateTime::from_utc(request.created_at, Utc);
+                        hours.working_days_between(created_at, now) >= reminders.after_days
+                    }
+
+                    None => true,
+                })
+                .map(|request| {
+                    let logger = logger.new(o!("review_request" => request.id));
+                    remind_stale_request(&handler, &sinks, reminders.sink.as_ref(), request, clock, logger)
+                })
+                .collect();
+
+            Either::B(future::join_all(reminders).map(|_| ()))
+        })
+    })
+}
+
+fn remind_stale_request(
+    handler: &ReviewHandler,
+    sinks: &[Sink],
+    reminder_sink: Option<&String>,
+    request: OpenRequest,
+    clock: Clock,
+    logger: slog::Logger,
+) -> impl Future<Item = (), Error = Error> {
+    let record_handler = handler.clone();
+    let sinks = sinks.to_vec();
+    let reminder_sink = reminder_sink.cloned();
+    let record_logger = logger.clone();
+    let review_request_id = request.id;
+
+    info!(logger, "Review request is still open, sending reminder"; "created_at" => %request.created_at);
+
+    let escalations = handler.deliveries_for(request.id).and_then(move |deliveries| {
+        let escalations = deliveries.into_iter().filter_map(|delivery| {
+            let sink = sinks.iter().find(|sink| sink.name() == delivery.sink)?;
+            let logger = logger.new(o!("sink" => sink.name()));
+
+            let escalation = sink.escalate(&delivery).or_else(move |err| {
+                error!(logger, "Error while escalating delivered review request"; "error" => %err);
+                Ok(())
+            });
+
+            Some(Either::A(escalation))
+        });
+
+        let reminder = reminder_sink
+            .and_then(|name| sinks.iter().find(|sink| sink.name() == name))
+            .map(|sink| {
+                let logger = logger.new(o!("sink" => sink.name()));
+
+                let reminder = sink.remind(&request).or_else(move |err| {
+                    error!(logger, "Error while sending reminder"; "error" => %err);
+                    Ok(())
+                });
+
+                Either::B(reminder)
             });
 
-        Either::B(result)
+        let notifications: Vec<_> = escalations.chain(reminder).collect();
+        future::join_all(notifications)
+    });
+
+    escalations
+        .and_then(move |_| record_handler.record_reminder(review_request_id, clock.now().naive_utc()))
+        .or_else(move |err| {
+            error!(record_logger, "Error while reminding about review request"; "error" => %err);
+            Ok(())
+        })
+}
+
+fn finish_review(
+    handler: &ReviewHandler,
+    sinks: &[Sink],
+    pull_request: PullRequest,
+    logger: slog::Logger,
+) -> impl Future<Item = (), Error = Error> {
+    let sinks = sinks.to_vec();
+
+    handler.finish_review(&pull_request).then(move |result| {
+        let deliveries = match result {
+            Ok(Some(deliveries)) => deliveries,
+
+            Ok(None) => {
+                debug!(logger, "Skipping closed pull request");
+                return Either::A(future::ok(()));
+            }
+
+            Err(err) => {
+                error!(logger, "Error while finishing review request"; "error" => %err);
+                return Either::A(future::ok(()));
+            }
+        };
+
+        info!(logger, "Review finished");
+
+        let notifications: Vec<_> = deliveries
+            .into_iter()
+            .filter_map(|delivery| {
+                let sink = sinks.iter().find(|sink| sink.name() == delivery.sink)?;
+                let logger = logger.new(o!("sink" => sink.name()));
+
+                let notification = sink.review_finished(&delivery).or_else(move |err| {
+                    error!(logger, "Error while notifying finished review"; "error" => %err);
+                    Ok(())
+                });
+
+                Some(notification)
+            })
+            .collect();
+
+        Either::B(future::join_all(notifications).map(|_| ()))
     })
 }
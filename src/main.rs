@@ -1,5 +1,8 @@
+extern crate chrono;
+extern crate clap;
 extern crate dotenv;
 extern crate env_logger;
+#[macro_use]
 extern crate failure;
 extern crate futures;
 extern crate openssl_probe;
@@ -10,27 +13,244 @@ extern crate slog_async;
 extern crate slog_term;
 extern crate tokio_core;
 
+use clap::{App, Arg, ArgMatches, SubCommand};
 use dotenv::dotenv;
 use failure::Error;
 use slog::Drain;
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::time::Duration;
 use tokio_core::reactor::Core as TokioCore;
 
-use reviewist::Config;
+use reviewist::{Config, DryRun, MigrationStatus, MissedReview, ReviewRef, TrackedReview};
 
 fn main() {
+    let matches = App::new("reviewist")
+        .about("Turns GitHub review requests into tasks and notifications")
+        .arg(
+            Arg::with_name("look-back-days")
+                .long("look-back-days")
+                .value_name("DAYS")
+                .help("Polls notifications from this many days ago, instead of resuming where the last run stopped")
+                .takes_value(true)
+                .validator(validate_days),
+        )
+        .arg(
+            Arg::with_name("server")
+                .long("server")
+                .help("Serves every user added with add-user, instead of the configured accounts"),
+        )
+        .arg(
+            Arg::with_name("once")
+                .long("once")
+                .conflicts_with("server")
+                .help("Handles a single batch of review requests and exits, failing if any was left undelivered"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .conflicts_with("server")
+                .help("Only logs what would be delivered or stored, handling a single batch without a subcommand"),
+        )
+        .arg(
+            Arg::with_name("no-migrate")
+                .long("no-migrate")
+                .help("Starts without running pending database migrations"),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Runs the pending database migrations")
+                .subcommand(SubCommand::with_name("status").about("Lists the applied and pending database migrations")),
+        )
+        .subcommand(
+            SubCommand::with_name("add-user")
+                .about("Adds a user to serve in server mode, or updates their credentials")
+                .arg(Arg::with_name("NAME").required(true))
+                .arg(
+                    Arg::with_name("github-token")
+                        .long("github-token")
+                        .help("Sets the user's GitHub token, read from ADD_USER_GITHUB_TOKEN or else from stdin"),
+                )
+                .arg(
+                    Arg::with_name("todoist-token")
+                        .long("todoist-token")
+                        .help("Sets the user's Todoist token, read from ADD_USER_TODOIST_TOKEN or else from stdin"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Lists the review requests waiting to be reviewed")
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .help("Lists finished review requests as well"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("show")
+                .about("Shows what is stored about a pull request's review request")
+                .arg(pull_request_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("forget")
+                .about("Deletes a pull request's review request, so that it's delivered anew next time")
+                .arg(pull_request_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("retry").about("Delivers review requests again to the sinks they didn't make it to"),
+        )
+        .subcommand(
+            SubCommand::with_name("backfill")
+                .about("Creates review requests for open pull requests from before the notifications go back")
+                .arg(
+                    Arg::with_name("yes")
+                        .long("yes")
+                        .help("Creates them without asking for confirmation"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("sync").about("Finishes closed pull requests and updates changed ones, once"))
+        .get_matches();
+
     let logger = configure_slog();
     env_logger::init();
     openssl_probe::init_ssl_cert_env_vars();
     dotenv().ok();
 
+    // A dry run applies to subcommands as well, and sums up what it would
+    // have done at the end
+    let dry_run = if matches.is_present("dry-run") {
+        Some(DryRun::new(&logger))
+    } else {
+        None
+    };
+
     let result = TokioCore::new().map_err(Error::from).and_then(|mut core| {
-        let future = reviewist::run(Config::defaults(logger.clone(), &core));
+        let mut config = Config::defaults(logger.clone(), &core);
+        config.look_back = look_back(&matches);
+        config.dry_run = dry_run.clone();
+
+        // Without a subcommand, a dry run handles a single batch
+        config.once = matches.is_present("once") || (dry_run.is_some() && matches.subcommand_name().is_none());
+
+        if let Some(migrate) = matches.subcommand_matches("migrate") {
+            if migrate.subcommand_matches("status").is_some() {
+                return reviewist::migration_status(&config).map(|status| print_migration_status(&status));
+            }
+
+            // A dry run's summary tells which migrations would have run
+            let migrated = reviewist::migrate(&config)?;
+            if config.dry_run.is_none() {
+                println!("Applied {} migration(s)", migrated.len());
+            }
+
+            return Ok(());
+        }
+
+        // Refuses to start on a schema from a newer reviewist, and leaves the
+        // database alone in a dry run
+        let run_migrations = !matches.is_present("no-migrate") && dry_run.is_none();
+        reviewist::prepare_database(&config, run_migrations)?;
+
+        if let Some(add_user) = matches.subcommand_matches("add-user") {
+            // Tokens are kept out of the arguments, which other users can see
+            let github_token = read_token(add_user, "github-token", "GitHub token", "ADD_USER_GITHUB_TOKEN")?;
+            let todoist_token = read_token(add_user, "todoist-token", "Todoist token", "ADD_USER_TODOIST_TOKEN")?;
+
+            let future = reviewist::add_user(
+                &config,
+                add_user.value_of("NAME").unwrap(),
+                github_token.as_ref().map(String::as_str),
+                todoist_token.as_ref().map(String::as_str),
+            );
+
+            return core.run(future);
+        }
+
+        match matches.subcommand() {
+            ("list", Some(list)) => {
+                let future = reviewist::list_reviews(&config, list.is_present("all"));
+                return core.run(future).map(|reviews| print_reviews(&reviews));
+            }
+
+            ("show", Some(show)) => {
+                let future = reviewist::show_review(&config, &pull_request(show));
+                return core
+                    .run(future)
+                    .map(|reviews| reviews.iter().for_each(print_review_details));
+            }
+
+            ("forget", Some(forget)) => {
+                let review = pull_request(forget);
+                let future = reviewist::forget_review(&config, &review);
+                return core
+                    .run(future)
+                    .map(|forgotten| println!("Forgot {} review request(s) for {}", forgotten, review));
+            }
+
+            ("retry", Some(_)) => {
+                let future = reviewist::retry_deliveries(&config);
+                return core
+                    .run(future)
+                    .map(|retried| println!("Retried {} review request(s)", retried));
+            }
+
+            ("sync", Some(_)) => {
+                let future = reviewist::sync_reviews(&config);
+                return core
+                    .run(future)
+                    .map(|synced| println!("Synced {} review request(s)", synced));
+            }
+
+            ("backfill", Some(backfill)) => {
+                let found = core.run(reviewist::find_missed_reviews(&config))?;
+
+                if found.reviews.is_empty() {
+                    println!("No review requests to backfill");
+                    return Ok(());
+                }
+
+                print_missed_reviews(&found.reviews);
+
+                let question = format!("Create {} review request(s)?", found.reviews.len());
+                if !backfill.is_present("yes") && !confirm(&question)? {
+                    println!("Nothing was created");
+                    return Ok(());
+                }
+
+                let report = core.run(found.create())?;
+                println!("Created {} review request(s)", report.created);
+
+                if report.failed.is_empty() {
+                    return Ok(());
+                }
+
+                let failed: Vec<_> = report.failed.iter().map(ReviewRef::to_string).collect();
+                return Err(format_err!(
+                    "Couldn't create review requests for {}, see the log for why",
+                    failed.join(", ")
+                ));
+            }
+
+            _ => {}
+        }
 
-        core.run(future)
+        if matches.is_present("server") {
+            return core.run(reviewist::run_server(config));
+        }
+
+        core.run(reviewist::run(config))
     });
 
     let err = match result {
-        Ok(_) => return,
+        Ok(_) => {
+            if let Some(dry_run) = dry_run {
+                print_dry_run_summary(&dry_run);
+            }
+
+            return;
+        }
+
         Err(err) => err,
     };
 
@@ -39,6 +259,202 @@ fn main() {
     } else {
         error!(logger, "critical error"; "cause" => ?err.cause());
     }
+
+    // Let the async drain flush before exiting
+    drop(logger);
+    process::exit(1);
+}
+
+fn print_migration_status(status: &MigrationStatus) {
+    for name in &status.applied {
+        println!("applied  {}", name);
+    }
+
+    for name in &status.pending {
+        println!("pending  {}", name);
+    }
+
+    for version in &status.unknown {
+        println!("unknown  {} (from a newer reviewist)", version);
+    }
+}
+
+fn print_missed_reviews(reviews: &[MissedReview]) {
+    println!("Open pull requests without a review request:");
+
+    for review in reviews {
+        println!(
+            "{:<24} {:<10} {} ({})",
+            review.pull_request.to_string(),
+            review.account,
+            review.title,
+            review.url
+        );
+    }
+}
+
+/// Asks a yes or no question on the terminal, defaulting to no.
+fn confirm(question: &str) -> Result<bool, Error> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+
+    let answer = answer.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+/// Reads the token asked for with `flag` from `env_var`, or else from a line
+/// of stdin.
+fn read_token(matches: &ArgMatches, flag: &str, name: &str, env_var: &str) -> Result<Option<String>, Error> {
+    if !matches.is_present(flag) {
+        return Ok(None);
+    }
+
+    if let Ok(token) = env::var(env_var) {
+        return Ok(Some(token));
+    }
+
+    eprint!("{}: ", name);
+    io::stderr().flush()?;
+
+    let mut token = String::new();
+    io::stdin().lock().read_line(&mut token)?;
+
+    match token.trim() {
+        "" => Err(format_err!("No {} was given", name)),
+        token => Ok(Some(token.to_string())),
+    }
+}
+
+fn print_dry_run_summary(dry_run: &DryRun) {
+    let summary = dry_run.summary();
+
+    if summary.is_empty() {
+        println!("Dry run: nothing would have changed");
+        return;
+    }
+
+    println!("Dry run: nothing was changed, but it would have");
+    for (action, count) in summary {
+        println!("  {} {} time(s)", action, count);
+    }
+}
+
+fn pull_request_arg() -> Arg<'static, 'static> {
+    Arg::with_name("PULL_REQUEST")
+        .required(true)
+        .help("Like reviewist#12, or octocat/reviewist#12")
+        .validator(|value| value.parse::<ReviewRef>().map(|_| ()).map_err(|err| err.to_string()))
+}
+
+fn pull_request(matches: &ArgMatches) -> ReviewRef {
+    matches.value_of("PULL_REQUEST").unwrap().parse().unwrap()
+}
+
+fn print_reviews(reviews: &[TrackedReview]) {
+    if reviews.is_empty() {
+        println!("No review requests");
+        return;
+    }
+
+    for review in reviews {
+        println!(
+            "{:<24} {:<10} {:<10} {:>8}  {:<28} {}",
+            review.pull_request.to_string(),
+            review.account,
+            status(review),
+            format_age(review.age()),
+            sink_status(review),
+            review.title
+        );
+    }
+}
+
+fn print_review_details(review: &TrackedReview) {
+    println!("{} {}", review.pull_request, review.title);
+    println!("  url:       {}", review.url);
+    println!("  account:   {}", review.account);
+    println!("  state:     {}", status(review));
+    println!("  created:   {} ({} ago)", review.created_at, format_age(review.age()));
+
+    if let Some(ref ci_state) = review.ci_state {
+        println!("  ci:        {}", ci_state);
+    }
+
+    if let Some(held_until) = review.held_until {
+        println!("  held:      until {}", held_until);
+    }
+
+    if let Some(reminded_at) = review.reminded_at {
+        println!("  reminded:  {}", reminded_at);
+    }
+
+    if let Some(finished_at) = review.finished_at {
+        println!("  finished:  {}", finished_at);
+    }
+
+    for delivery in &review.deliveries {
+        match delivery.external_id {
+            Some(ref external_id) => println!("  {:<10} delivered as {}", format!("{}:", delivery.sink), external_id),
+            None => println!("  {:<10} delivered", format!("{}:", delivery.sink)),
+        }
+    }
+
+    for failure in &review.failures {
+        println!(
+            "  {:<10} failed at {}: {}",
+            format!("{}:", failure.sink),
+            failure.failed_at,
+            failure.error
+        );
+    }
+}
+
+fn status(review: &TrackedReview) -> &str {
+    if review.finished_at.is_some() {
+        "finished"
+    } else {
+        &review.state
+    }
+}
+
+/// Like `todoist matrix(failed)`, or `-` before anything was delivered
+fn sink_status(review: &TrackedReview) -> String {
+    let delivered = review.deliveries.iter().map(|delivery| delivery.sink.clone());
+    let failed = review.failures.iter().map(|failure| format!("{}(failed)", failure.sink));
+    let sinks: Vec<_> = delivered.chain(failed).collect();
+
+    if sinks.is_empty() {
+        "-".to_string()
+    } else {
+        sinks.join(" ")
+    }
+}
+
+/// Like `3d 4h`, `2h 5m` or `12m`
+fn format_age(age: chrono::Duration) -> String {
+    let minutes = age.num_minutes().max(0);
+
+    if minutes >= 24 * 60 {
+        format!("{}d {}h", minutes / (24 * 60), minutes % (24 * 60) / 60)
+    } else if minutes >= 60 {
+        format!("{}h {}m", minutes / 60, minutes % 60)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+fn validate_days(days: String) -> Result<(), String> {
+    days.parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| "must be a number of days".to_string())
+}
+
+fn look_back(matches: &ArgMatches) -> Option<Duration> {
+    let days: u64 = matches.value_of("look-back-days")?.parse().ok()?;
+    Some(Duration::from_secs(days * 24 * 60 * 60))
 }
 
 fn configure_slog() -> slog::Logger {
@@ -0,0 +1,245 @@
+use failure::Error;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use reqwest::header::{Authorization, Headers};
+use reqwest::unstable::async::Client;
+use slog::Logger;
+use std::env;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+use Config;
+use dry_run::{self, DryRun};
+use github::PullRequest;
+use review_handler::OpenRequest;
+
+#[derive(Clone)]
+pub struct MatrixClient {
+    http: Client,
+    logger: Logger,
+    host: Url,
+    room_id: String,
+    txn_prefix: String,
+    txn_counter: Arc<AtomicUsize>,
+    dry_run: Option<DryRun>,
+}
+
+#[derive(Serialize)]
+struct RoomMessage {
+    msgtype: &'static str,
+    body: String,
+    format: &'static str,
+    formatted_body: String,
+}
+
+#[derive(Serialize)]
+struct Reaction {
+    #[serde(rename = "m.relates_to")]
+    relates_to: Annotation,
+}
+
+#[derive(Serialize)]
+struct Annotation {
+    rel_type: &'static str,
+    event_id: String,
+    key: &'static str,
+}
+
+#[derive(Deserialize)]
+struct SendResponse {
+    event_id: String,
+}
+
+const REVIEW_FINISHED_KEY: &str = "✅";
+
+impl MatrixClient {
+    pub fn new(config: &Config, homeserver: &Url) -> Result<MatrixClient, Error> {
+        let access_token = env::var("MATRIX_ACCESS_TOKEN")?;
+        let room_id = env::var("MATRIX_ROOM_ID")?;
+
+        let client = config
+            .http
+            .client_builder()?
+            .default_headers(default_headers(access_token))
+            .timeout(Duration::from_secs(30))
+            .build(&config.handle)?;
+
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH)?;
+
+        Ok(MatrixClient {
+            http: client,
+            logger: config.logger.clone(),
+            host: homeserver.clone(),
+            room_id,
+            txn_prefix: format!("reviewist-{}", started_at.as_secs()),
+            txn_counter: Arc::new(AtomicUsize::new(0)),
+            dry_run: config.dry_run.clone(),
+        })
+    }
+
+    /// Posts the review request to the room, returning the id of the created event.
+    pub fn send_review_request(
+        &self,
+        pr: &PullRequest,
+        note: Option<&str>,
+    ) -> impl Future<Item = String, Error = Error> {
+        let message = RoomMessage::for_pull_request(pr, note);
+        self.send_event("m.room.message", &message)
+    }
+
+    /// Reminds the room of a review request that has been open for a while.
+    pub fn send_reminder(&self, request: &OpenRequest) -> impl Future<Item = (), Error = Error> {
+        let message = RoomMessage::for_reminder(request);
+        self.send_event("m.room.message", &message).map(|_| ())
+    }
+
+    /// Reacts to a previously sent review request to mark it as done.
+    pub fn mark_review_finished(&self, event_id: String) -> impl Future<Item = (), Error = Error> {
+        let reaction = Reaction {
+            relates_to: Annotation {
+                rel_type: "m.annotation",
+                event_id,
+                key: REVIEW_FINISHED_KEY,
+            },
+        };
+
+        self.send_event("m.reaction", &reaction).map(|_| ())
+    }
+
+    fn send_event<T: ::serde::Serialize>(
+        &self,
+        event_type: &str,
+        content: &T,
+    ) -> impl Future<Item = String, Error = Error> {
+        if let Some(ref dry_run) = self.dry_run {
+            dry_run.record(&format!("send matrix {} event", event_type), content);
+            return Either::A(future::ok(dry_run::PLACEHOLDER_ID.to_string()));
+        }
+
+        let url = match self.event_url(event_type) {
+            Ok(url) => url,
+            Err(err) => return Either::A(future::err(err)),
+        };
+
+        let logger = self.logger.clone();
+        let request = self.http.put(url).json(content).send();
+
+        let result = request.map_err(Error::from).and_then(move |mut response| {
+            if response.status().is_success() {
+                let event = response
+                    .json::<SendResponse>()
+                    .map(|response| response.event_id)
+                    .map_err(Error::from);
+
+                return Either::A(event);
+            }
+
+            error!(logger, "Error while sending matrix event"; "response" => ?response);
+            Either::B(future::err(format_err!(
+                "Error while sending matrix event. response: {:?}",
+                response
+            )))
+        });
+
+        Either::B(result)
+    }
+
+    fn event_url(&self, event_type: &str) -> Result<Url, Error> {
+        let txn_number = self.txn_counter.fetch_add(1, Ordering::Relaxed);
+        let txn_id = format!("{}-{}", self.txn_prefix, txn_number);
+
+        let mut url = self.host.join("_matrix/client/r0/rooms/")?;
+        url.path_segments_mut()
+            .map_err(|_| format_err!("Invalid matrix homeserver: {}", self.host))?
+            .pop_if_empty()
+            .extend(&[self.room_id.as_str(), "send", event_type, txn_id.as_str()]);
+
+        Ok(url)
+    }
+}
+
+impl RoomMessage {
+    fn for_pull_request(pr: &PullRequest, note: Option<&str>) -> RoomMessage {
+        let mut body = format!(
+            "Review requested: {project}#{number}: {title} {url}",
+            project = pr.repo(),
+            number = pr.number,
+            title = pr.title,
+            url = pr.html_url,
+        );
+
+        let mut formatted_body = format!(
+            "Review requested: <a href=\"{url}\">{project}#{number}</a>: {title}",
+            url = escape_html(&pr.html_url),
+            project = escape_html(pr.repo()),
+            number = pr.number,
+            title = escape_html(&pr.title),
+        );
+
+        if let Some(note) = note {
+            body.push_str(&format!("\n{}", note));
+            formatted_body.push_str(&format!("<br>{}", escape_html(note)));
+        }
+
+        RoomMessage {
+            msgtype: "m.text",
+            body,
+            format: "org.matrix.custom.html",
+            formatted_body,
+        }
+    }
+
+    fn for_reminder(request: &OpenRequest) -> RoomMessage {
+        let body = format!(
+            "Review still pending since {date}: {project}#{number}: {title} {url}",
+            date = request.created_at.format("%Y-%m-%d"),
+            project = request.project,
+            number = request.pr_number,
+            title = request.pr_title,
+            url = request.pr_url,
+        );
+
+        let formatted_body = format!(
+            "Review still pending since {date}: <a href=\"{url}\">{project}#{number}</a>: {title}",
+            date = request.created_at.format("%Y-%m-%d"),
+            url = escape_html(&request.pr_url),
+            project = escape_html(&request.project),
+            number = escape_html(&request.pr_number),
+            title = escape_html(&request.pr_title),
+        );
+
+        RoomMessage {
+            msgtype: "m.text",
+            body,
+            format: "org.matrix.custom.html",
+            formatted_body,
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn default_headers(access_token: String) -> Headers {
+    let mut headers = Headers::new();
+    let auth_header = Authorization(format!("Bearer {}", access_token));
+    headers.set(auth_header);
+
+    headers
+}
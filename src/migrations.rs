@@ -0,0 +1,211 @@
+use diesel::connection::SimpleConnection;
+use diesel_migrations::{self, Migration, MigrationConnection, MigrationError, RunMigrationsError};
+use failure::Error;
+use std::collections::HashSet;
+use std::io;
+
+use Config;
+use storage;
+
+/// A migration built into the binary so that it can migrate the database by
+/// itself.
+pub struct EmbeddedMigration {
+    version: &'static str,
+    name: &'static str,
+    up_sql: &'static str,
+}
+
+impl Migration for EmbeddedMigration {
+    fn version(&self) -> &str {
+        self.version
+    }
+
+    fn run(&self, conn: &dyn SimpleConnection) -> Result<(), RunMigrationsError> {
+        conn.batch_execute(self.up_sql).map_err(Into::into)
+    }
+
+    /// Migrations are only ever run forward from the binary, reverting them
+    /// is left to the diesel CLI.
+    fn revert(&self, _conn: &dyn SimpleConnection) -> Result<(), RunMigrationsError> {
+        let message = format!("{} can't be reverted by reviewist, use the diesel CLI", self.name);
+        Err(MigrationError::IoError(io::Error::new(io::ErrorKind::InvalidInput, message)).into())
+    }
+}
+
+macro_rules! migration {
+    ($dir:expr, $version:expr, $name:expr) => {
+        EmbeddedMigration {
+            version: $version,
+            name: $name,
+            up_sql: include_str!(concat!("../", $dir, "/", $name, "/up.sql")),
+        }
+    };
+}
+
+/// Every migration in `migrations/`, oldest first. New migrations need to be
+/// added here as well, which the tests check through `built_in_migrations`.
+pub const SQLITE_MIGRATIONS: &[EmbeddedMigration] = &[
+    migration!(
+        "migrations",
+        "20180421153833",
+        "2018-04-21-153833_create_review_requests"
+    ),
+    migration!("migrations", "20180424022825", "2018-04-24-022825_add_pr_title"),
+    migration!("migrations", "20180512181502", "2018-05-12-181502_create_deliveries"),
+    migration!("migrations", "20180520093011", "2018-05-20-093011_add_held_until"),
+    migration!("migrations", "20180526140247", "2018-05-26-140247_add_content_hash"),
+    migration!("migrations", "20180602171925", "2018-06-02-171925_add_ci_state"),
+    migration!("migrations", "20180609102214", "2018-06-09-102214_add_review_state"),
+    migration!("migrations", "20180616083510", "2018-06-16-083510_add_reminded_at"),
+    migration!(
+        "migrations",
+        "20180623110245",
+        "2018-06-23-110245_create_polling_cursors"
+    ),
+    migration!("migrations", "20180630094120", "2018-06-30-094120_create_http_cache"),
+    migration!(
+        "migrations",
+        "20180707152033",
+        "2018-07-07-152033_add_notification_thread_id"
+    ),
+    migration!("migrations", "20180714093512", "2018-07-14-093512_add_account"),
+    migration!("migrations", "20180721101204", "2018-07-21-101204_create_users"),
+    migration!(
+        "migrations",
+        "20180811101530",
+        "2018-08-11-101530_create_delivery_failures"
+    ),
+    migration!(
+        "migrations",
+        "20180818094512",
+        "2018-08-18-094512_unique_review_requests"
+    ),
+];
+
+/// Every migration in `migrations_postgres/`, oldest first. PostgreSQL support
+/// came later, so these start from the schema SQLite had by then.
+pub const POSTGRES_MIGRATIONS: &[EmbeddedMigration] = &[
+    migration!(
+        "migrations_postgres",
+        "20180728143012",
+        "2018-07-28-143012_create_tables"
+    ),
+];
+
+/// The names of the migrations built in for SQLite and PostgreSQL, in that
+/// order, oldest first.
+pub fn built_in_migrations() -> (Vec<&'static str>, Vec<&'static str>) {
+    let names = |migrations: &[EmbeddedMigration]| -> Vec<&'static str> {
+        migrations.iter().map(|migration| migration.name).collect()
+    };
+    (names(SQLITE_MIGRATIONS), names(POSTGRES_MIGRATIONS))
+}
+
+/// Where the database schema is at, compared to the migrations built in.
+#[derive(Debug, Clone, Default)]
+pub struct MigrationStatus {
+    /// The built-in migrations that were run, by name
+    pub applied: Vec<String>,
+    /// The built-in migrations that weren't run yet, by name, oldest first
+    pub pending: Vec<String>,
+    /// The versions of migrations that were run but aren't built in, which
+    /// means a newer reviewist migrated the database
+    pub unknown: Vec<String>,
+}
+
+impl MigrationStatus {
+    /// Fails if the schema is newer than this reviewist understands.
+    fn ensure_known(&self) -> Result<(), Error> {
+        if self.unknown.is_empty() {
+            return Ok(());
+        }
+
+        Err(format_err!(
+            "The database was migrated by a newer reviewist, refusing to use it. Unknown migrations: {}",
+            self.unknown.join(", ")
+        ))
+    }
+}
+
+/// Compares the migrations that were run on the database with the built-in
+/// ones.
+pub fn migration_status(config: &Config) -> Result<MigrationStatus, Error> {
+    storage::connect(&config.database_url)?.migration_status()
+}
+
+/// Runs the pending migrations, returning their names. Databases migrated by a
+/// newer reviewist are left alone, and a dry run only records the migrations.
+pub fn migrate(config: &Config) -> Result<Vec<String>, Error> {
+    let storage = storage::connect(&config.database_url)?;
+    let status = storage.migration_status()?;
+    status.ensure_known()?;
+
+    if let Some(ref dry_run) = config.dry_run {
+        for name in &status.pending {
+            dry_run.record("run migration", name);
+        }
+    } else if !status.pending.is_empty() {
+        info!(config.logger, "Running migrations"; "pending" => status.pending.join(", "));
+        storage.run_migrations()?;
+    }
+
+    Ok(status.pending)
+}
+
+/// Gets the database ready on startup: it's migrated unless `run_pending` is
+/// false, in which case pending migrations are only warned about. Fails if the
+/// schema is newer than this reviewist understands either way.
+pub fn prepare_database(config: &Config, run_pending: bool) -> Result<(), Error> {
+    if run_pending {
+        return migrate(config).map(|_| ());
+    }
+
+    let status = migration_status(config)?;
+    status.ensure_known()?;
+
+    if !status.pending.is_empty() {
+        warn!(config.logger, "The database has pending migrations"; "pending" => status.pending.join(", "));
+    }
+
+    Ok(())
+}
+
+/// Compares the migrations that were run on `conn` with `migrations`. A new
+/// database doesn't have the table keeping track of migrations yet, which the
+/// backend tells with `tracked`, so that checking doesn't create it.
+pub fn status_of<C: MigrationConnection>(
+    conn: &C,
+    migrations: &[EmbeddedMigration],
+    tracked: bool,
+) -> Result<MigrationStatus, Error> {
+    let run_versions = if tracked {
+        conn.previously_run_migration_versions()?
+    } else {
+        HashSet::new()
+    };
+
+    let mut status = MigrationStatus::default();
+
+    for migration in migrations {
+        if run_versions.contains(migration.version) {
+            status.applied.push(migration.name.to_string());
+        } else {
+            status.pending.push(migration.name.to_string());
+        }
+    }
+
+    let mut unknown: Vec<String> = run_versions
+        .into_iter()
+        .filter(|version| !migrations.iter().any(|migration| migration.version == version))
+        .collect();
+    unknown.sort();
+    status.unknown = unknown;
+
+    Ok(status)
+}
+
+/// Runs the migrations among `migrations` that weren't run on `conn` yet.
+pub fn run_pending<C: MigrationConnection>(conn: &C, migrations: &[EmbeddedMigration]) -> Result<(), Error> {
+    let migrations = migrations.iter().map(|migration| migration as &dyn Migration);
+    diesel_migrations::run_migrations(conn, migrations, &mut io::sink()).map_err(Error::from)
+}
@@ -0,0 +1,598 @@
+use chrono::Utc;
+use failure::Error;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use futures::unsync::mpsc;
+use hyper::header::{Accept, Authorization, ContentType, Cookie, Location, SetCookie};
+use hyper::server::{Http, Request, Response, service_fn};
+use hyper::{self, Method, StatusCode};
+use reqwest::unstable::async::Client;
+use slog::Logger;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+use url::{Url, form_urlencoded};
+
+use Config;
+use review_handler::ReviewHandler;
+use secrets::{self, SecretKey};
+use server;
+
+/// How long users have to get through a provider's authorization page
+const STATE_LIFETIME_SECS: i64 = 10 * 60;
+/// How long users who connected GitHub have to connect Todoist as well
+const SESSION_LIFETIME_SECS: i64 = 60 * 60;
+/// Holds the nonce an authorization's state is bound to, so that only the
+/// browser that started it can finish it
+const NONCE_COOKIE: &str = "reviewist_onboarding_nonce";
+/// Holds who the user is, as told by GitHub
+const SESSION_COOKIE: &str = "reviewist_onboarding_session";
+
+/// An OAuth app registered with a provider, which gets tokens on behalf of
+/// the users who authorize it
+#[derive(Clone, Debug)]
+pub struct OauthApp {
+    pub client_id: String,
+    pub client_secret: String,
+    /// Where the provider's OAuth endpoints are, like `https://github.com/`
+    pub base: Url,
+}
+
+/// Endpoints that let users sign up for server mode by authorizing reviewist
+/// on GitHub and Todoist, instead of handing their tokens over. Users go to
+/// `/onboard/github` first, which names them after their GitHub login, and
+/// then to `/onboard/todoist` in the same browser. Their polling loop starts
+/// once both are authorized. Anyone who can reach the endpoints can sign up,
+/// so they are meant for the team's network only.
+#[derive(Clone, Debug)]
+pub struct OnboardingSettings {
+    /// The address the endpoints listen on
+    pub listen: SocketAddr,
+    /// Where users reach the endpoints, which the providers redirect back to
+    pub public_url: Url,
+    pub github: Option<OauthApp>,
+    pub todoist: Option<OauthApp>,
+}
+
+impl OnboardingSettings {
+    /// Reads the settings, enabled by setting `ONBOARDING_LISTEN`. Each
+    /// provider is enabled by setting its client id, like `GITHUB_CLIENT_ID`.
+    pub fn from_env() -> Option<OnboardingSettings> {
+        let listen = env::var("ONBOARDING_LISTEN")
+            .ok()?
+            .parse()
+            .expect("ONBOARDING_LISTEN must be an address like 127.0.0.1:8080");
+
+        let public_url = env::var("ONBOARDING_URL").unwrap_or_else(|_| format!("http://{}/", listen));
+        let public_url = Url::parse(&public_url).expect("ONBOARDING_URL must be a valid URL");
+
+        Some(OnboardingSettings {
+            listen,
+            public_url,
+            github: oauth_app_from_env("GITHUB", "https://github.com/"),
+            todoist: oauth_app_from_env("TODOIST", "https://todoist.com/"),
+        })
+    }
+}
+
+fn oauth_app_from_env(provider: &str, default_base: &str) -> Option<OauthApp> {
+    let client_id = env::var(format!("{}_CLIENT_ID", provider)).ok()?;
+
+    let secret_var = format!("{}_CLIENT_SECRET", provider);
+    let client_secret = env::var(&secret_var).unwrap_or_else(|_| panic!("{} must be set", secret_var));
+
+    let base_var = format!("{}_OAUTH_URL", provider);
+    let base = env::var(&base_var).unwrap_or_else(|_| default_base.to_string());
+
+    Some(OauthApp {
+        client_id,
+        client_secret,
+        base: Url::parse(&base).unwrap_or_else(|_| panic!("{} must be a valid URL", base_var)),
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Provider {
+    Github,
+    Todoist,
+}
+
+impl Provider {
+    fn from_name(name: &str) -> Option<Provider> {
+        match name {
+            "github" => Some(Provider::Github),
+            "todoist" => Some(Provider::Todoist),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Provider::Github => "github",
+            Provider::Todoist => "todoist",
+        }
+    }
+
+    fn authorize_path(&self) -> &'static str {
+        match *self {
+            Provider::Github => "login/oauth/authorize",
+            Provider::Todoist => "oauth/authorize",
+        }
+    }
+
+    fn token_path(&self) -> &'static str {
+        match *self {
+            Provider::Github => "login/oauth/access_token",
+            Provider::Todoist => "oauth/access_token",
+        }
+    }
+
+    /// Reading notifications needs `notifications`, and the pull requests of
+    /// private repositories need `repo`
+    fn scope(&self) -> &'static str {
+        match *self {
+            Provider::Github => "notifications repo",
+            Provider::Todoist => "data:read_write",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GithubUser {
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Clone)]
+struct Onboarding {
+    settings: OnboardingSettings,
+    handler: ReviewHandler,
+    key: SecretKey,
+    http: Client,
+    /// Where the GitHub API is, to find out who authorized reviewist
+    github_base: Url,
+    /// The nonces of the authorizations that were started and not finished
+    /// yet, with when they were started. Each can only be finished once.
+    pending: Rc<RefCell<HashMap<String, i64>>>,
+    /// Told about every user whose credentials changed, to start their loop
+    saved_users: mpsc::UnboundedSender<()>,
+    logger: Logger,
+}
+
+/// Serves the onboarding endpoints until the reactor stops. `saved_users` is
+/// told whenever a user's credentials were stored.
+pub fn serve(
+    config: &Config,
+    handler: &ReviewHandler,
+    key: &SecretKey,
+    saved_users: mpsc::UnboundedSender<()>,
+) -> Result<impl Future<Item = (), Error = Error>, Error> {
+    let settings = config
+        .onboarding
+        .clone()
+        .ok_or_else(|| format_err!("Onboarding is not configured"))?;
+
+    let http = config
+        .http
+        .client_builder()?
+        .timeout(Duration::from_secs(30))
+        .build(&config.handle)?;
+
+    let logger = config.logger.new(o!("onboarding" => settings.listen.to_string()));
+    info!(logger, "Serving onboarding endpoints"; "url" => %settings.public_url);
+
+    let onboarding = Onboarding {
+        settings,
+        handler: handler.clone(),
+        key: key.clone(),
+        http,
+        github_base: config.github_base.clone(),
+        pending: Rc::new(RefCell::new(HashMap::new())),
+        saved_users,
+        logger,
+    };
+
+    let new_service = {
+        let onboarding = onboarding.clone();
+        move || {
+            let onboarding = onboarding.clone();
+            Ok(service_fn(move |request| respond(&onboarding, request)))
+        }
+    };
+
+    let handle = config.handle.clone();
+    let connections = Http::new().serve_addr_handle(&onboarding.settings.listen, &handle, new_service)?;
+
+    let served = connections.map_err(Error::from).for_each(move |connection| {
+        let logger = onboarding.logger.clone();
+
+        handle.spawn(connection.map(|_| ()).map_err(move |err| {
+            debug!(logger, "Onboarding connection failed"; "error" => %err);
+        }));
+
+        Ok(())
+    });
+
+    Ok(served)
+}
+
+fn respond(onboarding: &Onboarding, request: Request) -> impl Future<Item = Response, Error = hyper::Error> {
+    let params: HashMap<String, String> = form_urlencoded::parse(request.query().unwrap_or("").as_bytes())
+        .into_owned()
+        .collect();
+    let param = |name: &str| params.get(name).cloned();
+    let cookie = |name: &str| {
+        request
+            .headers()
+            .get::<Cookie>()
+            .and_then(|cookies| cookies.get(name))
+            .map(str::to_string)
+    };
+
+    let segments: Vec<&str> = request.path().trim_matches('/').split('/').collect();
+    let provider = match (request.method(), segments.as_slice()) {
+        (&Method::Get, &[action, provider]) => Provider::from_name(provider).map(|provider| (action, provider)),
+        _ => None,
+    };
+
+    match provider {
+        Some(("onboard", provider)) => Either::A(future::ok(start_authorization(
+            onboarding,
+            provider,
+            cookie(SESSION_COOKIE),
+        ))),
+
+        Some(("callback", provider)) => match (param("code"), param("state"), cookie(NONCE_COOKIE)) {
+            (Some(code), Some(state), Some(nonce)) => {
+                let callback = Callback {
+                    provider,
+                    code,
+                    state,
+                    nonce,
+                    session: cookie(SESSION_COOKIE),
+                };
+                Either::B(finish_authorization(onboarding, callback))
+            }
+
+            _ => Either::A(future::ok(text_response(
+                StatusCode::BadRequest,
+                "Missing code, state or cookie",
+            ))),
+        },
+
+        _ => Either::A(future::ok(text_response(StatusCode::NotFound, "Not found"))),
+    }
+}
+
+/// Sends the user to the provider's authorization page, with a state bound to
+/// a nonce in a cookie. Todoist can only be authorized by users who connected
+/// GitHub first, which tells who they are.
+fn start_authorization(onboarding: &Onboarding, provider: Provider, session: Option<String>) -> Response {
+    let app = match onboarding.app(provider) {
+        Some(app) => app,
+        None => return text_response(StatusCode::NotFound, "This provider is not configured"),
+    };
+
+    let connected_github = session
+        .and_then(|session| onboarding.user_from_session(&session).ok())
+        .is_some();
+    if provider == Provider::Todoist && !connected_github {
+        return text_response(StatusCode::Unauthorized, "Connect GitHub at /onboard/github first");
+    }
+
+    let now = Utc::now().timestamp();
+    let nonce = match secrets::random_token() {
+        Ok(nonce) => nonce,
+        Err(err) => {
+            error!(onboarding.logger, "Unable to start authorization"; "error" => %err);
+            return text_response(StatusCode::InternalServerError, "Unable to start authorization");
+        }
+    };
+
+    let state = format!("{}\n{}\n{}", provider.name(), nonce, now);
+
+    let authorize_url = onboarding.key.encrypt(&state).and_then(|state| {
+        let mut url = app.base.join(provider.authorize_path())?;
+        url.query_pairs_mut()
+            .append_pair("client_id", &app.client_id)
+            .append_pair("redirect_uri", onboarding.redirect_uri(provider)?.as_str())
+            .append_pair("scope", provider.scope())
+            .append_pair("state", &state);
+
+        Ok(url)
+    });
+
+    match authorize_url {
+        Ok(url) => {
+            let cookie = onboarding.cookie(NONCE_COOKIE, &nonce, STATE_LIFETIME_SECS);
+
+            let mut pending = onboarding.pending.borrow_mut();
+            pending.retain(|_, started_at| now - *started_at <= STATE_LIFETIME_SECS);
+            pending.insert(nonce, now);
+
+            Response::new()
+                .with_status(StatusCode::Found)
+                .with_header(Location::new(url.into_string()))
+                .with_header(SetCookie(vec![cookie]))
+        }
+
+        Err(err) => {
+            error!(onboarding.logger, "Unable to start authorization"; "error" => %err);
+            text_response(StatusCode::InternalServerError, "Unable to start authorization")
+        }
+    }
+}
+
+/// What the provider sent the user back with, and the cookies they had
+struct Callback {
+    provider: Provider,
+    code: String,
+    state: String,
+    nonce: String,
+    session: Option<String>,
+}
+
+/// Exchanges the code the provider sent the user back with for a token, and
+/// stores it for the user. Users are named after the GitHub login the token
+/// belongs to, and the Todoist token goes to the user GitHub told about.
+fn finish_authorization(
+    onboarding: &Onboarding,
+    callback: Callback,
+) -> impl Future<Item = Response, Error = hyper::Error> {
+    let provider = callback.provider;
+
+    let app = match onboarding.app(provider) {
+        Some(app) => app.clone(),
+        None => {
+            return Either::A(future::ok(text_response(
+                StatusCode::NotFound,
+                "This provider is not configured",
+            )))
+        }
+    };
+
+    let checked = onboarding
+        .check_state(&callback)
+        .and_then(|_| match (provider, callback.session.as_ref()) {
+            (Provider::Github, _) => Ok(None),
+            (Provider::Todoist, Some(session)) => Ok(Some(onboarding.user_from_session(session)?)),
+            (Provider::Todoist, None) => Err(format_err!("No session")),
+        });
+
+    let session_user = match checked {
+        Ok(session_user) => session_user,
+        Err(err) => {
+            warn!(onboarding.logger, "Rejected authorization"; "error" => %err);
+            return Either::A(future::ok(text_response(
+                StatusCode::BadRequest,
+                "Invalid or expired state",
+            )));
+        }
+    };
+
+    let logger = onboarding.logger.new(o!("provider" => provider.name()));
+    let handler = onboarding.handler.clone();
+    let key = onboarding.key.clone();
+    let saved_users = onboarding.saved_users.clone();
+    let session_onboarding = onboarding.clone();
+
+    let onboarding = onboarding.clone();
+    let token = onboarding.exchange_code(provider, &app, callback.code);
+    let saved = token
+        .and_then(move |token| match session_user {
+            Some(user) => Either::A(future::ok((user, token))),
+            None => Either::B(onboarding.github_login(&token).map(move |user| (user, token))),
+        })
+        .and_then(move |(user, token)| {
+            let (github_token, todoist_token) = match provider {
+                Provider::Github => (Some(token), None),
+                Provider::Todoist => (None, Some(token)),
+            };
+
+            server::save_user(&handler, &key, &user, github_token, todoist_token).map(move |_| user)
+        });
+
+    let response = saved.then(move |result| match result {
+        Ok(user) => {
+            info!(logger, "User authorized reviewist"; "user" => &user);
+            saved_users.unbounded_send(()).ok();
+
+            let message = format!(
+                "{} is now connected for {}, you can close this page.",
+                provider.name(),
+                user
+            );
+
+            if provider == Provider::Todoist {
+                return Ok(text_response(StatusCode::Ok, &message));
+            }
+
+            // Remembers who the user is, for connecting Todoist next
+            let session = format!("session\n{}\n{}", user, Utc::now().timestamp());
+            match session_onboarding.key.encrypt(&session) {
+                Ok(session) => {
+                    let cookie = session_onboarding.cookie(SESSION_COOKIE, &session, SESSION_LIFETIME_SECS);
+                    Ok(text_response(StatusCode::Ok, &message).with_header(SetCookie(vec![cookie])))
+                }
+
+                Err(err) => {
+                    error!(logger, "Unable to start a session"; "error" => %err);
+                    Ok(text_response(
+                        StatusCode::InternalServerError,
+                        "Unable to start a session",
+                    ))
+                }
+            }
+        }
+
+        Err(err) => {
+            error!(logger, "Unable to finish authorization"; "error" => %err);
+            Ok(text_response(StatusCode::BadGateway, "Unable to finish authorization"))
+        }
+    });
+
+    Either::B(response)
+}
+
+impl Onboarding {
+    fn app(&self, provider: Provider) -> Option<&OauthApp> {
+        match provider {
+            Provider::Github => self.settings.github.as_ref(),
+            Provider::Todoist => self.settings.todoist.as_ref(),
+        }
+    }
+
+    fn redirect_uri(&self, provider: Provider) -> Result<Url, Error> {
+        Ok(self
+            .settings
+            .public_url
+            .join(&format!("callback/{}", provider.name()))?)
+    }
+
+    /// Checks that a state was made for this provider and the browser it came
+    /// back from, and wasn't used yet. States are encrypted, so they can't be
+    /// made up, and only last for a while.
+    fn check_state(&self, callback: &Callback) -> Result<(), Error> {
+        let state = self.key.decrypt(&callback.state)?;
+        let parts: Vec<&str> = state.splitn(3, '\n').collect();
+
+        let (state_provider, nonce, created_at) = match parts.as_slice() {
+            &[state_provider, nonce, created_at] => (state_provider, nonce, created_at.parse::<i64>()?),
+            _ => return Err(format_err!("Malformed state")),
+        };
+
+        if state_provider != callback.provider.name() {
+            return Err(format_err!("State was made for {}", state_provider));
+        }
+
+        if nonce != callback.nonce {
+            return Err(format_err!("State was made for another browser"));
+        }
+
+        if Utc::now().timestamp() - created_at > STATE_LIFETIME_SECS {
+            return Err(format_err!("State expired"));
+        }
+
+        match self.pending.borrow_mut().remove(nonce) {
+            Some(_) => Ok(()),
+            None => Err(format_err!("State was already used")),
+        }
+    }
+
+    /// The user a session cookie was made for, after they connected GitHub.
+    fn user_from_session(&self, session: &str) -> Result<String, Error> {
+        let session = self.key.decrypt(session)?;
+        let parts: Vec<&str> = session.splitn(3, '\n').collect();
+
+        let (user, created_at) = match parts.as_slice() {
+            &["session", user, created_at] => (user, created_at.parse::<i64>()?),
+            _ => return Err(format_err!("Malformed session")),
+        };
+
+        if Utc::now().timestamp() - created_at > SESSION_LIFETIME_SECS {
+            return Err(format_err!("Session expired"));
+        }
+
+        Ok(user.to_string())
+    }
+
+    /// A cookie only sent back to the onboarding endpoints, and out of reach
+    /// of scripts.
+    fn cookie(&self, name: &str, value: &str, max_age_secs: i64) -> String {
+        let secure = if self.settings.public_url.scheme() == "https" {
+            "; Secure"
+        } else {
+            ""
+        };
+
+        format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+            name, value, max_age_secs, secure
+        )
+    }
+
+    /// The login of the GitHub user a token belongs to, checked to make a
+    /// valid user name.
+    fn github_login(&self, token: &str) -> impl Future<Item = String, Error = Error> {
+        let user_url = match self.github_base.join("user") {
+            Ok(user_url) => user_url,
+            Err(err) => return Either::A(future::err(Error::from(err))),
+        };
+
+        let login = self
+            .http
+            .get(user_url)
+            .header(Authorization(format!("token {}", token)))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|mut response| response.json::<GithubUser>())
+            .map_err(Error::from)
+            .and_then(|user| server::validate_name(&user.login).map(|_| user.login));
+
+        Either::B(login)
+    }
+
+    fn exchange_code(
+        &self,
+        provider: Provider,
+        app: &OauthApp,
+        code: String,
+    ) -> impl Future<Item = String, Error = Error> {
+        let urls = app
+            .base
+            .join(provider.token_path())
+            .map_err(Error::from)
+            .and_then(|token_url| Ok((token_url, self.redirect_uri(provider)?)));
+
+        let (token_url, redirect_uri) = match urls {
+            Ok(urls) => urls,
+            Err(err) => return Either::A(future::err(err)),
+        };
+
+        let form = [
+            ("client_id", app.client_id.clone()),
+            ("client_secret", app.client_secret.clone()),
+            ("code", code),
+            ("redirect_uri", redirect_uri.into_string()),
+        ];
+
+        // GitHub answers with a form unless asked for JSON
+        let token = self
+            .http
+            .post(token_url)
+            .header(Accept::json())
+            .form(&form)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|mut response| response.json::<TokenResponse>())
+            .map_err(Error::from)
+            .and_then(|response| match response {
+                TokenResponse {
+                    access_token: Some(token),
+                    ..
+                } => Ok(token),
+
+                TokenResponse { error, .. } => Err(format_err!(
+                    "No token in the response, error: {}",
+                    error.unwrap_or_default()
+                )),
+            });
+
+        Either::B(token)
+    }
+}
+
+fn text_response(status: StatusCode, body: &str) -> Response {
+    Response::new()
+        .with_status(status)
+        .with_header(ContentType::plaintext())
+        .with_body(body.to_string())
+}
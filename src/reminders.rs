@@ -0,0 +1,25 @@
+use std::env;
+
+/// When to remind about review requests that are still open. Tasks get their
+/// priority bumped and become due today once the review request is
+/// `after_days` working days old, and `sink` (if set) gets a reminder.
+#[derive(Clone, Debug)]
+pub struct Reminders {
+    pub after_days: u32,
+    pub sink: Option<String>,
+}
+
+impl Reminders {
+    /// Reads the reminder settings, enabled by setting `REMIND_AFTER_DAYS`.
+    pub fn from_env() -> Option<Reminders> {
+        let after_days = env::var("REMIND_AFTER_DAYS")
+            .ok()?
+            .parse()
+            .expect("REMIND_AFTER_DAYS must be a number of days");
+
+        Some(Reminders {
+            after_days,
+            sink: env::var("REMINDER_SINK").ok(),
+        })
+    }
+}
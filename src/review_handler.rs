@@ -1,120 +1,713 @@
-use diesel::prelude::*;
-use diesel::sqlite::SqliteConnection;
+use chrono::{NaiveDateTime, Utc};
 use failure::Error;
-use futures::future::{self, poll_fn};
+use futures::future::{self, poll_fn, Either};
 use futures::prelude::*;
 use futures::sync::oneshot;
 
-use super::schema::review_requests;
+use super::schema::{deliveries, delivery_failures, http_cache, polling_cursors, review_requests, users};
+use serde::Serialize;
 use slog::Logger;
 use tokio;
 use tokio_threadpool::blocking;
 
 use super::Config;
-use github::PullRequest;
+use dry_run::DryRun;
+use github::{CacheStore, CiState, PollingCursor, PullRequest, DEFAULT_ACCOUNT};
 use std::sync::{Arc, Mutex};
+use storage::{self, Storage};
 
-#[derive(Insertable)]
+#[derive(Insertable, Serialize)]
 #[table_name = "review_requests"]
 pub struct NewReviewRequest {
-    project: String,
-    pr_number: String,
-    pr_url: String,
-    pr_title: String,
+    pub project: String,
+    pub pr_number: String,
+    pub pr_url: String,
+    pub pr_title: String,
+    pub api_url: String,
+    pub held_until: Option<NaiveDateTime>,
+    pub content_hash: String,
+    pub state: &'static str,
+    pub notification_thread_id: Option<String>,
+    pub account: String,
 }
 
+#[derive(Insertable, Serialize)]
+#[table_name = "deliveries"]
+pub struct NewDelivery {
+    pub review_request_id: i32,
+    pub sink: String,
+    pub external_id: Option<String>,
+}
+
+/// A sink that a review request couldn't be delivered to, until a later
+/// delivery to it succeeds.
+#[derive(Insertable, Serialize)]
+#[table_name = "delivery_failures"]
+pub struct NewDeliveryFailure {
+    pub review_request_id: i32,
+    pub sink: String,
+    pub error: String,
+    pub failed_at: NaiveDateTime,
+}
+
+#[derive(Insertable, Serialize)]
+#[table_name = "polling_cursors"]
+pub struct NewPollingCursor {
+    pub source: String,
+    pub last_modified: String,
+    pub polled_at: NaiveDateTime,
+}
+
+/// A response kept around to make conditional requests for its URL, with the
+/// credentials of the same account
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[table_name = "http_cache"]
+pub struct CachedResponse {
+    pub account: String,
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub body: String,
+}
+
+/// A user served in server mode, with their credentials as stored, that is
+/// encrypted. Missing credentials are left alone when saving.
+#[derive(Queryable, Insertable, AsChangeset, Debug, Clone)]
+#[table_name = "users"]
+pub struct StoredUser {
+    pub name: String,
+    pub github_token: Option<String>,
+    pub todoist_token: Option<String>,
+}
+
+/// The only source polled so far
+const NOTIFICATIONS_SOURCE: &str = "notifications";
+
+#[derive(Queryable, Debug, Clone)]
+pub struct Delivery {
+    pub sink: String,
+    pub external_id: Option<String>,
+}
+
+/// The last failed delivery of a review request to a sink.
+#[derive(Queryable, Debug, Clone)]
+pub struct DeliveryFailure {
+    pub sink: String,
+    pub error: String,
+    pub failed_at: NaiveDateTime,
+}
+
+/// A review request that needs to be followed up on, like one whose delivery
+/// was postponed and is now due.
+#[derive(Queryable, Serialize, Debug, Clone)]
+pub struct TrackedRequest {
+    pub id: i32,
+    pub api_url: String,
+    pub notification_thread_id: Option<String>,
+    pub account: String,
+}
+
+/// A delivered review request that is still open, as candidate for a
+/// reminder.
+#[derive(Queryable, Debug, Clone)]
+pub struct OpenRequest {
+    pub id: i32,
+    pub project: String,
+    pub pr_number: String,
+    pub pr_url: String,
+    pub pr_title: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Everything stored about a review request, for inspecting it.
+#[derive(Queryable, Debug, Clone)]
+pub struct StoredReview {
+    pub id: i32,
+    pub account: String,
+    pub project: String,
+    pub pr_number: String,
+    pub pr_url: String,
+    pub pr_title: String,
+    pub api_url: String,
+    pub state: String,
+    pub ci_state: Option<String>,
+    pub notification_thread_id: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub held_until: Option<NaiveDateTime>,
+    pub finished_at: Option<NaiveDateTime>,
+    pub reminded_at: Option<NaiveDateTime>,
+}
+
+/// A stored review request, along with the deliveries made for it and the
+/// ones that failed.
+pub type ReviewRecord = (StoredReview, Vec<Delivery>, Vec<DeliveryFailure>);
+
+/// Which review requests to load for inspecting them.
+#[derive(Debug, Clone)]
+pub enum ReviewFilter {
+    /// The ones still waiting to be reviewed
+    Unfinished,
+    All,
+    /// The ones for a pull request, one per account tracking it
+    PullRequest {
+        project: String,
+        pr_number: String,
+    },
+}
+
+/// How a new review request was recorded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recorded {
+    /// Stored with this id
+    Stored(i32),
+    /// Only noted by a dry run, so there's nothing stored to keep track of
+    DryRun,
+}
+
+/// Where a review request is on its way to the sinks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReviewState {
+    /// Arrived outside of working hours, and waits for the next working slot
+    Held(NaiveDateTime),
+    /// Waits for the pull request's CI to pass
+    WaitingCi,
+    /// Handed over to the sinks
+    Delivered,
+}
+
+/// Reads and writes review requests on a blocking thread of the runtime.
+/// The database is only reachable once the reactor runs, so callers outside
+/// of it wrap their calls in `future::lazy`.
 #[derive(Clone)]
 pub struct ReviewHandler {
-    connection: Arc<Mutex<SqliteConnection>>,
+    storage: Arc<Mutex<Box<dyn Storage>>>,
+    /// When set, the database is only read from
+    dry_run: Option<DryRun>,
 }
 
 impl ReviewHandler {
+    /// Records the review request for `pr`, starting in `state`. Only requests
+    /// that start as `Delivered` should be handed to the sinks right away.
     pub fn record_in_task(
         &self,
         pr: PullRequest,
+        state: ReviewState,
         logger: Logger,
-    ) -> impl Future<Item = Option<PullRequest>, Error = Error> {
-        let (sender, receiver) = oneshot::channel();
+    ) -> impl Future<Item = Option<(Recorded, PullRequest)>, Error = Error> {
+        self.record_review_request(pr, state)
+            .then(move |maybe_result| match maybe_result {
+                Ok(Some((recorded, pr))) => {
+                    match state {
+                        ReviewState::Held(until) => {
+                            info!(logger, "Holding review request"; "pull_request" => ?pr, "until" => %until)
+                        }
+                        ReviewState::WaitingCi => info!(logger, "Waiting for CI"; "pull_request" => ?pr),
+                        ReviewState::Delivered => info!(logger, "PR received"; "pull_request" => ?pr),
+                    }
 
-        let future = self.record_review_request(pr).then(move |maybe_result| {
-            match maybe_result {
-                Ok(Some(pr)) => {
-                    info!(logger, "PR received"; "pull_request" => ?pr);
-                    sender.send(Some(pr)).ok();
+                    Ok(Some((recorded, pr)))
                 }
 
                 Err(err) => {
                     error!(logger, "Error while recording review request"; "err" => %err);
-                    sender.send(None).ok();
-                }
-
-                _ => {
-                    sender.send(None).ok();
+                    Ok(None)
                 }
-            };
-
-            Ok(())
-        });
 
-        tokio::spawn(future);
-        receiver.map_err(Error::from)
+                _ => Ok(None),
+            })
     }
 
-    pub fn record_review_request(&self, pr: PullRequest) -> impl Future<Item = Option<PullRequest>, Error = Error> {
+    pub fn record_review_request(
+        &self,
+        pr: PullRequest,
+        state: ReviewState,
+    ) -> impl Future<Item = Option<(Recorded, PullRequest)>, Error = Error> {
         let new_request = NewReviewRequest {
             project: pr.repo().to_string(),
             pr_url: pr.html_url.to_string(),
             pr_number: pr.number.to_string(),
             pr_title: pr.title.to_string(),
+            api_url: pr.url.to_string(),
+            held_until: state.held_until(),
+            content_hash: pr.content_hash(),
+            state: state.name(),
+            notification_thread_id: pr.notification_thread_id.clone(),
+            account: pr.account.clone(),
+        };
+
+        let key = RequestKey::of(&pr);
+        let dry_run = self.dry_run.clone();
+
+        self.with_storage(move |storage| {
+            // A dry run only checks whether the review request is new
+            if let Some(ref dry_run) = dry_run {
+                let is_new = !storage.review_request_exists(&key)?;
+                if is_new {
+                    dry_run.record("record review request", &new_request);
+                }
+
+                return Ok(if is_new { Some(Recorded::DryRun) } else { None });
+            }
+
+            storage
+                .insert_review_request(&new_request)
+                .map(|maybe_id| maybe_id.map(Recorded::Stored))
+        })
+        .map(move |maybe_recorded| maybe_recorded.map(|recorded| (recorded, pr)))
+    }
+
+    pub fn record_delivery(
+        &self,
+        review_request_id: i32,
+        sink: &str,
+        external_id: Option<String>,
+    ) -> impl Future<Item = (), Error = Error> {
+        let new_delivery = NewDelivery {
+            review_request_id,
+            sink: sink.to_string(),
+            external_id,
+        };
+
+        self.write("record delivery", new_delivery, |new_delivery, storage| {
+            storage.insert_delivery(new_delivery)
+        })
+    }
+
+    /// Notes that delivering to `sink` failed with `error`, replacing an
+    /// earlier failure for the same sink.
+    pub fn record_delivery_failure(
+        &self,
+        review_request_id: i32,
+        sink: &str,
+        error: &Error,
+    ) -> impl Future<Item = (), Error = Error> {
+        let new_failure = NewDeliveryFailure {
+            review_request_id,
+            sink: sink.to_string(),
+            error: error.to_string(),
+            failed_at: Utc::now().naive_utc(),
+        };
+
+        self.write("record delivery failure", new_failure, |new_failure, storage| {
+            storage.insert_delivery_failure(new_failure)
+        })
+    }
+
+    /// Marks the review request for `pr` as finished, returning the deliveries
+    /// that were made for it. Returns `None` if the request is unknown or was
+    /// already finished.
+    pub fn finish_review(&self, pr: &PullRequest) -> impl Future<Item = Option<Vec<Delivery>>, Error = Error> {
+        let key = RequestKey::of(pr);
+        let dry_run = self.dry_run.clone();
+
+        self.with_storage(move |storage| match dry_run {
+            Some(ref dry_run) => match storage.unfinished_request(&key)? {
+                Some((request_id, _)) => {
+                    dry_run.record("finish review request", &key);
+                    storage.deliveries(request_id).map(Some)
+                }
+
+                None => Ok(None),
+            },
+
+            None => storage.finish_review_request(&key),
+        })
+    }
+
+    /// Stores the current content hash of `pr`, returning the deliveries that
+    /// were made for it if the hash changed. Returns `None` if the request is
+    /// unknown, finished or unchanged.
+    pub fn sync_review_request(&self, pr: &PullRequest) -> impl Future<Item = Option<Vec<Delivery>>, Error = Error> {
+        let key = RequestKey::of(pr);
+        let hash = pr.content_hash();
+        let dry_run = self.dry_run.clone();
+
+        self.with_storage(move |storage| match dry_run {
+            Some(ref dry_run) => match storage.unfinished_request(&key)? {
+                Some((request_id, ref stored_hash)) if *stored_hash != hash => {
+                    dry_run.record("update review request content", &key);
+                    storage.deliveries(request_id).map(Some)
+                }
+
+                _ => Ok(None),
+            },
+
+            None => storage.sync_content_hash(&key, &hash),
+        })
+    }
+
+    /// Returns the held review requests that are due at `now`, releasing them
+    /// so they're only returned once. Released requests count as delivered
+    /// unless they're moved to another state.
+    pub fn take_due_held_requests(&self, now: NaiveDateTime) -> impl Future<Item = Vec<TrackedRequest>, Error = Error> {
+        let dry_run = self.dry_run.clone();
+
+        self.with_storage(move |storage| match dry_run {
+            Some(ref dry_run) => {
+                let due_requests = storage.held_requests_due(now)?;
+                for request in &due_requests {
+                    dry_run.record("release held review request", request);
+                }
+
+                Ok(due_requests)
+            }
+
+            None => storage.take_held_requests(now),
+        })
+    }
+
+    /// Moves an already recorded review request to `new_state`.
+    pub fn update_state(
+        &self,
+        review_request_id: i32,
+        new_state: ReviewState,
+    ) -> impl Future<Item = (), Error = Error> {
+        let change = (review_request_id, new_state.name(), new_state.held_until());
+
+        self.write("update review request state", change, move |_, storage| {
+            storage.update_state(review_request_id, new_state)
+        })
+    }
+
+    /// Moves a review request waiting for CI to delivered, returning whether
+    /// it was still waiting. Only the caller that moved it should deliver it,
+    /// since the same request can be checked by more than one loop at once.
+    pub fn release_waiting_ci(&self, review_request_id: i32) -> impl Future<Item = bool, Error = Error> {
+        if let Some(ref dry_run) = self.dry_run {
+            dry_run.record("release review request waiting for CI", &review_request_id);
+            return Either::A(future::ok(true));
+        }
+
+        Either::B(self.with_storage(move |storage| {
+            storage.move_state(review_request_id, ReviewState::WaitingCi, ReviewState::Delivered)
+        }))
+    }
+
+    /// Returns every unfinished review request, whatever its state.
+    pub fn unfinished_requests(&self) -> impl Future<Item = Vec<TrackedRequest>, Error = Error> {
+        self.with_storage(move |storage| storage.unfinished_requests())
+    }
+
+    /// Returns the unfinished review requests that are waiting for CI.
+    pub fn waiting_ci_requests(&self) -> impl Future<Item = Vec<TrackedRequest>, Error = Error> {
+        self.with_storage(move |storage| storage.unfinished_requests_in(ReviewState::WaitingCi))
+    }
+
+    /// Notes that CI was seen failing at `now`, returning since when it has
+    /// been failing.
+    pub fn record_ci_failure(
+        &self,
+        review_request_id: i32,
+        now: NaiveDateTime,
+    ) -> impl Future<Item = NaiveDateTime, Error = Error> {
+        let dry_run = self.dry_run.clone();
+
+        self.with_storage(move |storage| match dry_run {
+            Some(ref dry_run) => {
+                dry_run.record("record CI failure", &(review_request_id, now));
+                let failing_since = storage.ci_failing_since(review_request_id)?;
+                Ok(failing_since.unwrap_or(now))
+            }
+
+            None => storage.record_ci_failure(review_request_id, now),
+        })
+    }
+
+    /// Forgets since when CI was failing, once it isn't anymore.
+    pub fn clear_ci_failure(&self, review_request_id: i32) -> impl Future<Item = (), Error = Error> {
+        self.write("clear CI failure", review_request_id, move |_, storage| {
+            storage.clear_ci_failure(review_request_id)
+        })
+    }
+
+    pub fn record_ci_state(&self, review_request_id: i32, new_state: CiState) -> impl Future<Item = (), Error = Error> {
+        let change = (review_request_id, new_state.as_str());
+
+        self.write("record CI state", change, move |_, storage| {
+            storage.update_ci_state(review_request_id, new_state)
+        })
+    }
+
+    /// Returns the unfinished review requests whose CI was last seen pending
+    /// or failing, along with that state.
+    pub fn unsettled_ci_requests(&self) -> impl Future<Item = Vec<(TrackedRequest, CiState)>, Error = Error> {
+        self.with_storage(move |storage| {
+            let mut requests = Vec::new();
+
+            for &ci_state in &[CiState::Pending, CiState::Failure] {
+                let tracked = storage.unfinished_requests_with_ci(ci_state)?;
+                requests.extend(tracked.into_iter().map(|request| (request, ci_state)));
+            }
+
+            Ok(requests)
+        })
+    }
+
+    /// Returns the delivered, unfinished review requests created before
+    /// `created_before` that nobody was reminded about yet.
+    pub fn unreminded_requests(
+        &self,
+        created_before: NaiveDateTime,
+    ) -> impl Future<Item = Vec<OpenRequest>, Error = Error> {
+        self.with_storage(move |storage| storage.unreminded_requests(created_before))
+    }
+
+    pub fn record_reminder(&self, review_request_id: i32, now: NaiveDateTime) -> impl Future<Item = (), Error = Error> {
+        self.write("record reminder", (review_request_id, now), move |_, storage| {
+            storage.update_reminded_at(review_request_id, now)
+        })
+    }
+
+    pub fn deliveries_for(&self, review_request_id: i32) -> impl Future<Item = Vec<Delivery>, Error = Error> {
+        self.with_storage(move |storage| storage.deliveries(review_request_id))
+    }
+
+    /// Returns where notification polling stopped on the last run, if it ever
+    /// ran.
+    pub fn polling_cursor(&self, account: &str) -> impl Future<Item = Option<PollingCursor>, Error = Error> {
+        let source = polling_source(account);
+        let account = account.to_string();
+
+        self.with_storage(move |storage| {
+            let cursor = storage.polling_cursor(&source)?;
+
+            Ok(cursor.map(|(last_modified, polled_at)| PollingCursor {
+                account: account.clone(),
+                last_modified,
+                polled_at,
+            }))
+        })
+    }
+
+    pub fn save_polling_cursor(&self, cursor: PollingCursor) -> impl Future<Item = (), Error = Error> {
+        let new_cursor = NewPollingCursor {
+            source: polling_source(&cursor.account),
+            last_modified: cursor.last_modified.clone(),
+            polled_at: cursor.polled_at,
         };
-        let conn = self.connection.clone();
-        let perform_insert = move || insert_review_request(&new_request, &*conn.lock().unwrap());
 
-        poll_fn(move || blocking(&perform_insert)).then(|res| {
+        self.write("save polling cursor", new_cursor, |new_cursor, storage| {
+            storage.save_polling_cursor(new_cursor)
+        })
+    }
+
+    pub fn cached_response(
+        &self,
+        account: &str,
+        response_url: &str,
+    ) -> impl Future<Item = Option<CachedResponse>, Error = Error> {
+        let account = account.to_string();
+        let response_url = response_url.to_string();
+
+        self.with_storage(move |storage| storage.cached_response(&account, &response_url))
+    }
+
+    /// Responses are only cached for later runs, so a dry run skips this
+    /// without a word.
+    pub fn store_cached_response(&self, response: CachedResponse) -> impl Future<Item = (), Error = Error> {
+        if self.dry_run.is_some() {
+            return Either::A(future::ok(()));
+        }
+
+        let stored = self.with_storage(move |storage| storage.store_cached_response(&response));
+        Either::B(stored)
+    }
+
+    /// Marks a cached response as fetched again at `now`. Like storing
+    /// responses, a dry run skips this.
+    pub fn touch_cached_response(
+        &self,
+        account: &str,
+        response_url: &str,
+        now: NaiveDateTime,
+    ) -> impl Future<Item = (), Error = Error> {
+        if self.dry_run.is_some() {
+            return Either::A(future::ok(()));
+        }
+
+        let account = account.to_string();
+        let response_url = response_url.to_string();
+
+        let touched = self.with_storage(move |storage| storage.touch_cached_response(&account, &response_url, now));
+        Either::B(touched)
+    }
+
+    /// Drops the responses fetched before `fetched_before`, returning how
+    /// many were dropped. Like storing them, a dry run skips this.
+    pub fn evict_cached_responses(&self, fetched_before: NaiveDateTime) -> impl Future<Item = usize, Error = Error> {
+        if self.dry_run.is_some() {
+            return Either::A(future::ok(0));
+        }
+
+        let evicted = self.with_storage(move |storage| storage.evict_cached_responses(fetched_before));
+        Either::B(evicted)
+    }
+
+    /// Returns the review requests matching `filter`, oldest first, along
+    /// with the deliveries made for each and the ones that failed.
+    pub fn stored_reviews(&self, filter: ReviewFilter) -> impl Future<Item = Vec<ReviewRecord>, Error = Error> {
+        self.with_storage(move |storage| storage.stored_reviews(&filter))
+    }
+
+    /// Returns what tells every stored review request apart, finished or
+    /// not.
+    pub fn recorded_requests(&self) -> impl Future<Item = Vec<RequestKey>, Error = Error> {
+        self.with_storage(move |storage| storage.recorded_requests())
+    }
+
+    /// Deletes the review requests with `ids`, along with their deliveries,
+    /// so that they're recorded anew when their pull requests come up again.
+    /// Returns how many were deleted.
+    pub fn forget_reviews(&self, ids: Vec<i32>) -> impl Future<Item = usize, Error = Error> {
+        if let Some(ref dry_run) = self.dry_run {
+            dry_run.record("forget review requests", &ids);
+            return Either::A(future::ok(ids.len()));
+        }
+
+        Either::B(self.with_storage(move |storage| storage.delete_review_requests(&ids)))
+    }
+
+    /// Adds the user, or updates the credentials of an existing one.
+    /// Only the name goes into a dry run's log, the credentials are left out.
+    pub fn save_user(&self, user: StoredUser) -> impl Future<Item = (), Error = Error> {
+        let name = user.name.clone();
+        self.write("save user", name, move |_, storage| storage.save_user(&user))
+    }
+
+    pub fn users(&self) -> impl Future<Item = Vec<StoredUser>, Error = Error> {
+        self.with_storage(move |storage| storage.users())
+    }
+
+    /// Writes `payload` with `operation`, unless this is a dry run, which
+    /// only records `action` along with the payload.
+    fn write<F, P>(&self, action: &str, payload: P, operation: F) -> impl Future<Item = (), Error = Error>
+    where
+        F: Fn(&P, &dyn Storage) -> Result<(), Error> + Send + 'static,
+        P: Serialize + Send + 'static,
+    {
+        match self.dry_run {
+            Some(ref dry_run) => {
+                dry_run.record(action, &payload);
+                Either::A(future::ok(()))
+            }
+
+            None => Either::B(self.with_storage(move |storage| operation(&payload, storage))),
+        }
+    }
+
+    fn with_storage<F, T>(&self, operation: F) -> impl Future<Item = T, Error = Error>
+    where
+        F: Fn(&dyn Storage) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let storage = self.storage.clone();
+
+        let future = poll_fn(move || blocking(|| operation(&**storage.lock().unwrap()))).then(move |res| {
             let result = match res {
-                Ok(Ok(true)) => Ok(Some(pr)),
-                Ok(Ok(false)) => Ok(None),
-                Ok(Err(err)) => Err(err),
+                Ok(result) => result,
                 Err(_) => Err(format_err!("Error while scheduling work")),
             };
 
-            future::result(result)
-        })
+            sender.send(result).ok();
+            Ok(())
+        });
+
+        tokio::spawn(future);
+        receiver.map_err(Error::from).and_then(future::result)
     }
 }
 
-pub fn new(config: &Config) -> Result<ReviewHandler, Error> {
-    let connection = establish_connection(config)?;
-    Ok(ReviewHandler {
-        connection: Arc::new(Mutex::new(connection)),
-    })
+impl CacheStore for ReviewHandler {
+    fn cached_response(
+        &self,
+        account: &str,
+        url: &str,
+    ) -> Box<dyn Future<Item = Option<CachedResponse>, Error = Error>> {
+        Box::new(ReviewHandler::cached_response(self, account, url))
+    }
+
+    fn store_cached_response(&self, response: CachedResponse) -> Box<dyn Future<Item = (), Error = Error>> {
+        Box::new(ReviewHandler::store_cached_response(self, response))
+    }
+
+    fn touch_cached_response(
+        &self,
+        account: &str,
+        url: &str,
+        now: NaiveDateTime,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        Box::new(ReviewHandler::touch_cached_response(self, account, url, now))
+    }
 }
 
-fn insert_review_request(new_request: &NewReviewRequest, conn: &SqliteConnection) -> Result<bool, Error> {
-    use super::schema::review_requests::dsl::*;
-    use diesel::dsl::exists;
-    use diesel::{insert_into, select};
+impl StoredReview {
+    /// The owner of the pull request's repository, as found in its API URL.
+    pub fn owner(&self) -> Option<&str> {
+        let mut segments = self.api_url.split('/').skip_while(|segment| *segment != "repos");
+        segments.nth(1).filter(|owner| !owner.is_empty())
+    }
 
-    let existing_rq = review_requests.filter(
-        project
-            .eq(&new_request.project)
-            .and(pr_number.eq(&new_request.pr_number)),
-    );
+    pub fn tracked_request(&self) -> TrackedRequest {
+        TrackedRequest {
+            id: self.id,
+            api_url: self.api_url.clone(),
+            notification_thread_id: self.notification_thread_id.clone(),
+            account: self.account.clone(),
+        }
+    }
+}
 
-    let rq_exists = select(exists(existing_rq)).get_result(conn).map_err(Error::from)?;
+impl ReviewState {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ReviewState::Held(_) => "held",
+            ReviewState::WaitingCi => "waiting_ci",
+            ReviewState::Delivered => "delivered",
+        }
+    }
 
-    if rq_exists {
-        return Ok(false);
+    pub fn held_until(&self) -> Option<NaiveDateTime> {
+        match *self {
+            ReviewState::Held(until) => Some(until),
+            _ => None,
+        }
     }
+}
 
-    insert_into(review_requests)
-        .values(new_request)
-        .execute(conn)
-        .map(|_| true)
-        .map_err(Error::from)
+pub fn new(config: &Config) -> Result<ReviewHandler, Error> {
+    let storage = storage::connect(&config.database_url)?;
+    Ok(ReviewHandler {
+        storage: Arc::new(Mutex::new(storage)),
+        dry_run: config.dry_run.clone(),
+    })
 }
 
-fn establish_connection(config: &Config) -> Result<SqliteConnection, Error> {
-    SqliteConnection::establish(&config.database_url)
-        .map_err(move |err| format_err!("Error while connecting to {}: {}", config.database_url, err))
+/// What tells review requests apart: the same pull request can be tracked
+/// once per account.
+#[derive(Queryable, Serialize, Debug, Clone, PartialEq)]
+pub struct RequestKey {
+    pub account: String,
+    pub project: String,
+    pub pr_number: String,
+}
+
+impl RequestKey {
+    pub fn of(pr: &PullRequest) -> RequestKey {
+        RequestKey {
+            account: pr.account.clone(),
+            project: pr.repo().to_string(),
+            pr_number: pr.number.to_string(),
+        }
+    }
+}
+
+/// The default account keeps the source its cursor had before there could be
+/// several accounts.
+fn polling_source(account: &str) -> String {
+    if account == DEFAULT_ACCOUNT {
+        NOTIFICATIONS_SOURCE.to_string()
+    } else {
+        format!("{}:{}", NOTIFICATIONS_SOURCE, account)
+    }
 }
@@ -1,3 +1,22 @@
+table! {
+    deliveries (id) {
+        id -> Integer,
+        review_request_id -> Integer,
+        sink -> Text,
+        external_id -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    delivery_failures (review_request_id, sink) {
+        review_request_id -> Integer,
+        sink -> Text,
+        error -> Text,
+        failed_at -> Timestamp,
+    }
+}
+
 table! {
     review_requests (id) {
         id -> Integer,
@@ -6,5 +25,56 @@ table! {
         pr_url -> Text,
         created_at -> Timestamp,
         pr_title -> Text,
+        finished_at -> Nullable<Timestamp>,
+        api_url -> Text,
+        held_until -> Nullable<Timestamp>,
+        content_hash -> Text,
+        ci_state -> Nullable<Text>,
+        state -> Text,
+        ci_failing_since -> Nullable<Timestamp>,
+        reminded_at -> Nullable<Timestamp>,
+        notification_thread_id -> Nullable<Text>,
+        account -> Text,
+    }
+}
+
+table! {
+    http_cache (account, url) {
+        account -> Text,
+        url -> Text,
+        etag -> Nullable<Text>,
+        last_modified -> Nullable<Text>,
+        body -> Text,
+        fetched_at -> Timestamp,
+    }
+}
+
+table! {
+    polling_cursors (source) {
+        source -> Text,
+        last_modified -> Text,
+        polled_at -> Timestamp,
+    }
+}
+
+table! {
+    users (id) {
+        id -> Integer,
+        name -> Text,
+        github_token -> Nullable<Text>,
+        todoist_token -> Nullable<Text>,
+        created_at -> Timestamp,
     }
 }
+
+joinable!(deliveries -> review_requests (review_request_id));
+joinable!(delivery_failures -> review_requests (review_request_id));
+
+allow_tables_to_appear_in_same_query!(
+    deliveries,
+    delivery_failures,
+    http_cache,
+    polling_cursors,
+    review_requests,
+    users,
+);
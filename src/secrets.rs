@@ -0,0 +1,86 @@
+use base64;
+use failure::Error;
+use openssl::rand::rand_bytes;
+use openssl::symm::{Cipher, decrypt_aead, encrypt_aead};
+use std::env;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// How many random bytes go in tokens that mustn't be guessed
+const RANDOM_TOKEN_LEN: usize = 16;
+
+/// Encrypts the credentials kept in the database with AES-256-GCM. Sealed
+/// values are the nonce, tag and ciphertext together, in base64.
+#[derive(Clone)]
+pub struct SecretKey {
+    key: Vec<u8>,
+}
+
+impl SecretKey {
+    /// Reads the key from `SECRET_KEY`, which holds 32 bytes in base64.
+    pub fn from_env() -> Option<SecretKey> {
+        let key = env::var("SECRET_KEY").ok()?;
+        Some(SecretKey::from_base64(&key).expect("SECRET_KEY must be 32 bytes encoded in base64"))
+    }
+
+    pub fn from_base64(key: &str) -> Result<SecretKey, Error> {
+        let key = base64::decode(key.trim())?;
+
+        if key.len() != KEY_LEN {
+            return Err(format_err!(
+                "Secret keys must be {} bytes long, got {}",
+                KEY_LEN,
+                key.len()
+            ));
+        }
+
+        Ok(SecretKey { key })
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> Result<String, Error> {
+        let mut nonce = [0; NONCE_LEN];
+        rand_bytes(&mut nonce)?;
+
+        let mut tag = [0; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            &[],
+            plaintext.as_bytes(),
+            &mut tag,
+        )?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&tag);
+        sealed.extend_from_slice(&ciphertext);
+
+        Ok(base64::encode(&sealed))
+    }
+
+    /// Fails if the value was sealed with another key, or tampered with.
+    pub fn decrypt(&self, sealed: &str) -> Result<String, Error> {
+        let sealed = base64::decode(sealed)?;
+
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err(format_err!("Encrypted value is too short"));
+        }
+
+        let (nonce, rest) = sealed.split_at(NONCE_LEN);
+        let (tag, ciphertext) = rest.split_at(TAG_LEN);
+
+        let plaintext = decrypt_aead(Cipher::aes_256_gcm(), &self.key, Some(nonce), &[], ciphertext, tag)
+            .map_err(|_| format_err!("Unable to decrypt value, the secret key may have changed"))?;
+
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
+
+/// A random value that can't be guessed, in URL safe base64.
+pub fn random_token() -> Result<String, Error> {
+    let mut token = [0; RANDOM_TOKEN_LEN];
+    rand_bytes(&mut token)?;
+
+    Ok(base64::encode_config(&token, base64::URL_SAFE_NO_PAD))
+}
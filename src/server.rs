@@ -0,0 +1,276 @@
+use failure::Error;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use futures::unsync::{mpsc, oneshot};
+use slog::Logger;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio_timer::Interval;
+
+use Config;
+use github::{GithubAccount, GithubCredentials, DEFAULT_ACCOUNT};
+use onboarding;
+use review_handler::{self, ReviewHandler, StoredUser};
+use secrets::SecretKey;
+
+/// How often to look for users whose polling loop isn't running, like users
+/// that were just added or whose loop failed, and for changed credentials
+const SUPERVISE_INTERVAL_SECS: u64 = 60;
+/// The longest user name that fits the database
+const MAX_USER_NAME_LEN: usize = 50;
+
+/// A user with every credential needed to serve them, decrypted
+#[derive(PartialEq)]
+struct User {
+    name: String,
+    github_token: String,
+    todoist_token: String,
+}
+
+/// A user's polling loop, which stops when this is dropped
+struct UserLoop {
+    user: User,
+    stop: oneshot::Sender<()>,
+}
+
+/// The running polling loops, by user name
+type UserLoops = Rc<RefCell<HashMap<String, UserLoop>>>;
+
+/// Stores a user for server mode, encrypting their credentials with the
+/// configured secret key. Credentials that aren't given are left as they
+/// were, so that they can be provided one at a time.
+pub fn add_user(
+    config: &Config,
+    name: &str,
+    github_token: Option<&str>,
+    todoist_token: Option<&str>,
+) -> impl Future<Item = (), Error = Error> {
+    let prepared = config
+        .secret_key
+        .clone()
+        .ok_or_else(|| format_err!("SECRET_KEY must be set to store users"))
+        .and_then(|key| Ok((key, review_handler::new(config)?)));
+
+    let (key, handler) = match prepared {
+        Ok(prepared) => prepared,
+        Err(err) => return Either::A(future::err(err)),
+    };
+
+    let name = name.to_string();
+    let github_token = github_token.map(str::to_string);
+    let todoist_token = todoist_token.map(str::to_string);
+
+    let saved = future::lazy(move || save_user(&handler, &key, &name, github_token, todoist_token));
+    Either::B(saved)
+}
+
+/// Encrypts the given credentials and stores them for the user named `name`.
+pub fn save_user(
+    handler: &ReviewHandler,
+    key: &SecretKey,
+    name: &str,
+    github_token: Option<String>,
+    todoist_token: Option<String>,
+) -> impl Future<Item = (), Error = Error> {
+    let sealed = validate_name(name).and_then(|_| {
+        Ok(StoredUser {
+            name: name.to_string(),
+            github_token: seal(key, github_token)?,
+            todoist_token: seal(key, todoist_token)?,
+        })
+    });
+
+    match sealed {
+        Ok(user) => Either::A(handler.save_user(user)),
+        Err(err) => Either::B(future::err(err)),
+    }
+}
+
+fn seal(key: &SecretKey, token: Option<String>) -> Result<Option<String>, Error> {
+    match token {
+        Some(token) => Ok(Some(key.encrypt(&token)?)),
+        None => Ok(None),
+    }
+}
+
+/// Users are tracked as a GitHub account of their own name, so the name of
+/// the single-user account is taken.
+pub fn validate_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.len() > MAX_USER_NAME_LEN {
+        return Err(format_err!(
+            "User names must be 1 to {} characters long",
+            MAX_USER_NAME_LEN
+        ));
+    }
+
+    if name == DEFAULT_ACCOUNT {
+        return Err(format_err!("{:?} can't be used as a user name", DEFAULT_ACCOUNT));
+    }
+
+    Ok(())
+}
+
+/// Serves every user stored in the database, each with a polling loop of
+/// their own. The loops share the configuration besides the credentials, and
+/// keep their review requests apart under the user's name. A supervisor
+/// starts the loops of new users, and restarts failed loops on its next
+/// check, without affecting the other users. It also restarts the loops of
+/// users whose credentials changed, and stops those of users who were
+/// removed or can't be served anymore. Users can also sign up through the
+/// onboarding endpoints, when configured.
+pub fn run_server(config: Config) -> impl Future<Item = (), Error = Error> {
+    let key = match config.secret_key.clone() {
+        Some(key) => key,
+        None => return Either::A(future::err(format_err!("SECRET_KEY must be set in server mode"))),
+    };
+
+    let handler = match review_handler::new(&config) {
+        Ok(handler) => handler,
+        Err(err) => return Either::A(future::err(err)),
+    };
+
+    // Users who sign up through onboarding are started right away, instead
+    // of on the next check
+    let (saved_users, wakes) = mpsc::unbounded();
+
+    let onboarding = if config.onboarding.is_some() {
+        match onboarding::serve(&config, &handler, &key, saved_users) {
+            Ok(served) => Either::A(served),
+            Err(err) => return Either::A(future::err(err)),
+        }
+    } else {
+        Either::B(future::empty())
+    };
+
+    let running: UserLoops = Rc::new(RefCell::new(HashMap::new()));
+    let interval = Interval::new(Instant::now(), Duration::from_secs(SUPERVISE_INTERVAL_SECS));
+    let checks = interval
+        .map(|_| ())
+        .map_err(Error::from)
+        .select(wakes.map_err(|_| format_err!("Onboarding wakes failed")));
+
+    let supervisor = checks.for_each(move |_| {
+        let config = config.clone();
+        let handler = handler.clone();
+        let running = running.clone();
+        let logger = config.logger.clone();
+
+        load_users(&handler, &key, &config.logger).then(move |result| {
+            let users = match result {
+                Ok(users) => users,
+
+                Err(err) => {
+                    error!(logger, "Error while loading users"; "error" => %err);
+                    return Ok(());
+                }
+            };
+
+            // Dropping a loop stops it
+            running
+                .borrow_mut()
+                .retain(|name, user_loop| users.iter().any(|user| user.name == *name && *user == user_loop.user));
+
+            for user in users {
+                if !running.borrow().contains_key(&user.name) {
+                    start_user_loop(&config, &handler, user, running.clone());
+                }
+            }
+
+            Ok(())
+        })
+    });
+
+    Either::B(supervisor.join(onboarding).map(|_| ()))
+}
+
+/// Loads the users that can be served. Users missing a credential, or whose
+/// credentials can't be decrypted, are skipped.
+fn load_users(
+    handler: &ReviewHandler,
+    key: &SecretKey,
+    logger: &Logger,
+) -> impl Future<Item = Vec<User>, Error = Error> {
+    let key = key.clone();
+    let logger = logger.clone();
+
+    handler.users().map(move |stored_users| {
+        stored_users
+            .into_iter()
+            .filter_map(|stored| {
+                let logger = logger.new(o!("user" => stored.name.clone()));
+
+                let (github_token, todoist_token) = match (stored.github_token, stored.todoist_token) {
+                    (Some(github_token), Some(todoist_token)) => (github_token, todoist_token),
+
+                    _ => {
+                        debug!(logger, "Waiting for the user's credentials");
+                        return None;
+                    }
+                };
+
+                let decrypted = key
+                    .decrypt(&github_token)
+                    .and_then(|github_token| Ok((github_token, key.decrypt(&todoist_token)?)));
+
+                match decrypted {
+                    Ok((github_token, todoist_token)) => Some(User {
+                        name: stored.name,
+                        github_token,
+                        todoist_token,
+                    }),
+
+                    Err(err) => {
+                        error!(logger, "Unable to decrypt the user's credentials"; "error" => %err);
+                        None
+                    }
+                }
+            })
+            .collect()
+    })
+}
+
+fn start_user_loop(config: &Config, handler: &ReviewHandler, user: User, running: UserLoops) {
+    let logger = config.logger.new(o!("user" => user.name.clone()));
+    let account = GithubAccount::new(&user.name, GithubCredentials::Token(user.github_token.clone()));
+
+    let mut user_config = config.clone();
+    user_config.logger = logger.clone();
+    user_config.github_accounts = vec![account];
+    user_config.todoist.token = Some(user.todoist_token.clone());
+
+    info!(logger, "Starting the user's polling loop");
+    let (stop, stopped) = oneshot::channel();
+    running.borrow_mut().insert(user.name.clone(), UserLoop { user, stop });
+
+    let user_loop = ::start(user_config, handler.clone())
+        .select2(stopped)
+        .then(move |result| {
+            let stopped = match result {
+                Ok(Either::A((_, stopped))) => {
+                    warn!(logger, "The user's polling loop stopped");
+                    stopped
+                }
+
+                Err(Either::A((err, stopped))) => {
+                    error!(logger, "The user's polling loop failed, restarting it later"; "error" => %err);
+                    stopped
+                }
+
+                Ok(Either::B(_)) | Err(Either::B(_)) => {
+                    info!(logger, "Stopped the user's polling loop");
+                    return Ok(());
+                }
+            };
+
+            // Forgets this loop, leaving any loop that replaced it alone
+            drop(stopped);
+            running
+                .borrow_mut()
+                .retain(|_, user_loop| !user_loop.stop.is_canceled());
+            Ok(())
+        });
+
+    config.handle.spawn(user_loop);
+}
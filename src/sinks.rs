@@ -0,0 +1,138 @@
+use failure::Error;
+use futures::future::{self, Either};
+use futures::prelude::*;
+
+use Config;
+use desktop_notifier::DesktopNotifier;
+use github::{CiStatus, PullRequest};
+use matrix_client::MatrixClient;
+use review_handler::{Delivery, OpenRequest};
+use todoist_client::TodoistClient;
+
+/// A destination that gets notified about review requests.
+#[derive(Clone)]
+pub enum Sink {
+    Todoist(Box<TodoistClient>),
+    Matrix(MatrixClient),
+    Desktop(DesktopNotifier),
+}
+
+pub fn from_config(config: &Config) -> Result<Vec<Sink>, Error> {
+    let mut sinks = vec![Sink::Todoist(Box::new(TodoistClient::new(config)?))];
+
+    if let Some(ref homeserver) = config.matrix_base {
+        sinks.push(Sink::Matrix(MatrixClient::new(config, homeserver)?));
+    }
+
+    if config.desktop_notifications {
+        sinks.push(Sink::Desktop(DesktopNotifier::new(config)?));
+    }
+
+    let reminder_sink = config.reminders.as_ref().and_then(|reminders| reminders.sink.as_ref());
+    if let Some(name) = reminder_sink {
+        match sinks.iter().find(|sink| sink.name() == name) {
+            Some(Sink::Todoist(_)) => return Err(format_err!("REMINDER_SINK must be a messaging sink, like matrix")),
+            Some(_) => {}
+            None => return Err(format_err!("REMINDER_SINK {} is not a configured sink", name)),
+        }
+    }
+
+    Ok(sinks)
+}
+
+impl Sink {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Sink::Todoist(_) => "todoist",
+            Sink::Matrix(_) => "matrix",
+            Sink::Desktop(_) => "desktop",
+        }
+    }
+
+    /// Delivers a new review request, with an optional note for the reviewer.
+    /// Resolves to the id of whatever was created on the sink side, if the
+    /// sink needs to refer to it later.
+    pub fn review_requested(
+        &self,
+        pr: &PullRequest,
+        note: Option<&str>,
+    ) -> impl Future<Item = Option<String>, Error = Error> {
+        match *self {
+            Sink::Todoist(ref client) => Either::A(client.create_task_for_pr(pr, note).map(Some)),
+            Sink::Matrix(ref client) => Either::B(Either::A(client.send_review_request(pr, note).map(Some))),
+            Sink::Desktop(ref notifier) => Either::B(Either::B(notifier.notify_review_request(pr, note).map(|_| None))),
+        }
+    }
+
+    /// Whether the sink should get the pull request's changed files and CI
+    /// status after the review request is delivered.
+    pub fn wants_review_context(&self) -> bool {
+        match *self {
+            Sink::Todoist(ref client) => client.comments_enabled(),
+            _ => false,
+        }
+    }
+
+    pub fn review_context(
+        &self,
+        external_id: &str,
+        pr: &PullRequest,
+        changed_files: &[String],
+        ci: &CiStatus,
+    ) -> impl Future<Item = (), Error = Error> {
+        match *self {
+            Sink::Todoist(ref client) => Either::A(client.add_review_context(external_id, pr, changed_files, ci)),
+            _ => Either::B(future::ok(())),
+        }
+    }
+
+    /// Tells an earlier delivery that CI passed or failed since.
+    pub fn ci_changed(&self, delivery: &Delivery, ci: &CiStatus) -> impl Future<Item = (), Error = Error> {
+        match (self, &delivery.external_id) {
+            (Sink::Todoist(client), Some(task_id)) if client.comments_enabled() => {
+                Either::A(client.add_ci_comment(task_id, ci))
+            }
+
+            _ => Either::B(future::ok(())),
+        }
+    }
+
+    /// Updates an earlier delivery after the pull request changed.
+    pub fn review_updated(&self, pr: &PullRequest, delivery: &Delivery) -> impl Future<Item = (), Error = Error> {
+        match (self, &delivery.external_id) {
+            (Sink::Todoist(client), Some(task_id)) => Either::A(client.update_task_for_pr(task_id, pr)),
+
+            _ => Either::B(future::ok(())),
+        }
+    }
+
+    /// Makes an earlier delivery stand out, after the review request was left
+    /// open for too long.
+    pub fn escalate(&self, delivery: &Delivery) -> impl Future<Item = (), Error = Error> {
+        match (self, &delivery.external_id) {
+            (Sink::Todoist(client), Some(task_id)) => Either::A(client.escalate_task(task_id)),
+
+            _ => Either::B(future::ok(())),
+        }
+    }
+
+    /// Sends a reminder about a review request that was left open for too
+    /// long. Only sinks that post messages can do that.
+    pub fn remind(&self, request: &OpenRequest) -> impl Future<Item = (), Error = Error> {
+        match *self {
+            Sink::Matrix(ref client) => Either::A(client.send_reminder(request)),
+            Sink::Desktop(ref notifier) => Either::B(Either::A(notifier.notify_reminder(request))),
+            Sink::Todoist(_) => Either::B(Either::B(future::err(format_err!(
+                "The todoist sink can't send reminders"
+            )))),
+        }
+    }
+
+    pub fn review_finished(&self, delivery: &Delivery) -> impl Future<Item = (), Error = Error> {
+        match (self, &delivery.external_id) {
+            (Sink::Matrix(client), Some(event_id)) => Either::A(client.mark_review_finished(event_id.clone())),
+
+            _ => Either::B(future::ok(())),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use chrono::NaiveDateTime;
+use failure::Error;
+use url::Url;
+
+use github::CiState;
+use migrations::MigrationStatus;
+use review_handler::{
+    CachedResponse, Delivery, NewDelivery, NewDeliveryFailure, NewPollingCursor, NewReviewRequest, OpenRequest,
+    RequestKey, ReviewFilter, ReviewRecord, ReviewState, StoredUser, TrackedRequest,
+};
+
+#[macro_use]
+mod queries;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod sqlite;
+
+#[cfg(feature = "postgres")]
+use self::postgres::PostgresStorage;
+use self::sqlite::SqliteStorage;
+
+/// Where review requests, and everything else kept between runs, are stored.
+/// Every backend runs the same queries, it's only the connection and the
+/// migrations that set them apart.
+///
+/// Dry runs are left to the review handler, which looks up what a write would
+/// have changed instead of making it.
+pub trait Storage: Send {
+    /// Compares the migrations that were run with the backend's built-in
+    /// ones.
+    fn migration_status(&self) -> Result<MigrationStatus, Error>;
+    /// Runs the built-in migrations that weren't run yet.
+    fn run_migrations(&self) -> Result<(), Error>;
+
+    fn review_request_exists(&self, key: &RequestKey) -> Result<bool, Error>;
+    /// Returns the id of the new review request, or `None` if it was
+    /// already recorded.
+    fn insert_review_request(&self, new_request: &NewReviewRequest) -> Result<Option<i32>, Error>;
+    /// Also clears an earlier failure to deliver to the same sink.
+    fn insert_delivery(&self, new_delivery: &NewDelivery) -> Result<(), Error>;
+    /// Replaces an earlier failure to deliver to the same sink, if any.
+    fn insert_delivery_failure(&self, new_failure: &NewDeliveryFailure) -> Result<(), Error>;
+    /// Returns the id and content hash of the unfinished review request for
+    /// `key`, if any.
+    fn unfinished_request(&self, key: &RequestKey) -> Result<Option<(i32, String)>, Error>;
+    /// Returns the deliveries of the review request it finished, if it
+    /// found it unfinished.
+    fn finish_review_request(&self, key: &RequestKey) -> Result<Option<Vec<Delivery>>, Error>;
+    /// Returns the deliveries of the review request whose hash it changed,
+    /// if any.
+    fn sync_content_hash(&self, key: &RequestKey, hash: &str) -> Result<Option<Vec<Delivery>>, Error>;
+    fn held_requests_due(&self, due_at: NaiveDateTime) -> Result<Vec<TrackedRequest>, Error>;
+    /// Moves the held requests that are due to delivered, returning them.
+    fn take_held_requests(&self, due_at: NaiveDateTime) -> Result<Vec<TrackedRequest>, Error>;
+    fn update_state(&self, review_request_id: i32, new_state: ReviewState) -> Result<(), Error>;
+    /// Moves the review request to `new_state` only if it's still in
+    /// `old_state`, returning whether it did.
+    fn move_state(&self, review_request_id: i32, old_state: ReviewState, new_state: ReviewState)
+        -> Result<bool, Error>;
+    fn unfinished_requests(&self) -> Result<Vec<TrackedRequest>, Error>;
+    fn unfinished_requests_in(&self, review_state: ReviewState) -> Result<Vec<TrackedRequest>, Error>;
+    fn unfinished_requests_with_ci(&self, last_ci_state: CiState) -> Result<Vec<TrackedRequest>, Error>;
+    fn ci_failing_since(&self, review_request_id: i32) -> Result<Option<NaiveDateTime>, Error>;
+    /// Returns since when CI has been failing.
+    fn record_ci_failure(&self, review_request_id: i32, now: NaiveDateTime) -> Result<NaiveDateTime, Error>;
+    fn clear_ci_failure(&self, review_request_id: i32) -> Result<(), Error>;
+    fn update_ci_state(&self, review_request_id: i32, new_state: CiState) -> Result<(), Error>;
+    fn unreminded_requests(&self, created_before: NaiveDateTime) -> Result<Vec<OpenRequest>, Error>;
+    fn update_reminded_at(&self, review_request_id: i32, now: NaiveDateTime) -> Result<(), Error>;
+    fn deliveries(&self, review_request_id: i32) -> Result<Vec<Delivery>, Error>;
+
+    /// Returns the last modified header and the poll time the cursor of
+    /// `source` was saved with.
+    fn polling_cursor(&self, source: &str) -> Result<Option<(String, NaiveDateTime)>, Error>;
+    /// Replaces the cursor of the same source, if any.
+    fn save_polling_cursor(&self, new_cursor: &NewPollingCursor) -> Result<(), Error>;
+    fn cached_response(&self, account: &str, response_url: &str) -> Result<Option<CachedResponse>, Error>;
+    /// Replaces the response cached for the same account and URL, if any.
+    fn store_cached_response(&self, response: &CachedResponse) -> Result<(), Error>;
+    fn touch_cached_response(&self, account: &str, response_url: &str, now: NaiveDateTime) -> Result<(), Error>;
+    /// Returns how many cached responses were deleted.
+    fn evict_cached_responses(&self, fetched_before: NaiveDateTime) -> Result<usize, Error>;
+
+    fn stored_reviews(&self, filter: &ReviewFilter) -> Result<Vec<ReviewRecord>, Error>;
+    fn recorded_requests(&self) -> Result<Vec<RequestKey>, Error>;
+    /// Returns how many review requests were deleted.
+    fn delete_review_requests(&self, ids: &[i32]) -> Result<usize, Error>;
+
+    fn save_user(&self, user: &StoredUser) -> Result<(), Error>;
+    fn users(&self) -> Result<Vec<StoredUser>, Error>;
+}
+
+/// Connects to the storage at `database_url`. URLs with a `postgres` or
+/// `postgresql` scheme are for PostgreSQL, anything else is a SQLite database
+/// path.
+pub fn connect(database_url: &str) -> Result<Box<dyn Storage>, Error> {
+    if is_postgres(database_url) {
+        connect_postgres(database_url)
+    } else {
+        Ok(Box::new(SqliteStorage::establish(database_url)?))
+    }
+}
+
+/// The database URL without the credentials in it, for error messages.
+fn redacted(database_url: &str) -> String {
+    match Url::parse(database_url) {
+        Ok(mut url) => {
+            url.set_username("").ok();
+            url.set_password(None).ok();
+            url.into_string()
+        }
+        Err(_) => database_url.to_string(),
+    }
+}
+
+fn is_postgres(database_url: &str) -> bool {
+    database_url.starts_with("postgres://") || database_url.starts_with("postgresql://")
+}
+
+#[cfg(feature = "postgres")]
+fn connect_postgres(database_url: &str) -> Result<Box<dyn Storage>, Error> {
+    Ok(Box::new(PostgresStorage::establish(database_url)?))
+}
+
+#[cfg(not(feature = "postgres"))]
+fn connect_postgres(_database_url: &str) -> Result<Box<dyn Storage>, Error> {
+    Err(format_err!(
+        "reviewist must be built with the postgres feature to use a PostgreSQL database"
+    ))
+}
@@ -0,0 +1,94 @@
+use diesel::connection::SimpleConnection;
+use diesel::insert_into;
+use diesel::pg::upsert::excluded;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::r2d2::{self, ConnectionManager, CustomizeConnection, Pool, PooledConnection};
+use failure::Error;
+
+use migrations::POSTGRES_MIGRATIONS;
+use review_handler::{CachedResponse, NewPollingCursor};
+use storage::redacted;
+
+/// Storage in a PostgreSQL database, for running reviewist somewhere without a
+/// disk that outlives it.
+///
+/// The connection is kept in a pool of one, which replaces it if the server
+/// dropped it, say on a restart or a failover.
+pub struct PostgresStorage {
+    pool: Pool<ConnectionManager<PgConnection>>,
+}
+
+impl PostgresStorage {
+    pub fn establish(database_url: &str) -> Result<PostgresStorage, Error> {
+        let pool = Pool::builder()
+            .max_size(1)
+            .connection_customizer(Box::new(UtcTimeZone))
+            .build(ConnectionManager::new(database_url))
+            .map_err(|err| format_err!("Error while connecting to {}: {}", redacted(database_url), err))?;
+
+        Ok(PostgresStorage { pool })
+    }
+
+    fn conn(&self) -> Result<PooledConnection<ConnectionManager<PgConnection>>, Error> {
+        self.pool.get().map_err(Error::from)
+    }
+
+    fn has_migrations_table(&self) -> Result<bool, Error> {
+        use diesel::dsl::sql;
+        use diesel::select;
+        use diesel::sql_types::Bool;
+
+        select(sql::<Bool>("to_regclass('__diesel_schema_migrations') IS NOT NULL"))
+            .get_result(&*self.conn()?)
+            .map_err(Error::from)
+    }
+
+    fn upsert_polling_cursor(&self, new_cursor: &NewPollingCursor) -> Result<(), Error> {
+        use schema::polling_cursors::dsl::*;
+
+        insert_into(polling_cursors)
+            .values(new_cursor)
+            .on_conflict(source)
+            .do_update()
+            .set((
+                last_modified.eq(excluded(last_modified)),
+                polled_at.eq(excluded(polled_at)),
+            ))
+            .execute(&*self.conn()?)
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn upsert_cached_response(&self, response: &CachedResponse) -> Result<(), Error> {
+        use diesel::dsl::now;
+        use schema::http_cache::dsl::*;
+
+        insert_into(http_cache)
+            .values(response)
+            .on_conflict((account, url))
+            .do_update()
+            .set((
+                etag.eq(excluded(etag)),
+                last_modified.eq(excluded(last_modified)),
+                body.eq(excluded(body)),
+                fetched_at.eq(now),
+            ))
+            .execute(&*self.conn()?)
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+}
+
+/// Timestamps are stored in UTC without a time zone, like SQLite's
+/// CURRENT_TIMESTAMP gives them, so every connection is set to UTC.
+#[derive(Debug)]
+struct UtcTimeZone;
+
+impl CustomizeConnection<PgConnection, r2d2::Error> for UtcTimeZone {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), r2d2::Error> {
+        conn.batch_execute("SET TIME ZONE 'UTC'").map_err(r2d2::Error::QueryError)
+    }
+}
+
+impl_storage!(PostgresStorage, POSTGRES_MIGRATIONS);
@@ -0,0 +1,680 @@
+/// Implements `Storage` for a backend holding its connection in `conn`, with
+/// the queries every backend shares. Diesel queries are checked against a
+/// single backend, so they're spelled out once here and built for each.
+///
+/// Upserts differ between backends, so the backend provides them as
+/// `upsert_polling_cursor` and `upsert_cached_response`. Each backend also
+/// tells whether it has the table tracking migrations with
+/// `has_migrations_table`, along with the `$migrations` it's migrated with.
+///
+/// Queries get their connection from the backend's `conn`, one statement at a
+/// time. Transactions hold on to theirs, so they can't call back into the
+/// storage.
+macro_rules! impl_storage {
+    ($storage:ty, $migrations:expr) => {
+        impl ::storage::Storage for $storage {
+            fn migration_status(&self) -> Result<::migrations::MigrationStatus, ::failure::Error> {
+                let tracked = self.has_migrations_table()?;
+
+                ::migrations::status_of(&*self.conn()?, $migrations, tracked)
+            }
+
+            fn run_migrations(&self) -> Result<(), ::failure::Error> {
+                ::migrations::run_pending(&*self.conn()?, $migrations)
+            }
+
+            fn review_request_exists(&self, key: &::review_handler::RequestKey) -> Result<bool, ::failure::Error> {
+                use diesel::dsl::exists;
+                use diesel::prelude::*;
+                use diesel::select;
+                use schema::review_requests::dsl::*;
+
+                let existing_rq = review_requests.filter(
+                    account
+                        .eq(&key.account)
+                        .and(project.eq(&key.project))
+                        .and(pr_number.eq(&key.pr_number)),
+                );
+
+                select(exists(existing_rq))
+                    .get_result(&*self.conn()?)
+                    .map_err(::failure::Error::from)
+            }
+
+            fn insert_review_request(
+                &self,
+                new_request: &::review_handler::NewReviewRequest,
+            ) -> Result<Option<i32>, ::failure::Error> {
+                use diesel::dsl::exists;
+                use diesel::prelude::*;
+                use diesel::{insert_into, select};
+                use schema::review_requests::dsl::*;
+
+                let existing_rq = review_requests.filter(
+                    account
+                        .eq(&new_request.account)
+                        .and(project.eq(&new_request.project))
+                        .and(pr_number.eq(&new_request.pr_number)),
+                );
+
+                let conn = self.conn()?;
+
+                // Checked on the transaction's connection, so that no other
+                // insert can come in between
+                conn.transaction(|| {
+                    if select(exists(existing_rq.clone())).get_result(&*conn)? {
+                        return Ok(None);
+                    }
+
+                    insert_into(review_requests).values(new_request).execute(&*conn)?;
+
+                    existing_rq
+                        .select(id)
+                        .first(&*conn)
+                        .map(Some)
+                        .map_err(::failure::Error::from)
+                })
+            }
+
+            fn insert_delivery(&self, new_delivery: &::review_handler::NewDelivery) -> Result<(), ::failure::Error> {
+                use diesel::prelude::*;
+                use diesel::{delete, insert_into};
+                use schema::{deliveries, delivery_failures};
+
+                let conn = self.conn()?;
+
+                conn.transaction(|| {
+                    delete(delivery_failures::table.find((new_delivery.review_request_id, &new_delivery.sink)))
+                        .execute(&*conn)?;
+
+                    insert_into(deliveries::table)
+                        .values(new_delivery)
+                        .execute(&*conn)
+                        .map(|_| ())
+                        .map_err(::failure::Error::from)
+                })
+            }
+
+            fn insert_delivery_failure(
+                &self,
+                new_failure: &::review_handler::NewDeliveryFailure,
+            ) -> Result<(), ::failure::Error> {
+                use diesel::prelude::*;
+                use diesel::{delete, insert_into};
+                use schema::delivery_failures;
+
+                let conn = self.conn()?;
+
+                conn.transaction(|| {
+                    delete(delivery_failures::table.find((new_failure.review_request_id, &new_failure.sink)))
+                        .execute(&*conn)?;
+
+                    insert_into(delivery_failures::table)
+                        .values(new_failure)
+                        .execute(&*conn)
+                        .map(|_| ())
+                        .map_err(::failure::Error::from)
+                })
+            }
+
+            fn unfinished_request(
+                &self,
+                key: &::review_handler::RequestKey,
+            ) -> Result<Option<(i32, String)>, ::failure::Error> {
+                use diesel::prelude::*;
+                use schema::review_requests::dsl::*;
+
+                review_requests
+                    .filter(
+                        account
+                            .eq(&key.account)
+                            .and(project.eq(&key.project))
+                            .and(pr_number.eq(&key.pr_number))
+                            .and(finished_at.is_null()),
+                    )
+                    .select((id, content_hash))
+                    .first::<(i32, String)>(&*self.conn()?)
+                    .optional()
+                    .map_err(::failure::Error::from)
+            }
+
+            fn finish_review_request(
+                &self,
+                key: &::review_handler::RequestKey,
+            ) -> Result<Option<Vec<::review_handler::Delivery>>, ::failure::Error> {
+                use diesel::dsl::now;
+                use diesel::prelude::*;
+                use diesel::update;
+                use schema::deliveries;
+                use schema::review_requests::dsl::*;
+
+                let conn = self.conn()?;
+
+                // Looked up on the transaction's connection, rather than with
+                // `unfinished_request` and `deliveries`, which take their own
+                conn.transaction(|| {
+                    let unfinished = review_requests
+                        .filter(
+                            account
+                                .eq(&key.account)
+                                .and(project.eq(&key.project))
+                                .and(pr_number.eq(&key.pr_number))
+                                .and(finished_at.is_null()),
+                        )
+                        .select((id, content_hash))
+                        .first::<(i32, String)>(&*conn)
+                        .optional()?;
+                    let request_id = match unfinished {
+                        Some((request_id, _)) => request_id,
+                        None => return Ok(None),
+                    };
+
+                    update(review_requests.find(request_id))
+                        .set(finished_at.eq(now.nullable()))
+                        .execute(&*conn)?;
+
+                    deliveries::table
+                        .filter(deliveries::review_request_id.eq(request_id))
+                        .select((deliveries::sink, deliveries::external_id))
+                        .load(&*conn)
+                        .map(Some)
+                        .map_err(::failure::Error::from)
+                })
+            }
+
+            fn sync_content_hash(
+                &self,
+                key: &::review_handler::RequestKey,
+                hash: &str,
+            ) -> Result<Option<Vec<::review_handler::Delivery>>, ::failure::Error> {
+                use diesel::prelude::*;
+                use diesel::update;
+                use schema::deliveries;
+                use schema::review_requests::dsl::*;
+
+                let conn = self.conn()?;
+
+                conn.transaction(|| {
+                    let unfinished = review_requests
+                        .filter(
+                            account
+                                .eq(&key.account)
+                                .and(project.eq(&key.project))
+                                .and(pr_number.eq(&key.pr_number))
+                                .and(finished_at.is_null()),
+                        )
+                        .select((id, content_hash))
+                        .first::<(i32, String)>(&*conn)
+                        .optional()?;
+                    let (request_id, stored_hash) = match unfinished {
+                        Some(found) => found,
+                        None => return Ok(None),
+                    };
+
+                    if stored_hash == hash {
+                        return Ok(None);
+                    }
+
+                    update(review_requests.find(request_id))
+                        .set(content_hash.eq(hash))
+                        .execute(&*conn)?;
+
+                    deliveries::table
+                        .filter(deliveries::review_request_id.eq(request_id))
+                        .select((deliveries::sink, deliveries::external_id))
+                        .load(&*conn)
+                        .map(Some)
+                        .map_err(::failure::Error::from)
+                })
+            }
+
+            fn held_requests_due(
+                &self,
+                due_at: ::chrono::NaiveDateTime,
+            ) -> Result<Vec<::review_handler::TrackedRequest>, ::failure::Error> {
+                use diesel::prelude::*;
+                use schema::review_requests::dsl::*;
+
+                review_requests
+                    .filter(held_until.le(due_at).and(finished_at.is_null()))
+                    .select((id, api_url, notification_thread_id, account))
+                    .load(&*self.conn()?)
+                    .map_err(::failure::Error::from)
+            }
+
+            fn take_held_requests(
+                &self,
+                due_at: ::chrono::NaiveDateTime,
+            ) -> Result<Vec<::review_handler::TrackedRequest>, ::failure::Error> {
+                use diesel::prelude::*;
+                use diesel::update;
+                use review_handler::ReviewState;
+                use schema::review_requests::dsl::*;
+
+                let conn = self.conn()?;
+
+                // Looked up on the transaction's connection, rather than with
+                // `held_requests_due`, which takes one of its own
+                conn.transaction(|| {
+                    let due_requests: Vec<::review_handler::TrackedRequest> = review_requests
+                        .filter(held_until.le(due_at).and(finished_at.is_null()))
+                        .select((id, api_url, notification_thread_id, account))
+                        .load(&*conn)?;
+
+                    let due_ids: Vec<i32> = due_requests.iter().map(|request| request.id).collect();
+                    let released = ReviewState::Delivered;
+                    update(review_requests.filter(id.eq_any(due_ids)))
+                        .set((state.eq(released.name()), held_until.eq(released.held_until())))
+                        .execute(&*conn)?;
+
+                    Ok(due_requests)
+                })
+            }
+
+            fn update_state(
+                &self,
+                review_request_id: i32,
+                new_state: ::review_handler::ReviewState,
+            ) -> Result<(), ::failure::Error> {
+                use diesel::prelude::*;
+                use diesel::update;
+                use schema::review_requests::dsl::*;
+
+                update(review_requests.find(review_request_id))
+                    .set((state.eq(new_state.name()), held_until.eq(new_state.held_until())))
+                    .execute(&*self.conn()?)
+                    .map(|_| ())
+                    .map_err(::failure::Error::from)
+            }
+
+            fn move_state(
+                &self,
+                review_request_id: i32,
+                old_state: ::review_handler::ReviewState,
+                new_state: ::review_handler::ReviewState,
+            ) -> Result<bool, ::failure::Error> {
+                use diesel::prelude::*;
+                use diesel::update;
+                use schema::review_requests::dsl::*;
+
+                let request = review_requests
+                    .find(review_request_id)
+                    .filter(state.eq(old_state.name()));
+                let moved = update(request)
+                    .set((state.eq(new_state.name()), held_until.eq(new_state.held_until())))
+                    .execute(&*self.conn()?)?;
+
+                Ok(moved == 1)
+            }
+
+            fn unfinished_requests(&self) -> Result<Vec<::review_handler::TrackedRequest>, ::failure::Error> {
+                use diesel::prelude::*;
+                use schema::review_requests::dsl::*;
+
+                review_requests
+                    .filter(finished_at.is_null())
+                    .select((id, api_url, notification_thread_id, account))
+                    .load(&*self.conn()?)
+                    .map_err(::failure::Error::from)
+            }
+
+            fn unfinished_requests_in(
+                &self,
+                review_state: ::review_handler::ReviewState,
+            ) -> Result<Vec<::review_handler::TrackedRequest>, ::failure::Error> {
+                use diesel::prelude::*;
+                use schema::review_requests::dsl::*;
+
+                review_requests
+                    .filter(state.eq(review_state.name()).and(finished_at.is_null()))
+                    .select((id, api_url, notification_thread_id, account))
+                    .load(&*self.conn()?)
+                    .map_err(::failure::Error::from)
+            }
+
+            fn unfinished_requests_with_ci(
+                &self,
+                last_ci_state: ::github::CiState,
+            ) -> Result<Vec<::review_handler::TrackedRequest>, ::failure::Error> {
+                use diesel::prelude::*;
+                use schema::review_requests::dsl::*;
+
+                review_requests
+                    .filter(ci_state.eq(last_ci_state.as_str()).and(finished_at.is_null()))
+                    .select((id, api_url, notification_thread_id, account))
+                    .load(&*self.conn()?)
+                    .map_err(::failure::Error::from)
+            }
+
+            fn ci_failing_since(
+                &self,
+                review_request_id: i32,
+            ) -> Result<Option<::chrono::NaiveDateTime>, ::failure::Error> {
+                use chrono::NaiveDateTime;
+                use diesel::prelude::*;
+                use schema::review_requests::dsl::*;
+
+                review_requests
+                    .find(review_request_id)
+                    .select(ci_failing_since)
+                    .first::<Option<NaiveDateTime>>(&*self.conn()?)
+                    .map_err(::failure::Error::from)
+            }
+
+            fn record_ci_failure(
+                &self,
+                review_request_id: i32,
+                now: ::chrono::NaiveDateTime,
+            ) -> Result<::chrono::NaiveDateTime, ::failure::Error> {
+                use diesel::prelude::*;
+                use diesel::update;
+                use schema::review_requests::dsl::*;
+
+                let request = review_requests.find(review_request_id);
+                update(request.filter(ci_failing_since.is_null()))
+                    .set(ci_failing_since.eq(Some(now)))
+                    .execute(&*self.conn()?)?;
+
+                let failing_since = self.ci_failing_since(review_request_id)?;
+                Ok(failing_since.unwrap_or(now))
+            }
+
+            fn clear_ci_failure(&self, review_request_id: i32) -> Result<(), ::failure::Error> {
+                use chrono::NaiveDateTime;
+                use diesel::prelude::*;
+                use diesel::update;
+                use schema::review_requests::dsl::*;
+
+                update(review_requests.find(review_request_id))
+                    .set(ci_failing_since.eq(None::<NaiveDateTime>))
+                    .execute(&*self.conn()?)
+                    .map(|_| ())
+                    .map_err(::failure::Error::from)
+            }
+
+            fn update_ci_state(
+                &self,
+                review_request_id: i32,
+                new_state: ::github::CiState,
+            ) -> Result<(), ::failure::Error> {
+                use diesel::prelude::*;
+                use diesel::update;
+                use schema::review_requests::dsl::*;
+
+                update(review_requests.find(review_request_id))
+                    .set(ci_state.eq(new_state.as_str()))
+                    .execute(&*self.conn()?)
+                    .map(|_| ())
+                    .map_err(::failure::Error::from)
+            }
+
+            fn unreminded_requests(
+                &self,
+                created_before: ::chrono::NaiveDateTime,
+            ) -> Result<Vec<::review_handler::OpenRequest>, ::failure::Error> {
+                use diesel::prelude::*;
+                use review_handler::ReviewState;
+                use schema::review_requests::dsl::*;
+
+                review_requests
+                    .filter(state.eq(ReviewState::Delivered.name()))
+                    .filter(finished_at.is_null().and(reminded_at.is_null()))
+                    .filter(created_at.le(created_before))
+                    .select((id, project, pr_number, pr_url, pr_title, created_at))
+                    .load(&*self.conn()?)
+                    .map_err(::failure::Error::from)
+            }
+
+            fn update_reminded_at(
+                &self,
+                review_request_id: i32,
+                now: ::chrono::NaiveDateTime,
+            ) -> Result<(), ::failure::Error> {
+                use diesel::prelude::*;
+                use diesel::update;
+                use schema::review_requests::dsl::*;
+
+                update(review_requests.find(review_request_id))
+                    .set(reminded_at.eq(Some(now)))
+                    .execute(&*self.conn()?)
+                    .map(|_| ())
+                    .map_err(::failure::Error::from)
+            }
+
+            fn deliveries(&self, review_request_id: i32) -> Result<Vec<::review_handler::Delivery>, ::failure::Error> {
+                use diesel::prelude::*;
+                use schema::deliveries;
+
+                deliveries::table
+                    .filter(deliveries::review_request_id.eq(review_request_id))
+                    .select((deliveries::sink, deliveries::external_id))
+                    .load(&*self.conn()?)
+                    .map_err(::failure::Error::from)
+            }
+
+            fn polling_cursor(
+                &self,
+                source: &str,
+            ) -> Result<Option<(String, ::chrono::NaiveDateTime)>, ::failure::Error> {
+                use chrono::NaiveDateTime;
+                use diesel::prelude::*;
+                use schema::polling_cursors;
+
+                polling_cursors::table
+                    .find(source)
+                    .select((polling_cursors::last_modified, polling_cursors::polled_at))
+                    .first::<(String, NaiveDateTime)>(&*self.conn()?)
+                    .optional()
+                    .map_err(::failure::Error::from)
+            }
+
+            fn save_polling_cursor(
+                &self,
+                new_cursor: &::review_handler::NewPollingCursor,
+            ) -> Result<(), ::failure::Error> {
+                self.upsert_polling_cursor(new_cursor)
+            }
+
+            fn cached_response(
+                &self,
+                response_account: &str,
+                response_url: &str,
+            ) -> Result<Option<::review_handler::CachedResponse>, ::failure::Error> {
+                use diesel::prelude::*;
+                use schema::http_cache::dsl::*;
+
+                http_cache
+                    .find((response_account, response_url))
+                    .select((account, url, etag, last_modified, body))
+                    .first(&*self.conn()?)
+                    .optional()
+                    .map_err(::failure::Error::from)
+            }
+
+            fn store_cached_response(
+                &self,
+                response: &::review_handler::CachedResponse,
+            ) -> Result<(), ::failure::Error> {
+                self.upsert_cached_response(response)
+            }
+
+            fn touch_cached_response(
+                &self,
+                response_account: &str,
+                response_url: &str,
+                now: ::chrono::NaiveDateTime,
+            ) -> Result<(), ::failure::Error> {
+                use diesel::prelude::*;
+                use diesel::update;
+                use schema::http_cache::dsl::*;
+
+                update(http_cache.find((response_account, response_url)))
+                    .set(fetched_at.eq(now))
+                    .execute(&*self.conn()?)
+                    .map(|_| ())
+                    .map_err(::failure::Error::from)
+            }
+
+            fn evict_cached_responses(
+                &self,
+                fetched_before: ::chrono::NaiveDateTime,
+            ) -> Result<usize, ::failure::Error> {
+                use diesel::delete;
+                use diesel::prelude::*;
+                use schema::http_cache::dsl::*;
+
+                delete(http_cache.filter(fetched_at.lt(fetched_before)))
+                    .execute(&*self.conn()?)
+                    .map_err(::failure::Error::from)
+            }
+
+            fn stored_reviews(
+                &self,
+                filter: &::review_handler::ReviewFilter,
+            ) -> Result<Vec<::review_handler::ReviewRecord>, ::failure::Error> {
+                use chrono::NaiveDateTime;
+                use diesel::prelude::*;
+                use review_handler::{Delivery, DeliveryFailure, ReviewFilter, StoredReview};
+                use schema::review_requests::dsl::*;
+                use schema::{deliveries, delivery_failures};
+
+                let mut query = review_requests
+                    .select((
+                        id,
+                        account,
+                        project,
+                        pr_number,
+                        pr_url,
+                        pr_title,
+                        api_url,
+                        state,
+                        ci_state,
+                        notification_thread_id,
+                        created_at,
+                        held_until,
+                        finished_at,
+                        reminded_at,
+                    ))
+                    .order(id)
+                    .into_boxed();
+
+                query = match *filter {
+                    ReviewFilter::Unfinished => query.filter(finished_at.is_null()),
+                    ReviewFilter::All => query,
+                    ReviewFilter::PullRequest {
+                        project: ref filtered_project,
+                        pr_number: ref filtered_number,
+                    } => query.filter(project.eq(filtered_project).and(pr_number.eq(filtered_number))),
+                };
+
+                let reviews = query.load::<StoredReview>(&*self.conn()?)?;
+
+                let review_ids: Vec<i32> = reviews.iter().map(|review| review.id).collect();
+                let all_deliveries = deliveries::table
+                    .filter(deliveries::review_request_id.eq_any(&review_ids))
+                    .order(deliveries::id)
+                    .select((
+                        deliveries::review_request_id,
+                        deliveries::sink,
+                        deliveries::external_id,
+                    ))
+                    .load::<(i32, String, Option<String>)>(&*self.conn()?)?;
+
+                let all_failures = delivery_failures::table
+                    .filter(delivery_failures::review_request_id.eq_any(&review_ids))
+                    .order(delivery_failures::sink)
+                    .select((
+                        delivery_failures::review_request_id,
+                        delivery_failures::sink,
+                        delivery_failures::error,
+                        delivery_failures::failed_at,
+                    ))
+                    .load::<(i32, String, String, NaiveDateTime)>(&*self.conn()?)?;
+
+                let with_deliveries = reviews
+                    .into_iter()
+                    .map(|review| {
+                        let review_deliveries = all_deliveries
+                            .iter()
+                            .filter(|delivery| delivery.0 == review.id)
+                            .map(|delivery| Delivery {
+                                sink: delivery.1.clone(),
+                                external_id: delivery.2.clone(),
+                            })
+                            .collect();
+
+                        let review_failures = all_failures
+                            .iter()
+                            .filter(|failure| failure.0 == review.id)
+                            .map(|failure| DeliveryFailure {
+                                sink: failure.1.clone(),
+                                error: failure.2.clone(),
+                                failed_at: failure.3,
+                            })
+                            .collect();
+
+                        (review, review_deliveries, review_failures)
+                    })
+                    .collect();
+
+                Ok(with_deliveries)
+            }
+
+            fn recorded_requests(&self) -> Result<Vec<::review_handler::RequestKey>, ::failure::Error> {
+                use diesel::prelude::*;
+                use schema::review_requests::dsl::*;
+
+                review_requests
+                    .select((account, project, pr_number))
+                    .load(&*self.conn()?)
+                    .map_err(::failure::Error::from)
+            }
+
+            fn delete_review_requests(&self, forgotten_ids: &[i32]) -> Result<usize, ::failure::Error> {
+                use diesel::delete;
+                use diesel::prelude::*;
+                use schema::review_requests::dsl::*;
+                use schema::{deliveries, delivery_failures};
+
+                let conn = self.conn()?;
+
+                conn.transaction(|| {
+                    delete(deliveries::table.filter(deliveries::review_request_id.eq_any(forgotten_ids)))
+                        .execute(&*conn)?;
+                    delete(delivery_failures::table.filter(delivery_failures::review_request_id.eq_any(forgotten_ids)))
+                        .execute(&*conn)?;
+                    delete(review_requests.filter(id.eq_any(forgotten_ids)))
+                        .execute(&*conn)
+                        .map_err(::failure::Error::from)
+                })
+            }
+
+            fn save_user(&self, user: &::review_handler::StoredUser) -> Result<(), ::failure::Error> {
+                use diesel::prelude::*;
+                use diesel::{insert_into, update};
+                use schema::users;
+
+                let updated = update(users::table.filter(users::name.eq(&user.name)))
+                    .set(user)
+                    .execute(&*self.conn()?)?;
+
+                if updated == 0 {
+                    insert_into(users::table).values(user).execute(&*self.conn()?)?;
+                }
+
+                Ok(())
+            }
+
+            fn users(&self) -> Result<Vec<::review_handler::StoredUser>, ::failure::Error> {
+                use diesel::prelude::*;
+                use schema::users;
+
+                users::table
+                    .select((users::name, users::github_token, users::todoist_token))
+                    .order(users::id)
+                    .load(&*self.conn()?)
+                    .map_err(::failure::Error::from)
+            }
+        }
+    };
+}
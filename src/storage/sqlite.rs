@@ -0,0 +1,57 @@
+use diesel::prelude::*;
+use diesel::replace_into;
+use diesel::sqlite::SqliteConnection;
+use failure::Error;
+
+use migrations::SQLITE_MIGRATIONS;
+use review_handler::{CachedResponse, NewPollingCursor};
+use schema::{http_cache, polling_cursors};
+use storage::redacted;
+
+/// Storage in a SQLite database, which is a file at the database URL.
+pub struct SqliteStorage {
+    conn: SqliteConnection,
+}
+
+impl SqliteStorage {
+    pub fn establish(database_url: &str) -> Result<SqliteStorage, Error> {
+        let conn = SqliteConnection::establish(database_url)
+            .map_err(|err| format_err!("Error while connecting to {}: {}", redacted(database_url), err))?;
+
+        Ok(SqliteStorage { conn })
+    }
+
+    fn conn(&self) -> Result<&SqliteConnection, Error> {
+        Ok(&self.conn)
+    }
+
+    fn has_migrations_table(&self) -> Result<bool, Error> {
+        use diesel::dsl::sql;
+        use diesel::select;
+        use diesel::sql_types::Bool;
+
+        select(sql::<Bool>(
+            "EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '__diesel_schema_migrations')",
+        ))
+        .get_result(&self.conn)
+        .map_err(Error::from)
+    }
+
+    fn upsert_polling_cursor(&self, new_cursor: &NewPollingCursor) -> Result<(), Error> {
+        replace_into(polling_cursors::table)
+            .values(new_cursor)
+            .execute(&self.conn)
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+
+    fn upsert_cached_response(&self, response: &CachedResponse) -> Result<(), Error> {
+        replace_into(http_cache::table)
+            .values(response)
+            .execute(&self.conn)
+            .map(|_| ())
+            .map_err(Error::from)
+    }
+}
+
+impl_storage!(SqliteStorage, SQLITE_MIGRATIONS);
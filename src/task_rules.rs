@@ -0,0 +1,117 @@
+use chrono::prelude::*;
+use serde_json;
+use std::env;
+use std::fs::File;
+
+use github::PullRequest;
+
+/// Rules used to derive a task's priority and due date from the pull request.
+/// They can be overridden by pointing `TASK_RULES_FILE` to a JSON file with
+/// any subset of these fields.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TaskRules {
+    /// Labels that make a review urgent: highest priority, due today
+    pub urgent_labels: Vec<String>,
+    /// Base branches whose pull requests should be reviewed first
+    pub release_branch_prefixes: Vec<String>,
+    /// Pull requests up to this many changed lines are quick wins, and get bumped
+    pub small_pr_lines: u64,
+    /// Pull requests over this many changed lines or files get more time
+    pub large_pr_lines: u64,
+    pub large_pr_files: u64,
+    pub large_pr_due_days: u32,
+    /// Pull requests opened longer ago than this get bumped and are due today
+    pub stale_after_days: i64,
+    /// Requests made to one of the user's teams are due later than direct ones
+    pub team_request_due_days: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TaskSchedule {
+    pub priority: u8,
+    pub due_in_days: u32,
+}
+
+const MIN_PRIORITY: u8 = 1;
+const MAX_PRIORITY: u8 = 4;
+
+impl Default for TaskRules {
+    fn default() -> TaskRules {
+        TaskRules {
+            urgent_labels: vec!["urgent".to_string(), "hotfix".to_string()],
+            release_branch_prefixes: vec!["release".to_string(), "hotfix".to_string()],
+            small_pr_lines: 50,
+            large_pr_lines: 500,
+            large_pr_files: 20,
+            large_pr_due_days: 2,
+            stale_after_days: 2,
+            team_request_due_days: 1,
+        }
+    }
+}
+
+impl TaskRules {
+    pub fn from_env() -> TaskRules {
+        let path = match env::var("TASK_RULES_FILE") {
+            Ok(path) => path,
+            Err(_) => return TaskRules::default(),
+        };
+
+        let file = File::open(&path).expect("TASK_RULES_FILE must point to a readable file");
+        serde_json::from_reader(file).expect("TASK_RULES_FILE must contain valid task rules")
+    }
+
+    /// Computes the schedule for a review task, starting from `base_priority`
+    /// (from 1, normal, to 4, urgent) and bumping it as rules apply.
+    pub fn schedule_for(&self, pr: &PullRequest, base_priority: Option<u8>, now: DateTime<Local>) -> TaskSchedule {
+        if self.urgent_labels.iter().any(|label| pr.has_label(label)) {
+            return TaskSchedule {
+                priority: MAX_PRIORITY,
+                due_in_days: 0,
+            };
+        }
+
+        let mut priority = base_priority.unwrap_or(MIN_PRIORITY);
+        let mut due_in_days = 0;
+
+        let is_release = self.release_branch_prefixes
+            .iter()
+            .any(|prefix| pr.base_branch().starts_with(prefix.as_str()));
+
+        if is_release {
+            priority += 1;
+        }
+
+        if pr.changed_lines() <= self.small_pr_lines {
+            priority += 1;
+        } else if pr.changed_lines() > self.large_pr_lines || pr.changed_files > self.large_pr_files {
+            due_in_days = self.large_pr_due_days;
+        }
+
+        if pr.direct_request == Some(false) {
+            due_in_days = due_in_days.max(self.team_request_due_days);
+        }
+
+        if now.signed_duration_since(pr.created_at).num_days() >= self.stale_after_days {
+            priority += 1;
+            due_in_days = 0;
+        }
+
+        TaskSchedule {
+            priority: priority.max(MIN_PRIORITY).min(MAX_PRIORITY),
+            due_in_days,
+        }
+    }
+}
+
+impl TaskSchedule {
+    /// The due date in a format the Todoist API understands
+    pub fn due_string(&self) -> String {
+        match self.due_in_days {
+            0 => "today".to_string(),
+            1 => "tomorrow".to_string(),
+            days => format!("in {} days", days),
+        }
+    }
+}
@@ -1,89 +1,677 @@
+use chrono::{DateTime, Local, Utc};
 use failure::Error;
-use futures::future;
+use futures::future::{self, Either};
 use futures::prelude::*;
 use reqwest::header::{Authorization, Headers};
 use reqwest::unstable::async::Client;
+use serde::de::DeserializeOwned;
 use slog::Logger;
+use std::collections::HashMap;
 use std::env;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use url::Url;
 
 use Config;
-use github::PullRequest;
+use clock::Clock;
+use dry_run::{self, DryRun};
+use github::{CiState, CiStatus, PullRequest};
+use task_rules::{TaskRules, TaskSchedule};
+use working_hours::WorkingHours;
+
+/// Where new tasks should be created, and how they should be tagged. Projects,
+/// sections and labels are configured by name, and resolved to ids through the
+/// Todoist API the first time a task is created. `priority` is the starting
+/// point for the task rules. While a pull request is a draft, its task gets
+/// `draft_label` and is due on `draft_due` (a Todoist due string), if set.
+/// With `comments`, the pull request description, changed files and CI status
+/// are added to the task as a comment. Without a `token`, the one in
+/// `TODOIST_TOKEN` is used.
+#[derive(Clone, Debug, Default)]
+pub struct TodoistSettings {
+    pub token: Option<String>,
+    pub project: Option<String>,
+    pub section: Option<String>,
+    pub labels: Vec<String>,
+    pub priority: Option<u8>,
+    pub draft_label: Option<String>,
+    pub draft_due: Option<String>,
+    pub comments: bool,
+}
 
 #[derive(Clone)]
 pub struct TodoistClient {
     http: Client,
     logger: Logger,
     host: Url,
+    settings: TodoistSettings,
+    /// The projects of the GitHub accounts that have their own, by account
+    account_projects: Vec<(String, String)>,
+    rules: TaskRules,
+    working_hours: Option<WorkingHours>,
+    clock: Clock,
+    resolved_ids: Arc<Mutex<Option<ResolvedIds>>>,
+    dry_run: Option<DryRun>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct ResolvedIds {
+    project_id: Option<i64>,
+    section_id: Option<i64>,
+    account_project_ids: HashMap<String, i64>,
+    label_ids: Vec<i64>,
+    draft_label_id: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct NamedResource {
+    id: i64,
+    name: String,
 }
 
 #[derive(Serialize)]
 struct NewTask {
     content: String,
-    due_string: String,
+    description: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    due_string: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    due_date: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    project_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    section_id: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    label_ids: Vec<i64>,
+    priority: u8,
+}
+
+#[derive(Deserialize)]
+struct CreatedTask {
+    id: i64,
+}
+
+#[derive(Serialize)]
+struct NewComment {
+    task_id: i64,
+    content: String,
+}
+
+/// How much of the pull request description goes into the task comment
+const DESCRIPTION_LIMIT: usize = 1000;
+/// How many changed files are listed in the task comment
+const CHANGED_FILES_LIMIT: usize = 30;
+/// Todoist's highest priority, given to tasks that were left open for too long
+const URGENT_PRIORITY: u8 = 4;
+
+/// Changes to an existing task, sent when its pull request changes.
+#[derive(Serialize)]
+struct TaskUpdate {
+    content: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label_ids: Option<Vec<i64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    due_string: Option<String>,
+}
+
+/// Makes a task as urgent as it gets.
+#[derive(Serialize)]
+struct TaskEscalation {
+    priority: u8,
+    due_string: &'static str,
+}
+
+impl TodoistSettings {
+    pub fn from_env() -> TodoistSettings {
+        let labels = env::var("TODOIST_LABELS")
+            .map(|labels| {
+                labels
+                    .split(',')
+                    .map(|label| label.trim().trim_matches('@').to_string())
+                    .filter(|label| !label.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let priority = env::var("TODOIST_PRIORITY")
+            .ok()
+            .map(|priority| match priority.parse() {
+                Ok(priority @ 1..=4) => priority,
+                _ => panic!("TODOIST_PRIORITY must be a number between 1 and 4"),
+            });
+
+        TodoistSettings {
+            token: None,
+            project: env::var("TODOIST_PROJECT").ok(),
+            section: env::var("TODOIST_SECTION").ok(),
+            labels,
+            priority,
+            draft_label: env::var("TODOIST_DRAFT_LABEL").ok(),
+            draft_due: env::var("TODOIST_DRAFT_DUE").ok(),
+            comments: ::env_flag("TODOIST_COMMENTS"),
+        }
+    }
 }
 
 impl TodoistClient {
     pub fn new(config: &Config) -> Result<TodoistClient, Error> {
-        let todoist_token = env::var("TODOIST_TOKEN")?;
-        let client = Client::builder()
+        let todoist_token = match config.todoist.token {
+            Some(ref token) => token.clone(),
+            None => env::var("TODOIST_TOKEN")?,
+        };
+        let client = config
+            .http
+            .client_builder()?
             .default_headers(default_headers(todoist_token))
             .timeout(Duration::from_secs(30))
-            .build(&config.core.handle())?;
+            .build(&config.handle)?;
+
+        if config.todoist.section.is_some() && config.todoist.project.is_none() {
+            return Err(format_err!(
+                "A todoist section can only be used together with a project"
+            ));
+        }
+
+        let account_projects = config
+            .github_accounts
+            .iter()
+            .filter_map(|account| Some((account.name.clone(), account.todoist_project.clone()?)))
+            .collect();
 
         Ok(TodoistClient {
             http: client,
             host: config.todoist_base.clone(),
             logger: config.logger.clone(),
+            settings: config.todoist.clone(),
+            account_projects,
+            rules: config.task_rules.clone(),
+            working_hours: config.working_hours.clone(),
+            clock: config.clock,
+            resolved_ids: Arc::new(Mutex::new(None)),
+            dry_run: config.dry_run.clone(),
         })
     }
 
-    pub fn create_task_for_pr(&self, pr: &PullRequest) -> impl Future<Item = (), Error = Error> {
-        let new_task = NewTask::for_pull_request(pr);
+    /// Creates a review task, resolving to the id of the new task. The `note`
+    /// is added to the task description.
+    pub fn create_task_for_pr(
+        &self,
+        pr: &PullRequest,
+        note: Option<&str>,
+    ) -> impl Future<Item = String, Error = Error> {
         let new_task_url = self.host.join("API/v8/tasks").unwrap();
         let logger = self.logger.clone();
+        let http = self.http.clone();
+        let now = self.clock.now();
+        let schedule = self
+            .rules
+            .schedule_for(pr, self.settings.priority, now.with_timezone(&Local));
+        let working_hours = self.working_hours.clone();
+        let settings = self.settings.clone();
+        let note = note.map(str::to_string);
+        let dry_run = self.dry_run.clone();
+        let pr = pr.clone();
+
+        debug!(logger, "Computed task schedule"; "schedule" => ?schedule);
+
+        self.resolve_ids().and_then(move |ids| {
+            let new_task = NewTask::for_pull_request(
+                &pr,
+                ids,
+                schedule,
+                working_hours.as_ref(),
+                now,
+                &settings,
+                note.as_ref().map(String::as_str),
+            );
+
+            if let Some(dry_run) = dry_run {
+                dry_run.record("create todoist task", &new_task);
+                return Either::B(future::ok(dry_run::PLACEHOLDER_ID.to_string()));
+            }
+
+            let request = http.post(new_task_url).json(&new_task).send();
+            let created = request.then(move |response| match response {
+                Ok(mut ok_response) => {
+                    if ok_response.status().is_success() {
+                        let task = ok_response
+                            .json::<CreatedTask>()
+                            .map(|task| task.id.to_string())
+                            .map_err(Error::from);
+
+                        return Either::A(task);
+                    }
+
+                    error!(logger, "Error while creating todoist task"; "response" => ?ok_response);
+                    return Either::B(future::err(format_err!(
+                        "Error while creating todoist task. response: {:?}",
+                        ok_response
+                    )));
+                }
 
-        let request = self.http.post(new_task_url).json(&new_task).send();
-        request.then(move |response| match response {
-            Ok(ok_response) => {
-                if ok_response.status().is_success() {
-                    return future::ok(());
+                Err(err) => {
+                    let err = Error::from(err);
+                    error!(logger, "Error while creating todoist task"; "error" => %err);
+                    return Either::B(future::err(err));
                 }
+            });
 
-                error!(logger, "Error while creating todoist task"; "response" => ?ok_response);
-                return future::err(format_err!(
-                    "Error while creating todoist task. response: {:?}",
-                    ok_response
-                ));
+            Either::A(created)
+        })
+    }
+
+    /// Brings the content and labels of an existing task up to date with its
+    /// pull request. Draft pull requests also get their task postponed.
+    pub fn update_task_for_pr(&self, task_id: &str, pr: &PullRequest) -> impl Future<Item = (), Error = Error> {
+        let task_url = match self.host.join(&format!("API/v8/tasks/{}", task_id)) {
+            Ok(url) => url,
+            Err(err) => return Either::A(future::err(Error::from(err))),
+        };
+
+        let http = self.http.clone();
+        let settings = self.settings.clone();
+        let dry_run = self.dry_run.clone();
+        let pr = pr.clone();
+
+        let update = self.resolve_ids().and_then(move |ids| {
+            let update = TaskUpdate::for_pull_request(&pr, ids, &settings);
+
+            if let Some(dry_run) = dry_run {
+                dry_run.record("update todoist task", &update);
+                return Either::A(future::ok(()));
             }
 
-            Err(err) => {
-                let err = Error::from(err);
-                error!(logger, "Error while creating todoist task"; "error" => %err);
-                return future::err(err);
+            let sent = http.post(task_url)
+                .json(&update)
+                .send()
+                .and_then(|response| response.error_for_status())
+                .map(|_| ())
+                .map_err(Error::from);
+
+            Either::B(sent)
+        });
+
+        Either::B(update)
+    }
+
+    /// Bumps the task to the highest priority and makes it due today.
+    pub fn escalate_task(&self, task_id: &str) -> impl Future<Item = (), Error = Error> {
+        let task_url = match self.host.join(&format!("API/v8/tasks/{}", task_id)) {
+            Ok(url) => url,
+            Err(err) => return Either::A(future::err(Error::from(err))),
+        };
+
+        let escalation = TaskEscalation {
+            priority: URGENT_PRIORITY,
+            due_string: "today",
+        };
+
+        if let Some(ref dry_run) = self.dry_run {
+            dry_run.record("escalate todoist task", &escalation);
+            return Either::A(future::ok(()));
+        }
+
+        let request = self.http
+            .post(task_url)
+            .json(&escalation)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(Error::from);
+
+        Either::B(request)
+    }
+
+    pub fn comments_enabled(&self) -> bool {
+        self.settings.comments
+    }
+
+    /// Adds a comment to the task with what's needed to start the review.
+    pub fn add_review_context(
+        &self,
+        task_id: &str,
+        pr: &PullRequest,
+        changed_files: &[String],
+        ci: &CiStatus,
+    ) -> impl Future<Item = (), Error = Error> {
+        let content = format!(
+            "{description}\n\n{files}\n\n{ci}",
+            description = description_section(pr),
+            files = changed_files_section(pr.changed_files, changed_files),
+            ci = ci_section(ci),
+        );
+
+        self.add_comment(task_id, content)
+    }
+
+    pub fn add_ci_comment(&self, task_id: &str, ci: &CiStatus) -> impl Future<Item = (), Error = Error> {
+        let mut content = format!("**CI is {} now** ({} checks)", ci_verb(ci.state), ci.checks.len());
+
+        if ci.state == CiState::Failure {
+            for check in ci.failing_checks() {
+                content.push_str(&format!("\n- {} failed", check.name));
             }
-        })
+        }
+
+        self.add_comment(task_id, content)
+    }
+
+    fn add_comment(&self, task_id: &str, content: String) -> impl Future<Item = (), Error = Error> {
+        // Tasks of a dry run only have a placeholder id
+        if let Some(ref dry_run) = self.dry_run {
+            dry_run.record("add todoist comment", &content);
+            return Either::A(future::ok(()));
+        }
+
+        let task_id = match task_id.parse() {
+            Ok(task_id) => task_id,
+            Err(_) => return Either::A(future::err(format_err!("Invalid todoist task id: {:?}", task_id))),
+        };
+
+        let comments_url = match self.host.join("API/v8/comments") {
+            Ok(url) => url,
+            Err(err) => return Either::A(future::err(Error::from(err))),
+        };
+
+        let comment = NewComment { task_id, content };
+
+        let request = self.http
+            .post(comments_url)
+            .json(&comment)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(Error::from);
+
+        Either::B(request)
+    }
+
+    fn resolve_ids(&self) -> impl Future<Item = ResolvedIds, Error = Error> {
+        if let Some(ids) = self.resolved_ids.lock().unwrap().clone() {
+            return Either::A(future::ok(ids));
+        }
+
+        let cache = self.resolved_ids.clone();
+        let logger = self.logger.clone();
+
+        let project_and_section = self.resolve_project_and_section();
+        let labels = self.resolve_labels();
+
+        let resolved = project_and_section.join(labels).map(
+            move |((project_id, section_id, account_project_ids), (label_ids, draft_label_id))| {
+                let ids = ResolvedIds {
+                    project_id,
+                    section_id,
+                    account_project_ids,
+                    label_ids,
+                    draft_label_id,
+                };
+
+                debug!(logger, "Resolved todoist ids"; "ids" => ?ids);
+                *cache.lock().unwrap() = Some(ids.clone());
+
+                ids
+            },
+        );
+
+        Either::B(resolved)
+    }
+
+    /// Resolves the configured project and section, along with the projects
+    /// of the accounts that have their own.
+    fn resolve_project_and_section(
+        &self,
+    ) -> impl Future<Item = (Option<i64>, Option<i64>, HashMap<String, i64>), Error = Error> {
+        if self.settings.project.is_none() && self.account_projects.is_empty() {
+            return Either::A(future::ok((None, None, HashMap::new())));
+        }
+
+        let project_name = self.settings.project.clone();
+        let section_name = self.settings.section.clone();
+        let account_projects = self.account_projects.clone();
+        let client = self.clone();
+
+        let projects = self.get_resources("API/v8/projects");
+        let resolved = projects.and_then(move |projects| {
+            let project_id = match project_name {
+                Some(ref name) => Some(find_by_name(&projects, name, "project")?),
+                None => None,
+            };
+
+            let account_project_ids = account_projects
+                .iter()
+                .map(|(account, name)| Ok((account.clone(), find_by_name(&projects, name, "project")?)))
+                .collect::<Result<HashMap<_, _>, Error>>()?;
+
+            Ok((project_id, section_name, account_project_ids))
+        });
+
+        let resolved = resolved.and_then(move |(project_id, section_name, account_project_ids)| {
+            let (project_id, section_name) = match (project_id, section_name) {
+                (Some(project_id), Some(section_name)) => (project_id, section_name),
+                (project_id, _) => return Either::A(future::ok((project_id, None, account_project_ids))),
+            };
+
+            let sections_path = format!("API/v8/sections?project_id={}", project_id);
+            let section = client.get_resources(&sections_path).and_then(move |sections| {
+                let section_id = find_by_name(&sections, &section_name, "section")?;
+                Ok((Some(project_id), Some(section_id), account_project_ids))
+            });
+
+            Either::B(section)
+        });
+
+        Either::B(resolved)
+    }
+
+    fn resolve_labels(&self) -> impl Future<Item = (Vec<i64>, Option<i64>), Error = Error> {
+        if self.settings.labels.is_empty() && self.settings.draft_label.is_none() {
+            return Either::A(future::ok((vec![], None)));
+        }
+
+        let label_names = self.settings.labels.clone();
+        let draft_label = self.settings.draft_label.clone();
+
+        let resolved = self.get_resources("API/v8/labels").and_then(move |labels| {
+            let label_ids = label_names
+                .iter()
+                .map(|name| find_by_name(&labels, name, "label"))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let draft_label_id = match draft_label {
+                Some(ref name) => Some(find_by_name(&labels, name, "label")?),
+                None => None,
+            };
+
+            Ok((label_ids, draft_label_id))
+        });
+
+        Either::B(resolved)
+    }
+
+    fn get_resources<T: DeserializeOwned>(&self, path: &str) -> impl Future<Item = Vec<T>, Error = Error> {
+        let url = match self.host.join(path) {
+            Ok(url) => url,
+            Err(err) => return Either::A(future::err(Error::from(err))),
+        };
+
+        let request = self
+            .http
+            .get(url)
+            .send()
+            .and_then(|response| response.error_for_status());
+        let resources = request
+            .and_then(|mut response| response.json::<Vec<T>>())
+            .map_err(Error::from);
+
+        Either::B(resources)
     }
 }
 
 impl NewTask {
-    fn for_pull_request(pr: &PullRequest) -> NewTask {
-        let content = format!(
-            "{url} ({project}#{number}: {title})",
-            url = pr.html_url,
+    fn for_pull_request(
+        pr: &PullRequest,
+        ids: ResolvedIds,
+        schedule: TaskSchedule,
+        working_hours: Option<&WorkingHours>,
+        now: DateTime<Utc>,
+        settings: &TodoistSettings,
+        note: Option<&str>,
+    ) -> NewTask {
+        let mut description = format!(
+            "Review requested on {project}#{number}, opened {created_at}",
             project = pr.repo(),
             number = pr.number,
-            title = pr.title
+            created_at = pr.created_at.format("%Y-%m-%d %H:%M"),
         );
 
+        if let Some(note) = note {
+            description.push_str("\n\n");
+            description.push_str(note);
+        }
+
+        // With working hours, the due date is counted in working days, so it
+        // has to be an exact date rather than something like "tomorrow"
+        let (due_string, due_date) = match working_hours {
+            Some(hours) => {
+                let due_date = hours.due_date(now, schedule.due_in_days);
+                (None, Some(due_date.format("%Y-%m-%d").to_string()))
+            }
+
+            None => (Some(schedule.due_string()), None),
+        };
+
+        let (due_string, due_date) = match draft_due(pr, settings) {
+            Some(draft_due) => (Some(draft_due), None),
+            None => (due_string, due_date),
+        };
+
+        // Sections belong to the configured project, so they don't apply to
+        // the project of an account
+        let (project_id, section_id) = match ids.account_project_ids.get(&pr.account) {
+            Some(&project_id) => (Some(project_id), None),
+            None => (ids.project_id, ids.section_id),
+        };
+
         NewTask {
-            content,
-            due_string: "today".to_string(),
+            content: task_content(pr),
+            description,
+            due_string,
+            due_date,
+            project_id,
+            section_id,
+            label_ids: task_labels(pr, ids),
+            priority: schedule.priority,
         }
     }
 }
 
+impl TaskUpdate {
+    fn for_pull_request(pr: &PullRequest, ids: ResolvedIds, settings: &TodoistSettings) -> TaskUpdate {
+        // Labels are only sent if reviewist manages any, so that labels added
+        // by hand are kept otherwise
+        let manages_labels = !ids.label_ids.is_empty() || ids.draft_label_id.is_some();
+
+        TaskUpdate {
+            content: task_content(pr),
+            label_ids: if manages_labels { Some(task_labels(pr, ids)) } else { None },
+            due_string: draft_due(pr, settings),
+        }
+    }
+}
+
+fn task_content(pr: &PullRequest) -> String {
+    format!(
+        "{url} ({project}#{number}: {title})",
+        url = pr.html_url,
+        project = pr.repo(),
+        number = pr.number,
+        title = pr.title
+    )
+}
+
+fn description_section(pr: &PullRequest) -> String {
+    let body = pr.body.as_ref().map(|body| body.trim()).unwrap_or("");
+
+    if body.is_empty() {
+        return "**Description**\n_No description provided_".to_string();
+    }
+
+    let mut description: String = body.chars().take(DESCRIPTION_LIMIT).collect();
+    if description.len() < body.len() {
+        description.push_str("…");
+    }
+
+    format!("**Description**\n{}", description)
+}
+
+/// Lists the first of the changed files. `total` is the count from the pull
+/// request, since the listed files are only the first page of them.
+fn changed_files_section(total: u64, changed_files: &[String]) -> String {
+    let mut section = format!("**Changed files** ({})", total);
+    let listed = changed_files.iter().take(CHANGED_FILES_LIMIT);
+    let listed_count = listed.len() as u64;
+
+    for file in listed {
+        section.push_str(&format!("\n- `{}`", file));
+    }
+
+    if total > listed_count {
+        section.push_str(&format!("\n- …and {} more", total - listed_count));
+    }
+
+    section
+}
+
+fn ci_section(ci: &CiStatus) -> String {
+    let mut section = format!("**CI**: {} ({} checks)", ci.state, ci.checks.len());
+
+    if ci.state == CiState::Failure {
+        for check in ci.failing_checks() {
+            section.push_str(&format!("\n- {} failed", check.name));
+        }
+    }
+
+    section
+}
+
+fn ci_verb(state: CiState) -> &'static str {
+    match state {
+        CiState::Pending => "pending",
+        CiState::Success => "passing",
+        CiState::Failure => "failing",
+    }
+}
+
+fn task_labels(pr: &PullRequest, ids: ResolvedIds) -> Vec<i64> {
+    let mut label_ids = ids.label_ids;
+
+    if let (true, Some(draft_label_id)) = (pr.draft, ids.draft_label_id) {
+        label_ids.push(draft_label_id);
+    }
+
+    label_ids
+}
+
+fn draft_due(pr: &PullRequest, settings: &TodoistSettings) -> Option<String> {
+    if pr.draft {
+        settings.draft_due.clone()
+    } else {
+        None
+    }
+}
+
+fn find_by_name(resources: &[NamedResource], name: &str, kind: &str) -> Result<i64, Error> {
+    resources
+        .iter()
+        .find(|resource| resource.name == name)
+        .map(|resource| resource.id)
+        .ok_or_else(|| format_err!("Unable to find todoist {} named {:?}", kind, name))
+}
+
 fn default_headers(todoist_token: String) -> Headers {
     let mut headers = Headers::new();
     let auth_header = Authorization(format!("Bearer {}", todoist_token));
@@ -0,0 +1,159 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use chrono_tz::Tz;
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// When the user is expected to be reviewing code. Used to push due dates to
+/// the next working day, and optionally to hold review requests that arrive
+/// outside of working hours until the next working slot.
+#[derive(Clone, Debug)]
+pub struct WorkingHours {
+    pub timezone: Tz,
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub holidays: Vec<NaiveDate>,
+    pub hold_outside_hours: bool,
+}
+
+/// How far ahead to look for a working day before giving up, in case every
+/// day is configured as a holiday.
+const MAX_LOOKAHEAD_DAYS: i64 = 366;
+
+impl WorkingHours {
+    /// Reads the working hours from `WORKING_HOURS` (e.g. `09:00-18:00`), which
+    /// must be set for the rest of the settings to be considered.
+    pub fn from_env() -> Option<WorkingHours> {
+        let hours = env::var("WORKING_HOURS").ok()?;
+        let (start, end) = parse_hours(&hours).expect("WORKING_HOURS must look like 09:00-18:00");
+
+        let timezone = env::var("WORKING_TIMEZONE")
+            .map(|timezone| {
+                timezone
+                    .parse()
+                    .expect("WORKING_TIMEZONE must be a timezone name, like Europe/Berlin")
+            })
+            .unwrap_or(Tz::UTC);
+
+        let days = match env::var("WORKING_DAYS") {
+            Ok(days) => days
+                .split(',')
+                .map(|day| day.trim().parse().expect("WORKING_DAYS must be a list of weekdays"))
+                .collect(),
+            Err(_) => vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri],
+        };
+
+        if days.is_empty() {
+            panic!("WORKING_DAYS must contain at least one weekday");
+        }
+
+        let holidays = match env::var("HOLIDAYS_FILE") {
+            Ok(path) => read_holidays(&path),
+            Err(_) => vec![],
+        };
+
+        Some(WorkingHours {
+            timezone,
+            days,
+            start,
+            end,
+            holidays,
+            hold_outside_hours: ::env_flag("HOLD_OUTSIDE_WORKING_HOURS"),
+        })
+    }
+
+    pub fn is_working_time(&self, at: DateTime<Utc>) -> bool {
+        let local = at.with_timezone(&self.timezone);
+        let time = local.time();
+
+        self.is_working_day(local.date().naive_local()) && time >= self.start && time < self.end
+    }
+
+    /// The first moment, starting at `at`, that falls within working hours.
+    pub fn next_working_slot(&self, at: DateTime<Utc>) -> DateTime<Utc> {
+        if self.is_working_time(at) {
+            return at;
+        }
+
+        let local = at.with_timezone(&self.timezone);
+        let today = local.date().naive_local();
+
+        let day = if self.is_working_day(today) && local.time() < self.start {
+            today
+        } else {
+            self.next_working_day(today)
+        };
+
+        let start = day.and_time(self.start);
+        self.timezone
+            .from_local_datetime(&start)
+            .earliest()
+            .unwrap_or_else(|| self.timezone.from_utc_datetime(&start))
+            .with_timezone(&Utc)
+    }
+
+    /// The date a task should be due if it was requested at `at` and should
+    /// take `working_days` days, counting from the next working slot.
+    pub fn due_date(&self, at: DateTime<Utc>, working_days: u32) -> NaiveDate {
+        let slot = self.next_working_slot(at).with_timezone(&self.timezone);
+
+        (0..working_days).fold(slot.date().naive_local(), |day, _| self.next_working_day(day))
+    }
+
+    /// How many working days passed from `from` to `to`, not counting the
+    /// day of `from` itself.
+    pub fn working_days_between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> u32 {
+        let mut day = from.with_timezone(&self.timezone).date().naive_local();
+        let last_day = to.with_timezone(&self.timezone).date().naive_local();
+
+        let mut working_days = 0;
+        while day < last_day {
+            day = day.succ();
+
+            if self.is_working_day(day) {
+                working_days += 1;
+            }
+        }
+
+        working_days
+    }
+
+    fn is_working_day(&self, day: NaiveDate) -> bool {
+        self.days.contains(&day.weekday()) && !self.holidays.contains(&day)
+    }
+
+    fn next_working_day(&self, after: NaiveDate) -> NaiveDate {
+        (1..MAX_LOOKAHEAD_DAYS + 1)
+            .map(|offset| after + Duration::days(offset))
+            .find(|&day| self.is_working_day(day))
+            .unwrap_or_else(|| after.succ())
+    }
+}
+
+fn parse_hours(hours: &str) -> Option<(NaiveTime, NaiveTime)> {
+    let mut parts = hours.splitn(2, '-');
+    let start = NaiveTime::parse_from_str(parts.next()?.trim(), "%H:%M").ok()?;
+    let end = NaiveTime::parse_from_str(parts.next()?.trim(), "%H:%M").ok()?;
+
+    if start < end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+/// Reads one `YYYY-MM-DD` date per line. Empty lines and lines starting with
+/// `#` are ignored.
+fn read_holidays(path: &str) -> Vec<NaiveDate> {
+    let file = File::open(path).expect("HOLIDAYS_FILE must point to a readable file");
+
+    BufReader::new(file)
+        .lines()
+        .map(|line| line.expect("Error while reading HOLIDAYS_FILE"))
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| NaiveDate::parse_from_str(&line, "%Y-%m-%d").expect("HOLIDAYS_FILE must contain YYYY-MM-DD dates"))
+        .collect()
+}
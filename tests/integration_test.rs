@@ -1,93 +1,1670 @@
 #![type_length_limit = "2097152"]
 
+extern crate chrono;
+extern crate chrono_tz;
 extern crate failure;
 extern crate fake_github;
 extern crate futures;
 extern crate ipc_channel;
 extern crate nix;
+extern crate reqwest;
 extern crate reviewist;
 #[macro_use]
+extern crate serde_json;
+#[macro_use]
 extern crate slog;
 extern crate tokio_core;
 extern crate tokio_timer;
 extern crate url;
 
-use failure::Error;
-use futures::future::{self, Either};
-use futures::prelude::*;
-use std::process::{Command, Stdio};
-use std::time::{Duration, Instant};
-use tokio_core::reactor::Core;
-use tokio_timer::Delay;
+use chrono::prelude::*;
+use failure::Error;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use reqwest::header::{Cookie, Location, SetCookie};
+use reqwest::StatusCode;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::Core;
+use tokio_timer::Delay;
+
+use fake_github::{Message, Response};
+use ipc_channel::ipc;
+use reviewist::{
+    Clock, Config, DryRun, GithubAccount, GithubApp, GithubCredentials, HttpSettings, NotificationMark, OauthApp,
+    OnboardingSettings, Reminders, ReviewSource, SecretKey, TaskRules, TodoistSettings, UserCredentials,
+    UserTokenRefresh, WorkingHours, DEFAULT_ACCOUNT,
+};
+use std::env;
+use url::Url;
+
+#[test]
+fn test_one_pr() {
+    let result = with_fake_server(|server, db| {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let future = build_main_future(&core, &server, &db);
+        let limited_future = time_limit(future, 1);
+
+        core.run(limited_future).map(move |_| {
+            server.sender.send(Message::GetTaskCount).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::TaskCountResponse(count)) => count,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let task_count = result.unwrap();
+    assert_eq!(task_count, 1);
+}
+
+#[test]
+fn test_multiple_prs() {
+    const PR_COUNT: usize = 10;
+
+    let result = with_fake_server(|server, db| {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        for _ in 0..PR_COUNT {
+            server.sender.send(Message::AddReviewRequest).ok();
+        }
+
+        let future = build_main_future(&core, &server, &db);
+        let limited_future = time_limit(future, 1);
+
+        core.run(limited_future).map(move |_| {
+            server.sender.send(Message::GetTaskCount).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::TaskCountResponse(count)) => count,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let task_count = result.unwrap();
+    assert_eq!(task_count, PR_COUNT);
+}
+
+#[test]
+fn test_matrix_sink() {
+    let result = with_fake_server(|server, db| {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut config = build_config(&core, &server, &db);
+        config.matrix_base = Some(Url::parse(&format!("http://{}/matrix/", server.address)).unwrap());
+
+        let future = reviewist::run(config);
+        let limited_future = time_limit(future, 1);
+
+        core.run(limited_future).map(move |_| {
+            server.sender.send(Message::GetMatrixMessageCount).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::MatrixMessageCountResponse(count)) => count,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let message_count = result.unwrap();
+    assert_eq!(message_count, 1);
+}
+
+#[test]
+fn test_todoist_task_settings() {
+    let result = with_fake_server(|server, db| {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut config = build_config(&core, &server, &db);
+        config.todoist = TodoistSettings {
+            token: None,
+            project: Some("Code Review".to_string()),
+            section: Some("Incoming".to_string()),
+            labels: vec!["review".to_string()],
+            priority: Some(3),
+            draft_label: None,
+            draft_due: None,
+            comments: false,
+        };
+
+        let future = reviewist::run(config);
+        let limited_future = time_limit(future, 1);
+
+        core.run(limited_future).map(move |_| {
+            server.sender.send(Message::GetLastTask).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::LastTaskResponse(Some(task))) => task,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let task: serde_json::Value = serde_json::from_str(&result.unwrap()).unwrap();
+    assert_eq!(task["project_id"], 101);
+    assert_eq!(task["section_id"], 200);
+    assert_eq!(task["label_ids"], json!([300]));
+    assert_eq!(task["priority"], 3);
+}
+
+#[test]
+fn test_urgent_label_rule() {
+    let result = with_fake_server(|server, db| {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        server.sender.send(Message::AddReviewRequest).ok();
+        server
+            .sender
+            .send(Message::SetPullRequestLabels(vec!["hotfix".to_string()]))
+            .ok();
+
+        let future = build_main_future(&core, &server, &db);
+        let limited_future = time_limit(future, 1);
+
+        core.run(limited_future).map(move |_| {
+            server.sender.send(Message::GetLastTask).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::LastTaskResponse(Some(task))) => task,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let task: serde_json::Value = serde_json::from_str(&result.unwrap()).unwrap();
+    assert_eq!(task["priority"], 4);
+    assert_eq!(task["due_string"], "today");
+}
+
+#[test]
+fn test_task_updated_when_pr_changes() {
+    let result = with_fake_server(|server, db| {
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let future = build_main_future(&core, &server, &db);
+        core.run(time_limit(future, 1))?;
+
+        server
+            .sender
+            .send(Message::SetPullRequestTitle("Renamed PR".to_string()))
+            .ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let future = build_main_future(&core, &server, &db);
+
+        core.run(time_limit(future, 1)).map(move |_| {
+            server.sender.send(Message::GetLastTaskUpdate).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::LastTaskUpdateResponse(Some(update))) => update,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let (task_id, update) = result.unwrap();
+    let update: serde_json::Value = serde_json::from_str(&update).unwrap();
+    assert_eq!(task_id, "1000");
+    assert_eq!(update["content"], "https://example.com (reviewist#0: Renamed PR)");
+}
+
+#[test]
+fn test_review_commands() {
+    let result = with_fake_server(|server, db| -> Result<_, Error> {
+        server.sender.send(Message::AddReviewRequest).ok();
+        server.sender.send(Message::FailMatrixMessages(1)).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.matrix_base = Some(Url::parse(&format!("http://{}/matrix/", server.address)).unwrap());
+        core.run(time_limit(reviewist::run(config.clone()), 1))?;
+
+        let failed = core.run(reviewist::list_reviews(&config, false))?;
+        let retried = core.run(reviewist::retry_deliveries(&config))?;
+        let after_retry = core.run(reviewist::list_reviews(&config, false))?;
+
+        server
+            .sender
+            .send(Message::SetPullRequestTitle("Renamed PR".to_string()))
+            .ok();
+        let synced = core.run(reviewist::sync_reviews(&config))?;
+
+        let review = "renato-zannon/reviewist#0".parse().unwrap();
+        let shown = core.run(reviewist::show_review(&config, &review))?;
+
+        let other_owner = "octocat/reviewist#0".parse().unwrap();
+        let shown_for_other_owner = core.run(reviewist::show_review(&config, &other_owner))?;
+        let forgotten_for_other_owner = core.run(reviewist::forget_review(&config, &other_owner))?;
+
+        let forgotten = core.run(reviewist::forget_review(&config, &review))?;
+        let after_forget = core.run(reviewist::list_reviews(&config, true))?;
+
+        server.sender.send(Message::GetMatrixMessageCount).ok();
+        let matrix_messages = match server.receiver.recv() {
+            Ok(Response::MatrixMessageCountResponse(count)) => count,
+            response => panic!("Unexpected response: {:?}", response),
+        };
+
+        server.sender.send(Message::GetLastTaskUpdate).ok();
+        let task_update = match server.receiver.recv() {
+            Ok(Response::LastTaskUpdateResponse(Some(update))) => update,
+            response => panic!("Unexpected response: {:?}", response),
+        };
+
+        Ok((
+            failed,
+            retried,
+            after_retry,
+            matrix_messages,
+            synced,
+            task_update,
+            (shown, shown_for_other_owner),
+            (forgotten, forgotten_for_other_owner),
+            after_forget,
+        ))
+    });
+
+    let (failed, retried, after_retry, matrix_messages, synced, task_update, shown, forgotten, after_forget) =
+        result.unwrap();
+    let (shown, shown_for_other_owner) = shown;
+    let (forgotten, forgotten_for_other_owner) = forgotten;
+
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].pull_request.to_string(), "renato-zannon/reviewist#0");
+    assert_eq!(failed[0].account, DEFAULT_ACCOUNT);
+    assert_eq!(failed[0].state, "delivered");
+    assert_eq!(failed[0].deliveries.len(), 1);
+    assert_eq!(failed[0].deliveries[0].sink, "todoist");
+    assert_eq!(failed[0].deliveries[0].external_id, Some("1000".to_string()));
+    assert_eq!(failed[0].failures.len(), 1);
+    assert_eq!(failed[0].failures[0].sink, "matrix");
+    assert!(failed[0].failures[0].error.contains("BadGateway"));
+
+    assert_eq!(retried, 1);
+    assert_eq!(matrix_messages, 1);
+    assert_eq!(after_retry[0].deliveries.len(), 2);
+    assert!(after_retry[0].failures.is_empty());
+
+    let (task_id, task_update) = task_update;
+    let task_update: serde_json::Value = serde_json::from_str(&task_update).unwrap();
+    assert_eq!(synced, 1);
+    assert_eq!(task_id, "1000");
+    assert_eq!(task_update["content"], "https://example.com (reviewist#0: Renamed PR)");
+
+    assert_eq!(shown.len(), 1);
+    assert_eq!(shown[0].title, "Some important PR");
+    assert!(shown_for_other_owner.is_empty());
+    assert_eq!(forgotten_for_other_owner, 0);
+    assert_eq!(forgotten, 1);
+    assert!(after_forget.is_empty());
+}
+
+#[test]
+fn test_review_context_comments() {
+    let result = with_fake_server(|server, db| {
+        server.sender.send(Message::AddReviewRequest).ok();
+        server.sender.send(Message::SetCiState("failure".to_string())).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.todoist.comments = true;
+        core.run(time_limit(reviewist::run(config), 1))?;
+
+        server.sender.send(Message::SetCiState("success".to_string())).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.todoist.comments = true;
+
+        core.run(time_limit(reviewist::run(config), 1)).map(move |_| {
+            server.sender.send(Message::GetComments).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::CommentsResponse(comments)) => comments,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let comments = result.unwrap();
+    assert_eq!(comments.len(), 2);
+    assert!(comments[0].contains("Makes everything faster"));
+    assert!(comments[0].contains("`src/lib.rs`"));
+    assert!(comments[0].contains("**CI**: failure"));
+    assert!(comments[0].contains("ci/build failed"));
+    assert!(comments[1].contains("CI is passing now"));
+}
+
+#[test]
+fn test_pending_ci_comments() {
+    let result = with_fake_server(|server, db| {
+        server.sender.send(Message::AddReviewRequest).ok();
+        server.sender.send(Message::SetCiState("pending".to_string())).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.todoist.comments = true;
+        core.run(time_limit(reviewist::run(config), 1))?;
+
+        server.sender.send(Message::SetCiState("failure".to_string())).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.todoist.comments = true;
+
+        core.run(time_limit(reviewist::run(config), 1)).map(move |_| {
+            server.sender.send(Message::GetComments).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::CommentsResponse(comments)) => comments,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let comments = result.unwrap();
+    assert_eq!(comments.len(), 2);
+    assert!(comments[0].contains("**Changed files** (4)"));
+    assert!(comments[0].contains("**CI**: pending"));
+    assert!(comments[1].contains("CI is failing now"));
+    assert!(comments[1].contains("ci/build failed"));
+}
+
+#[test]
+fn test_wait_for_ci() {
+    let result = with_fake_server(|server, db| {
+        server.sender.send(Message::AddReviewRequest).ok();
+        server.sender.send(Message::SetCiState("failure".to_string())).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.wait_for_ci = Some(chrono::Duration::hours(1));
+        core.run(time_limit(reviewist::run(config), 1))?;
+
+        server.sender.send(Message::GetTaskCount).ok();
+        let count_while_failing = match server.receiver.recv() {
+            Ok(Response::TaskCountResponse(count)) => count,
+            response => panic!("Unexpected response: {:?}", response),
+        };
+
+        server.sender.send(Message::SetCiState("success".to_string())).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.wait_for_ci = Some(chrono::Duration::hours(1));
+
+        core.run(time_limit(reviewist::run(config), 1)).map(move |_| {
+            server.sender.send(Message::GetTaskCount).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::TaskCountResponse(count)) => (count_while_failing, count),
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let (count_while_failing, count_after_passing) = result.unwrap();
+    assert_eq!(count_while_failing, 0);
+    assert_eq!(count_after_passing, 1);
+}
+
+#[test]
+fn test_ci_failure_threshold() {
+    let result = with_fake_server(|server, db| {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        server.sender.send(Message::AddReviewRequest).ok();
+        server.sender.send(Message::SetCiState("failure".to_string())).ok();
+
+        let mut config = build_config(&core, &server, &db);
+        config.wait_for_ci = Some(chrono::Duration::zero());
+
+        let future = reviewist::run(config);
+        let limited_future = time_limit(future, 1);
+
+        core.run(limited_future).map(move |_| {
+            server.sender.send(Message::GetLastTask).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::LastTaskResponse(Some(task))) => task,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let task: serde_json::Value = serde_json::from_str(&result.unwrap()).unwrap();
+    let description = task["description"].as_str().unwrap();
+    assert!(description.contains("CI has been failing since"));
+    assert!(description.contains("ci/build"));
+}
+
+#[test]
+fn test_stale_review_reminder() {
+    let result = with_fake_server(|server, db| {
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let future = build_main_future(&core, &server, &db);
+        core.run(time_limit(future, 1))?;
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.matrix_base = Some(Url::parse(&format!("http://{}/matrix/", server.address)).unwrap());
+        config.reminders = Some(Reminders {
+            after_days: 0,
+            sink: Some("matrix".to_string()),
+        });
+
+        core.run(time_limit(reviewist::run(config), 1)).map(move |_| {
+            server.sender.send(Message::GetLastTaskUpdate).ok();
+            let update = match server.receiver.recv() {
+                Ok(Response::LastTaskUpdateResponse(Some(update))) => update,
+                response => panic!("Unexpected response: {:?}", response),
+            };
+
+            server.sender.send(Message::GetMatrixMessageCount).ok();
+            match server.receiver.recv() {
+                Ok(Response::MatrixMessageCountResponse(count)) => (update, count),
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let ((task_id, update), message_count) = result.unwrap();
+    let update: serde_json::Value = serde_json::from_str(&update).unwrap();
+    assert_eq!(task_id, "1000");
+    assert_eq!(update["priority"], 4);
+    assert_eq!(update["due_string"], "today");
+    assert_eq!(message_count, 1);
+}
+
+#[test]
+fn test_stale_review_reminder_follows_clock() {
+    let result = with_fake_server(|server, db| {
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let future = build_main_future(&core, &server, &db);
+        core.run(time_limit(future, 1))?;
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.matrix_base = Some(Url::parse(&format!("http://{}/matrix/", server.address)).unwrap());
+        config.reminders = Some(Reminders {
+            after_days: 2,
+            sink: Some("matrix".to_string()),
+        });
+        config.clock = Clock::Fixed(Utc::now() + chrono::Duration::days(3));
+
+        core.run(time_limit(reviewist::run(config), 1)).map(move |_| matrix_message_count(&server))
+    });
+
+    assert_eq!(result.unwrap(), 1);
+}
+
+#[test]
+fn test_polling_resumes_after_restart() {
+    let result = with_fake_server(|server, db| {
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let future = build_main_future(&core, &server, &db);
+        core.run(time_limit(future, 1))?;
+
+        let first_run_polls = if_modified_since(&server).len();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let future = build_main_future(&core, &server, &db);
+        core.run(time_limit(future, 1))?;
+
+        let second_run_polls = if_modified_since(&server).len();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.look_back = Some(Duration::from_secs(60 * 60 * 24));
+
+        core.run(time_limit(reviewist::run(config), 1)).map(move |_| {
+            let polls = if_modified_since(&server);
+            (polls[first_run_polls].clone(), polls[second_run_polls].clone())
+        })
+    });
+
+    let (resumed_since, look_back_since) = result.unwrap();
+    assert_eq!(resumed_since, fake_github::NOTIFICATIONS_LAST_MODIFIED);
+
+    let yesterday = Utc::today().pred().format("%d %b %Y").to_string();
+    assert!(look_back_since.contains(&yesterday));
+}
+
+#[test]
+fn test_once() {
+    let result = with_fake_server(|server, db| -> Result<_, Error> {
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.once = true;
+        let first_run = core.run(within(reviewist::run(config.clone()), 5))?;
+
+        // The second run resumes from the first one
+        let second_run = core.run(within(reviewist::run(config.clone()), 5))?;
+        let polls = if_modified_since(&server);
+
+        // Deliveries to a sink that doesn't answer fail
+        server.sender.send(Message::AddReviewRequest).ok();
+        config.matrix_base = Some(Url::parse(&format!("http://{}/nowhere/", server.address)).unwrap());
+        let failed_run = core.run(within(reviewist::run(config.clone()), 5))?;
+
+        server.sender.send(Message::GetTaskCount).ok();
+        let task_count = match server.receiver.recv() {
+            Ok(Response::TaskCountResponse(count)) => count,
+            response => panic!("Unexpected response: {:?}", response),
+        };
+
+        // A run without new review requests doesn't fail over earlier failures
+        let later_run = core.run(within(reviewist::run(config), 5))?;
+
+        Ok((first_run, second_run, polls, failed_run, task_count, later_run))
+    });
+
+    let (first_run, second_run, polls, failed_run, task_count, later_run) = result.unwrap();
+
+    assert!(first_run.unwrap().is_ok());
+    assert!(second_run.unwrap().is_ok());
+    assert_eq!(polls.len(), 2);
+    assert_eq!(polls[1], fake_github::NOTIFICATIONS_LAST_MODIFIED);
+
+    let err = failed_run.expect("the run should have finished").unwrap_err();
+    assert!(err.to_string().contains("reviewist#"));
+    assert_eq!(task_count, 2);
+    assert!(later_run.unwrap().is_ok());
+}
+
+#[test]
+fn test_dry_run() {
+    let result = with_fake_server(|server, db| -> Result<_, Error> {
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        let dry_run = DryRun::new(&config.logger);
+        config.dry_run = Some(dry_run.clone());
+        config.once = true;
+        let run = core.run(within(reviewist::run(config.clone()), 5))?;
+
+        server.sender.send(Message::GetTaskCount).ok();
+        let task_count = match server.receiver.recv() {
+            Ok(Response::TaskCountResponse(count)) => count,
+            response => panic!("Unexpected response: {:?}", response),
+        };
+
+        config.dry_run = None;
+        let reviews = core.run(reviewist::list_reviews(&config, true))?;
+
+        Ok((run, task_count, reviews, dry_run.summary()))
+    });
+
+    let (run, task_count, reviews, summary) = result.unwrap();
+
+    assert!(run.unwrap().is_ok());
+    assert_eq!(task_count, 0);
+    assert!(reviews.is_empty());
+    assert!(summary.contains(&("create todoist task".to_string(), 1)));
+    assert!(summary.contains(&("record review request".to_string(), 1)));
+}
+
+#[test]
+fn test_dry_run_waiting_ci() {
+    let result = with_fake_server(|server, db| -> Result<_, Error> {
+        server.sender.send(Message::AddReviewRequest).ok();
+        server.sender.send(Message::SetCiState("failure".to_string())).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        let dry_run = DryRun::new(&config.logger);
+        config.dry_run = Some(dry_run.clone());
+        config.wait_for_ci = Some(chrono::Duration::hours(1));
+        config.once = true;
+        let run = core.run(within(reviewist::run(config), 5))?;
+
+        Ok((run, dry_run.summary()))
+    });
+
+    let (run, summary) = result.unwrap();
+
+    assert!(run.unwrap().is_ok());
+    assert!(summary.contains(&("record review request".to_string(), 1)));
+    assert!(!summary.iter().any(|(action, _)| action == "record CI failure"));
+}
+
+#[test]
+fn test_dry_run_retry() {
+    let result = with_fake_server(|server, db| -> Result<_, Error> {
+        server.sender.send(Message::AddReviewRequest).ok();
+        server.sender.send(Message::FailMatrixMessages(1)).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.matrix_base = Some(Url::parse(&format!("http://{}/matrix/", server.address)).unwrap());
+        core.run(time_limit(reviewist::run(config.clone()), 1))?;
+
+        let dry_run = DryRun::new(&config.logger);
+        config.dry_run = Some(dry_run.clone());
+        let retried = core.run(reviewist::retry_deliveries(&config))?;
+
+        let matrix_messages = matrix_message_count(&server);
+
+        config.dry_run = None;
+        let reviews = core.run(reviewist::list_reviews(&config, false))?;
+
+        Ok((retried, matrix_messages, reviews, dry_run.summary()))
+    });
+
+    let (retried, matrix_messages, reviews, summary) = result.unwrap();
+
+    assert_eq!(retried, 1);
+    assert_eq!(matrix_messages, 0);
+    assert_eq!(reviews[0].deliveries.len(), 1);
+    assert_eq!(reviews[0].failures.len(), 1);
+    assert!(summary.contains(&("send matrix m.room.message event".to_string(), 1)));
+    assert!(summary.contains(&("record delivery".to_string(), 1)));
+}
+
+#[test]
+fn test_backfill() {
+    let result = with_fake_server(|server, db| -> Result<_, Error> {
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.once = true;
+        core.run(reviewist::run(config.clone()))?;
+
+        // Only shows up in searches, like review requests from before the
+        // notifications go back
+        server.sender.send(Message::AddReviewRequest).ok();
+        let backfill = core.run(reviewist::find_missed_reviews(&config))?;
+        let missed: Vec<_> = backfill
+            .reviews
+            .iter()
+            .map(|review| review.pull_request.to_string())
+            .collect();
+        let created = core.run(backfill.create())?;
+
+        let second_backfill = core.run(reviewist::find_missed_reviews(&config))?;
+
+        server.sender.send(Message::GetTaskCount).ok();
+        let task_count = match server.receiver.recv() {
+            Ok(Response::TaskCountResponse(count)) => count,
+            response => panic!("Unexpected response: {:?}", response),
+        };
+
+        // Review requests that don't make it to any sink are reported
+        server.sender.send(Message::AddReviewRequest).ok();
+        config.todoist_base = Url::parse(&format!("http://{}/nowhere/", server.address)).unwrap();
+        let undelivered = core.run(reviewist::find_missed_reviews(&config))?;
+        let undelivered = core.run(undelivered.create())?;
+
+        Ok((missed, created, second_backfill.reviews.len(), task_count, undelivered))
+    });
+
+    let (missed, created, missed_after, task_count, undelivered) = result.unwrap();
+
+    assert_eq!(missed, vec!["renato-zannon/reviewist#1"]);
+    assert_eq!(created.created, 1);
+    assert!(created.failed.is_empty());
+    assert_eq!(missed_after, 0);
+    assert_eq!(task_count, 2);
+
+    let failed: Vec<_> = undelivered.failed.iter().map(|review| review.to_string()).collect();
+    assert_eq!(undelivered.created, 0);
+    assert_eq!(failed, vec!["renato-zannon/reviewist#2"]);
+}
+
+#[test]
+fn test_built_in_migrations() {
+    let (sqlite, postgres) = reviewist::built_in_migrations();
+
+    assert_eq!(sqlite, migration_dirs("migrations"));
+    assert_eq!(postgres, migration_dirs("migrations_postgres"));
+}
+
+#[test]
+fn test_migrations() {
+    let result = with_fake_server(|server, _| -> Result<_, Error> {
+        let db = new_unmigrated_database();
+        let core = Core::new().expect("failed to start tokio core");
+        let config = build_config(&core, &server, &db);
+
+        let before = reviewist::migration_status(&config)?;
+        let untouched = std::fs::metadata(&db.path)?.len() == 0;
+        let applied = reviewist::migrate(&config)?;
+        let after = reviewist::migration_status(&config)?;
+        let applied_again = reviewist::migrate(&config)?;
+
+        Ok((before, untouched, applied, after, applied_again))
+    });
+
+    let (before, untouched, applied, after, applied_again) = result.unwrap();
+
+    // Every migration in the directory is built in
+    let migrations = migration_dirs("migrations");
+
+    // Checking doesn't create anything in a new database
+    assert!(untouched);
+    assert!(before.applied.is_empty());
+    assert_eq!(before.pending, migrations);
+    assert_eq!(applied, migrations);
+    assert_eq!(after.applied, migrations);
+    assert!(after.pending.is_empty());
+    assert!(after.unknown.is_empty());
+    assert!(applied_again.is_empty());
+}
+
+/// Runs against the PostgreSQL database at `TEST_POSTGRES_URL`, which is
+/// emptied first. Skipped unless it's set.
+#[cfg(feature = "postgres")]
+#[test]
+fn test_postgres_storage() {
+    let database_url = match env::var("TEST_POSTGRES_URL") {
+        Ok(database_url) => database_url,
+        Err(_) => return,
+    };
+
+    let reset = Command::new("psql")
+        .args(&[
+            &database_url,
+            "-q",
+            "-c",
+            "SET client_min_messages TO WARNING; DROP SCHEMA public CASCADE; CREATE SCHEMA public;",
+        ])
+        .status()
+        .expect("failed to start psql");
+    assert!(reset.success());
+
+    let result = with_fake_server(move |server, db| -> Result<_, Error> {
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.database_url = database_url;
+        config.once = true;
+
+        let before = reviewist::migration_status(&config)?;
+        let applied = reviewist::migrate(&config)?;
+        let first_run = core.run(within(reviewist::run(config.clone()), 5))?;
+        let second_run = core.run(within(reviewist::run(config.clone()), 5))?;
+        let polls = if_modified_since(&server);
+
+        server.sender.send(Message::GetTaskCount).ok();
+        let task_count = match server.receiver.recv() {
+            Ok(Response::TaskCountResponse(count)) => count,
+            response => panic!("Unexpected response: {:?}", response),
+        };
+
+        let reviews = core.run(reviewist::list_reviews(&config, true))?;
+
+        Ok((before, applied, first_run, second_run, polls, task_count, reviews.len()))
+    });
+
+    let (before, applied, first_run, second_run, polls, task_count, review_count) = result.unwrap();
+
+    // Every migration in the directory is built in
+    let migrations = migration_dirs("migrations_postgres");
+
+    assert_eq!(migrations.len(), 1);
+    assert_eq!(before.pending, migrations);
+    assert_eq!(applied, migrations);
+    assert!(first_run.unwrap().is_ok());
+    assert!(second_run.unwrap().is_ok());
+    assert_eq!(polls.len(), 2);
+    assert_eq!(polls[1], fake_github::NOTIFICATIONS_LAST_MODIFIED);
+    assert_eq!(task_count, 1);
+    assert_eq!(review_count, 1);
+}
+
+#[test]
+fn test_rate_limit_pauses_requests() {
+    let result = with_fake_server(|server, db| {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        server.sender.send(Message::AddReviewRequest).ok();
+        server.sender.send(Message::SetRateLimitRemaining(0)).ok();
+
+        let future = build_main_future(&core, &server, &db);
+        let limited_future = time_limit(future, 1);
+
+        core.run(limited_future).map(move |_| {
+            server.sender.send(Message::GetTaskCount).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::TaskCountResponse(count)) => count,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let task_count = result.unwrap();
+    assert_eq!(task_count, 0);
+}
+
+#[test]
+fn test_rate_limited_requests_are_retried() {
+    let result = with_fake_server(|server, db| {
+        server.sender.send(Message::AddReviewRequest).ok();
+        server.sender.send(Message::RejectPullRequests(1)).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.once = true;
+
+        core.run(time_limit(reviewist::run(config), 5)).map(move |_| {
+            server.sender.send(Message::GetTaskCount).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::TaskCountResponse(count)) => count,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let task_count = result.unwrap();
+    assert_eq!(task_count, 1);
+}
+
+#[test]
+fn test_secondary_rate_limit_without_headers_pauses_requests() {
+    let result = with_fake_server(|server, db| {
+        server.sender.send(Message::AddReviewRequest).ok();
+        server.sender.send(Message::ForbidPullRequests(1)).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.once = true;
+
+        // The request waits out the backoff rather than failing right away
+        core.run(within(reviewist::run(config), 2))
+            .map(move |outcome| (outcome.is_none(), task_count(&server)))
+    });
+
+    let (still_running, task_count) = result.unwrap();
+    assert!(still_running);
+    assert_eq!(task_count, 0);
+}
+
+#[test]
+fn test_unchanged_pull_requests_come_from_cache() {
+    let result = with_fake_server(|server, db| {
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let future = build_main_future(&core, &server, &db);
+        core.run(time_limit(future, 1))?;
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let future = build_main_future(&core, &server, &db);
+
+        core.run(time_limit(future, 1)).map(move |_| {
+            server.sender.send(Message::GetNotModifiedCount).ok();
+            let not_modified_count = match server.receiver.recv() {
+                Ok(Response::NotModifiedCountResponse(count)) => count,
+                response => panic!("Unexpected response: {:?}", response),
+            };
+
+            server.sender.send(Message::GetTaskCount).ok();
+            match server.receiver.recv() {
+                Ok(Response::TaskCountResponse(count)) => (not_modified_count, count),
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let (not_modified_count, task_count) = result.unwrap();
+    assert!(not_modified_count > 0);
+    assert_eq!(task_count, 1);
+}
+
+#[test]
+fn test_notifications_marked_read_once_delivered() {
+    let result = with_fake_server(|server, db| {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut config = build_config(&core, &server, &db);
+        config.mark_notifications = Some(NotificationMark::Read);
+
+        let future = reviewist::run(config);
+        let limited_future = time_limit(future, 1);
+
+        core.run(limited_future).map(move |_| {
+            server.sender.send(Message::GetMarkedNotifications).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::MarkedNotificationsResponse(thread_ids)) => thread_ids,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let thread_ids = result.unwrap();
+    assert_eq!(thread_ids, vec!["0".to_string()]);
+}
+
+#[test]
+fn test_search_source() {
+    let result = with_fake_server(|server, db| {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        server.sender.send(Message::AddReviewRequest).ok();
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut config = build_config(&core, &server, &db);
+        config.review_source = ReviewSource::Search;
+
+        let future = reviewist::run(config);
+        let limited_future = time_limit(future, 1);
+
+        core.run(limited_future).map(move |_| {
+            server.sender.send(Message::GetTaskCount).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::TaskCountResponse(count)) => count,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let task_count = result.unwrap();
+    assert_eq!(task_count, 2);
+}
+
+#[test]
+fn test_failed_search_delivers_nothing() {
+    let result = with_fake_server(|server, db| -> Result<_, Error> {
+        server.sender.send(Message::AddReviewRequest).ok();
+        server.sender.send(Message::AddReviewRequest).ok();
+        server.sender.send(Message::FailSearchPage(1)).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.review_source = ReviewSource::Search;
+        config.once = true;
+        let run = core.run(within(reviewist::run(config), 5))?;
+
+        server.sender.send(Message::GetTaskCount).ok();
+        let task_count = match server.receiver.recv() {
+            Ok(Response::TaskCountResponse(count)) => count,
+            response => panic!("Unexpected response: {:?}", response),
+        };
+
+        Ok((run, task_count))
+    });
+
+    let (run, task_count) = result.unwrap();
+    assert!(run.expect("the run should have finished").is_err());
+    assert_eq!(task_count, 0);
+}
+
+#[test]
+fn test_search_finishes_closed_pull_requests() {
+    let result = with_fake_server(|server, db| -> Result<_, Error> {
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.review_source = ReviewSource::Search;
+        config.once = true;
+        core.run(within(reviewist::run(config.clone()), 5))?
+            .expect("the run should have finished")?;
+        let open_before = core.run(reviewist::list_reviews(&config, false))?.len();
+
+        server.sender.send(Message::ClosePullRequests).ok();
+        core.run(within(reviewist::run(config.clone()), 5))?
+            .expect("the run should have finished")?;
+        let open_after = core.run(reviewist::list_reviews(&config, false))?.len();
+
+        Ok((open_before, open_after))
+    });
+
+    let (open_before, open_after) = result.unwrap();
+    assert_eq!(open_before, 1);
+    assert_eq!(open_after, 0);
+}
+
+#[test]
+fn test_search_merged_with_notifications() {
+    let result = with_fake_server(|server, db| {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        server.sender.send(Message::AddReviewRequest).ok();
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut config = build_config(&core, &server, &db);
+        config.review_source = ReviewSource::Both;
+
+        let future = reviewist::run(config);
+        let limited_future = time_limit(future, 1);
+
+        core.run(limited_future).map(move |_| {
+            server.sender.send(Message::GetTaskCount).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::TaskCountResponse(count)) => count,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let task_count = result.unwrap();
+    assert_eq!(task_count, 2);
+}
+
+#[test]
+fn test_enterprise_api_paths() {
+    let result = with_fake_server(|server, db| {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        server.sender.send(Message::AddReviewRequest).ok();
+        server.sender.send(Message::AddReviewRequest).ok();
 
-use fake_github::{Message, Response};
-use ipc_channel::ipc;
-use reviewist::Config;
-use std::env;
-use url::Url;
+        // The GraphQL endpoint is worked out from the REST API's path, like
+        // on GitHub Enterprise Server
+        let mut config = build_config(&core, &server, &db);
+        config.github_base = Url::parse(&format!("http://{}/ghe/api/v3/", server.address)).unwrap();
+        config.review_source = ReviewSource::Both;
+
+        let future = reviewist::run(config);
+        let limited_future = time_limit(future, 1);
+
+        core.run(limited_future).map(move |_| task_count(&server))
+    });
+
+    let task_count = result.unwrap();
+    assert_eq!(task_count, 2);
+}
 
 #[test]
-fn test_one_pr() {
+fn test_no_proxy_hosts_skip_the_proxy() {
     let result = with_fake_server(|server, db| {
         let mut core = Core::new().expect("failed to start tokio core");
 
         server.sender.send(Message::AddReviewRequest).ok();
 
-        let future = build_main_future(&core, &server, &db);
+        // Nothing listens on the proxy, so only requests that skip it go
+        // through
+        let mut config = build_config(&core, &server, &db);
+        config.http.proxy = Some(Url::parse("http://proxy.invalid:3128/").unwrap());
+        config.http.no_proxy = vec![server.address.ip().to_string()];
+
+        let future = reviewist::run(config);
+        let limited_future = time_limit(future, 1);
+
+        core.run(limited_future).map(move |_| task_count(&server))
+    });
+
+    let task_count = result.unwrap();
+    assert_eq!(task_count, 1);
+}
+
+#[test]
+fn test_github_app_authentication() {
+    let result = with_fake_server(|server, db| {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut config = build_config(&core, &server, &db);
+        let app = GithubApp {
+            app_id: 42,
+            installation_id: 7,
+            private_key: include_str!("fixtures/github_app_key.pem").to_string(),
+            user: UserCredentials::Token("user-token".to_string()),
+        };
+        config.github_accounts = vec![GithubAccount::new(DEFAULT_ACCOUNT, GithubCredentials::App(app))];
+
+        let future = reviewist::run(config);
         let limited_future = time_limit(future, 1);
 
         core.run(limited_future).map(move |_| {
-            server.sender.send(Message::GetTaskCount).ok();
+            server.sender.send(Message::GetAuthorizations).ok();
 
             match server.receiver.recv() {
-                Ok(Response::TaskCountResponse(count)) => count,
+                Ok(Response::AuthorizationsResponse(authorizations)) => authorizations,
                 response => panic!("Unexpected response: {:?}", response),
             }
         })
     });
 
-    let task_count = result.unwrap();
-    assert_eq!(task_count, 1);
+    let authorizations = result.unwrap();
+    let used_for = |endpoint: &str| -> Vec<String> {
+        authorizations
+            .iter()
+            .filter(|authorization| authorization.0 == endpoint)
+            .map(|authorization| authorization.1.clone())
+            .collect()
+    };
+
+    let token_requests = used_for("installation_token");
+    assert_eq!(token_requests.len(), 1);
+    assert!(token_requests[0].starts_with("Bearer "));
+
+    assert!(!used_for("notifications").is_empty());
+    assert!(used_for("notifications").iter().all(|auth| auth == "token user-token"));
+
+    assert!(!used_for("pull_request").is_empty());
+    assert!(used_for("pull_request")
+        .iter()
+        .all(|auth| auth == "token installation-token"));
 }
 
 #[test]
-fn test_multiple_prs() {
-    const PR_COUNT: usize = 10;
+fn test_github_app_user_token_refresh() {
+    let refresh_token_path = env::temp_dir().join(format!("reviewist-refresh-token-{}", std::process::id()));
+    std::fs::write(&refresh_token_path, "refresh\n").unwrap();
 
-    let result = with_fake_server(|server, db| {
+    let path = refresh_token_path.clone();
+    let result = with_fake_server(move |server, db| {
         let mut core = Core::new().expect("failed to start tokio core");
 
-        for _ in 0..PR_COUNT {
-            server.sender.send(Message::AddReviewRequest).ok();
-        }
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut config = build_config(&core, &server, &db);
+        let app = GithubApp {
+            app_id: 42,
+            installation_id: 7,
+            private_key: include_str!("fixtures/github_app_key.pem").to_string(),
+            user: UserCredentials::Refreshed(UserTokenRefresh {
+                client_id: "client".to_string(),
+                client_secret: "secret".to_string(),
+                oauth_base: Url::parse(&format!("http://{}/github/", server.address)).unwrap(),
+                refresh_token_path: path,
+            }),
+        };
+        config.github_accounts = vec![GithubAccount::new(DEFAULT_ACCOUNT, GithubCredentials::App(app))];
+
+        let future = reviewist::run(config);
+        let limited_future = time_limit(future, 1);
+
+        core.run(limited_future).map(move |_| {
+            server.sender.send(Message::GetAuthorizations).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::AuthorizationsResponse(authorizations)) => authorizations,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    let authorizations = result.unwrap();
+    let notifications: Vec<_> = authorizations
+        .iter()
+        .filter(|authorization| authorization.0 == "notifications")
+        .collect();
+
+    assert!(!notifications.is_empty());
+    assert!(notifications.iter().all(|auth| auth.1 == "token refresh-token"));
+
+    // The used refresh token was replaced with the new one
+    let refresh_token = std::fs::read_to_string(&refresh_token_path).unwrap();
+    std::fs::remove_file(&refresh_token_path).ok();
+    assert_eq!(refresh_token, "refresh-next");
+}
+
+#[test]
+fn test_cached_responses_are_kept_per_account() {
+    let result = with_fake_server(|server, db| {
+        server.sender.send(Message::AddReviewRequest).ok();
 
+        let mut core = Core::new().expect("failed to start tokio core");
         let future = build_main_future(&core, &server, &db);
+        core.run(time_limit(future, 1))?;
+
+        let mut core = Core::new().expect("failed to start tokio core");
+        let mut config = build_config(&core, &server, &db);
+        config.github_accounts = vec![GithubAccount::new(
+            "other",
+            GithubCredentials::Token("other-token".to_string()),
+        )];
+
+        core.run(time_limit(reviewist::run(config), 1)).map(move |_| {
+            server.sender.send(Message::GetNotModifiedCount).ok();
+
+            match server.receiver.recv() {
+                Ok(Response::NotModifiedCountResponse(count)) => count,
+                response => panic!("Unexpected response: {:?}", response),
+            }
+        })
+    });
+
+    // The other account fetches the pull request without the first one's etag
+    let not_modified_count = result.unwrap();
+    assert_eq!(not_modified_count, 0);
+}
+
+#[test]
+fn test_multiple_github_accounts() {
+    let result = with_fake_server(|server, db| {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut config = build_config(&core, &server, &db);
+        config.github_accounts = vec![
+            GithubAccount {
+                todoist_project: Some("Code Review".to_string()),
+                ..GithubAccount::new("work", GithubCredentials::Token("work-token".to_string()))
+            },
+            GithubAccount::new("personal", GithubCredentials::Token("personal-token".to_string())),
+        ];
+
+        let future = reviewist::run(config);
         let limited_future = time_limit(future, 1);
 
         core.run(limited_future).map(move |_| {
-            server.sender.send(Message::GetTaskCount).ok();
+            server.sender.send(Message::GetTasks).ok();
+            let tasks = match server.receiver.recv() {
+                Ok(Response::TasksResponse(tasks)) => tasks,
+                response => panic!("Unexpected response: {:?}", response),
+            };
+
+            server.sender.send(Message::GetAuthorizations).ok();
+            let authorizations = match server.receiver.recv() {
+                Ok(Response::AuthorizationsResponse(authorizations)) => authorizations,
+                response => panic!("Unexpected response: {:?}", response),
+            };
+
+            (tasks, authorizations)
+        })
+    });
+
+    let (tasks, authorizations) = result.unwrap();
+
+    // The same pull request is tracked once per account
+    let mut project_ids: Vec<_> = tasks
+        .iter()
+        .map(|task| serde_json::from_str::<serde_json::Value>(task).unwrap()["project_id"].clone())
+        .collect();
+    project_ids.sort_by_key(|project_id| project_id.to_string());
+    assert_eq!(project_ids, vec![json!(101), serde_json::Value::Null]);
+
+    let polled_with = |token: &str| {
+        authorizations
+            .iter()
+            .any(|authorization| authorization.0 == "notifications" && authorization.1 == format!("token {}", token))
+    };
+
+    assert!(polled_with("work-token"));
+    assert!(polled_with("personal-token"));
+}
+
+#[test]
+fn test_server_mode() {
+    let result = with_fake_server(|server, db| {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        add_test_user(&mut core, &server, &db, "alice");
+        add_test_user(&mut core, &server, &db, "bob");
+
+        let mut config = build_config(&core, &server, &db);
+        config.github_accounts = vec![];
+        config.secret_key = Some(SecretKey::from_base64(TEST_SECRET_KEY).unwrap());
+
+        let future = reviewist::run_server(config);
+        let limited_future = time_limit(future, 1);
+
+        core.run(limited_future).map(move |_| {
+            server.sender.send(Message::GetAuthorizations).ok();
+            let authorizations = match server.receiver.recv() {
+                Ok(Response::AuthorizationsResponse(authorizations)) => authorizations,
+                response => panic!("Unexpected response: {:?}", response),
+            };
+
+            let stored = std::fs::read(&db.path).unwrap();
+            (authorizations, String::from_utf8_lossy(&stored).into_owned())
+        })
+    });
+
+    let (authorizations, stored) = result.unwrap();
+    let used_for = |endpoint: &str| -> Vec<String> {
+        let mut used: Vec<_> = authorizations
+            .iter()
+            .filter(|authorization| authorization.0 == endpoint)
+            .map(|authorization| authorization.1.clone())
+            .collect();
+
+        used.sort();
+        used.dedup();
+        used
+    };
+
+    // Each user gets a task of their own, made with their own credentials
+    assert_eq!(used_for("task"), vec!["Bearer alice-todoist", "Bearer bob-todoist"]);
+    assert_eq!(used_for("notifications"), vec!["token alice-github", "token bob-github"]);
+    assert_eq!(authorizations.iter().filter(|auth| auth.0 == "task").count(), 2);
+
+    assert!(!stored.contains("alice-github"));
+    assert!(!stored.contains("alice-todoist"));
+}
+
+#[test]
+fn test_onboarding() {
+    let result = with_fake_server(|server, db| {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let listen = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+
+        let oauth_app = |provider: &str| OauthApp {
+            client_id: format!("{}-client", provider),
+            client_secret: format!("{}-secret", provider),
+            base: Url::parse(&format!("http://{}/{}/", server.address, provider)).unwrap(),
+        };
+
+        let mut config = build_config(&core, &server, &db);
+        config.github_accounts = vec![];
+        config.secret_key = Some(SecretKey::from_base64(TEST_SECRET_KEY).unwrap());
+        config.onboarding = Some(OnboardingSettings {
+            listen,
+            public_url: Url::parse(&format!("http://{}/", listen)).unwrap(),
+            github: Some(oauth_app("github")),
+            todoist: Some(oauth_app("todoist")),
+        });
+
+        // Goes through both providers like a browser would, then tries to
+        // reuse the GitHub callback, and to connect Todoist without GitHub
+        let browser = Browser::new(&core);
+        let stranger = Browser::new(&core);
+        let page = move |path: &str| format!("http://{}/{}", listen, path);
+
+        let statuses = Rc::new(RefCell::new(vec![]));
+        let visited = statuses.clone();
+        let visit = move |browser: &Browser, url: String| {
+            let visited = visited.clone();
+            browser.visit(url).map(move |(status, url)| {
+                visited.borrow_mut().push(status);
+                url
+            })
+        };
+
+        let onboarding = Delay::new(Instant::now() + Duration::from_millis(100))
+            .map_err(Error::from)
+            .and_then({
+                let (browser, visit) = (browser.clone(), visit.clone());
+                move |_| visit(&browser, page("onboard/github"))
+            })
+            .and_then({
+                let (browser, visit) = (browser.clone(), visit.clone());
+                move |callback| visit(&browser, page("onboard/todoist")).map(move |_| callback)
+            })
+            .and_then({
+                let visit = visit.clone();
+                move |callback| visit(&browser, callback.clone()).map(move |_| callback)
+            })
+            .and_then({
+                let (stranger, visit) = (stranger.clone(), visit.clone());
+                move |callback| visit(&stranger, page("onboard/todoist")).map(move |_| callback)
+            })
+            .and_then(move |callback| visit(&stranger, callback));
+
+        let future = reviewist::run_server(config).join(onboarding);
+        let limited_future = time_limit(future, 2);
+
+        core.run(limited_future).map(move |_| {
+            let authorizations = authorizations(&server);
+
+            let statuses = statuses.borrow().clone();
+            (authorizations, statuses)
+        })
+    });
+
+    let (authorizations, statuses) = result.unwrap();
+
+    // Each state works once, in the browser it was made for, and only users
+    // who connected GitHub can connect Todoist
+    assert_eq!(
+        statuses,
+        vec![
+            StatusCode::Ok,
+            StatusCode::Ok,
+            StatusCode::BadRequest,
+            StatusCode::Unauthorized,
+            StatusCode::BadRequest,
+        ]
+    );
+
+    // The user is named after their GitHub login, and served with the tokens
+    // the providers handed out
+    assert_eq!(
+        tokens_used_for(&authorizations, "notifications"),
+        vec!["token github-code-token"]
+    );
+    assert_eq!(
+        tokens_used_for(&authorizations, "task"),
+        vec!["Bearer todoist-code-token"]
+    );
+    assert_eq!(
+        tokens_used_for(&authorizations, "user"),
+        vec!["token github-code-token"]
+    );
+}
+
+#[test]
+fn test_due_date_skips_non_working_days() {
+    let result = with_fake_server(|server, db| {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut config = build_config(&core, &server, &db);
+        config.working_hours = Some(weekday_working_hours(false));
+        config.clock = Clock::Fixed(saturday_noon());
+
+        let future = reviewist::run(config);
+        let limited_future = time_limit(future, 1);
+
+        core.run(limited_future).map(move |_| {
+            server.sender.send(Message::GetLastTask).ok();
 
             match server.receiver.recv() {
-                Ok(Response::TaskCountResponse(count)) => count,
+                Ok(Response::LastTaskResponse(Some(task))) => task,
                 response => panic!("Unexpected response: {:?}", response),
             }
         })
     });
 
-    let task_count = result.unwrap();
-    assert_eq!(task_count, PR_COUNT);
+    let task: serde_json::Value = serde_json::from_str(&result.unwrap()).unwrap();
+    assert_eq!(task["due_date"], "2018-07-30");
+    assert_eq!(task["due_string"], serde_json::Value::Null);
+}
+
+#[test]
+fn test_hold_outside_working_hours() {
+    let result = with_fake_server(|server, db| -> Result<_, Error> {
+        let mut core = Core::new().expect("failed to start tokio core");
+
+        server.sender.send(Message::AddReviewRequest).ok();
+
+        let mut config = build_config(&core, &server, &db);
+        config.working_hours = Some(weekday_working_hours(true));
+        config.clock = Clock::Fixed(saturday_noon());
+        core.run(time_limit(reviewist::run(config.clone()), 1))?;
+
+        server.sender.send(Message::GetTaskCount).ok();
+        let held_task_count = match server.receiver.recv() {
+            Ok(Response::TaskCountResponse(count)) => count,
+            response => panic!("Unexpected response: {:?}", response),
+        };
+
+        // Held requests are released once due, even if holding was turned
+        // off in the meantime
+        config.working_hours = None;
+        config.clock = Clock::Fixed(Utc.ymd(2018, 7, 30).and_hms(10, 0, 0));
+        core.run(time_limit(reviewist::run(config), 1))?;
+
+        server.sender.send(Message::GetTaskCount).ok();
+        let released_task_count = match server.receiver.recv() {
+            Ok(Response::TaskCountResponse(count)) => count,
+            response => panic!("Unexpected response: {:?}", response),
+        };
+
+        Ok((held_task_count, released_task_count))
+    });
+
+    let (held_task_count, released_task_count) = result.unwrap();
+    assert_eq!(held_task_count, 0);
+    assert_eq!(released_task_count, 1);
+}
+
+/// Outside of the working hours of `weekday_working_hours`, which start again
+/// on Monday, 2018-07-30
+fn saturday_noon() -> DateTime<Utc> {
+    Utc.ymd(2018, 7, 28).and_hms(12, 0, 0)
+}
+
+fn weekday_working_hours(hold_outside_hours: bool) -> WorkingHours {
+    WorkingHours {
+        timezone: chrono_tz::UTC,
+        days: vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri],
+        start: NaiveTime::from_hms(9, 0, 0),
+        end: NaiveTime::from_hms(18, 0, 0),
+        holidays: vec![],
+        hold_outside_hours,
+    }
+}
+
+/// Asks the fake server for something it recorded.
+fn fake_request(server: &FakeServer, message: Message) -> Response {
+    server.sender.send(message).ok();
+    server.receiver.recv().expect("the fake server stopped answering")
+}
+
+fn task_count(server: &FakeServer) -> usize {
+    match fake_request(server, Message::GetTaskCount) {
+        Response::TaskCountResponse(count) => count,
+        response => panic!("Unexpected response: {:?}", response),
+    }
+}
+
+fn matrix_message_count(server: &FakeServer) -> usize {
+    match fake_request(server, Message::GetMatrixMessageCount) {
+        Response::MatrixMessageCountResponse(count) => count,
+        response => panic!("Unexpected response: {:?}", response),
+    }
+}
+
+fn if_modified_since(server: &FakeServer) -> Vec<String> {
+    server.sender.send(Message::GetIfModifiedSince).ok();
+
+    match server.receiver.recv() {
+        Ok(Response::IfModifiedSinceResponse(dates)) => dates,
+        response => panic!("Unexpected response: {:?}", response),
+    }
+}
+
+fn authorizations(server: &FakeServer) -> Vec<(String, String)> {
+    match fake_request(server, Message::GetAuthorizations) {
+        Response::AuthorizationsResponse(authorizations) => authorizations,
+        response => panic!("Unexpected response: {:?}", response),
+    }
+}
+
+/// The distinct Authorization headers an endpoint of the fake server was
+/// called with, sorted.
+fn tokens_used_for(authorizations: &[(String, String)], endpoint: &str) -> Vec<String> {
+    let mut used: Vec<_> = authorizations
+        .iter()
+        .filter(|authorization| authorization.0 == endpoint)
+        .map(|authorization| authorization.1.clone())
+        .collect();
+
+    used.sort();
+    used.dedup();
+    used
+}
+
+/// Stores a user for server mode, with credentials made from their name
+fn add_test_user(core: &mut Core, server: &FakeServer, db: &DatabasePath, name: &str) {
+    let added = {
+        let mut config = build_config(core, server, db);
+        config.secret_key = Some(SecretKey::from_base64(TEST_SECRET_KEY).unwrap());
+
+        let github_token = format!("{}-github", name);
+        let todoist_token = format!("{}-todoist", name);
+        reviewist::add_user(&config, name, Some(&github_token), Some(&todoist_token))
+    };
+
+    core.run(added).unwrap();
+}
+
+/// The migrations in `dir`, sorted by name.
+fn migration_dirs(dir: &str) -> Vec<String> {
+    let mut migrations: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    migrations.sort();
+    migrations
 }
 
 fn build_main_future(core: &Core, server: &FakeServer, db: &DatabasePath) -> impl Future<Item = (), Error = Error> {
-    reviewist::run(Config {
+    reviewist::run(build_config(core, server, db))
+}
+
+fn build_config(core: &Core, server: &FakeServer, db: &DatabasePath) -> Config {
+    Config {
         logger: configure_slog(),
-        core: &core,
+        handle: core.handle(),
         github_base: Url::parse(&format!("http://{}/github/", server.address)).unwrap(),
+        github_graphql_url: None,
+        github_accounts: vec![GithubAccount::new(
+            DEFAULT_ACCOUNT,
+            GithubCredentials::Token("lol123".to_string()),
+        )],
+        http: HttpSettings::default(),
         todoist_base: Url::parse(&format!("http://{}/todoist/", server.address)).unwrap(),
+        todoist: TodoistSettings::default(),
+        task_rules: TaskRules::default(),
+        working_hours: None,
+        clock: Clock::System,
+        wait_for_ci: None,
+        reminders: None,
+        once: false,
+        dry_run: None,
+        look_back: None,
+        mark_notifications: None,
+        review_source: ReviewSource::Notifications,
+        matrix_base: None,
+        desktop_notifications: false,
         database_url: db.fd_path(),
-    })
+        secret_key: None,
+        onboarding: None,
+    }
 }
 
+/// 32 bytes, in base64
+const TEST_SECRET_KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
 struct FakeServer {
     receiver: ipc::IpcReceiver<Response>,
     sender: ipc::IpcSender<Message>,
@@ -108,6 +1685,8 @@ fn with_fake_server<T>(f: impl FnOnce(FakeServer, DatabasePath) -> T) -> T {
     let db_path = new_database();
     env::set_var("TODOIST_TOKEN", "lol123");
     env::set_var("GITHUB_TOKEN", "lol123");
+    env::set_var("MATRIX_ACCESS_TOKEN", "lol123");
+    env::set_var("MATRIX_ROOM_ID", "!reviews:localhost");
 
     let (receiver, message) = server.accept().unwrap();
     let (address, sender) = match message {
@@ -128,6 +1707,64 @@ fn with_fake_server<T>(f: impl FnOnce(FakeServer, DatabasePath) -> T) -> T {
     result
 }
 
+/// Follows redirects like a browser, keeping the cookies it's given.
+#[derive(Clone)]
+struct Browser {
+    http: reqwest::unstable::async::Client,
+    cookies: Rc<RefCell<HashMap<String, String>>>,
+}
+
+impl Browser {
+    fn new(core: &Core) -> Browser {
+        let http = reqwest::unstable::async::Client::builder()
+            .redirect(reqwest::RedirectPolicy::none())
+            .build(&core.handle())
+            .unwrap();
+
+        Browser {
+            http,
+            cookies: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+
+    /// Resolves to the status and URL of the page `url` ends up on.
+    fn visit(&self, url: String) -> impl Future<Item = (StatusCode, String), Error = Error> {
+        let browser = self.clone();
+
+        future::loop_fn(url, move |url| {
+            let mut cookie = Cookie::new();
+            for (name, value) in browser.cookies.borrow().iter() {
+                cookie.set(name.clone(), value.clone());
+            }
+
+            let cookies = browser.cookies.clone();
+
+            browser
+                .http
+                .get(url.as_str())
+                .header(cookie)
+                .send()
+                .map_err(Error::from)
+                .map(move |response| {
+                    let set_cookies = response
+                        .headers()
+                        .get::<SetCookie>()
+                        .map(|set_cookie| set_cookie.0.clone());
+                    for set_cookie in set_cookies.unwrap_or_default() {
+                        let mut pair = set_cookie.split(';').next().unwrap().splitn(2, '=');
+                        let name = pair.next().unwrap().to_string();
+                        cookies.borrow_mut().insert(name, pair.next().unwrap_or("").to_string());
+                    }
+
+                    match response.headers().get::<Location>() {
+                        Some(location) => future::Loop::Continue(location.to_string()),
+                        None => future::Loop::Break((response.status(), url)),
+                    }
+                })
+        })
+    }
+}
+
 fn time_limit<F>(future: F, seconds: u64) -> impl Future<Item = (), Error = Error>
 where
     F: Future<Error = Error>,
@@ -141,6 +1778,22 @@ where
     })
 }
 
+/// Runs `future` to completion, unless it takes longer than `seconds`. Its
+/// outcome is given back as is, to check the errors of runs that end.
+fn within<F>(future: F, seconds: u64) -> impl Future<Item = Option<Result<F::Item, Error>>, Error = Error>
+where
+    F: Future<Error = Error>,
+{
+    let delay = Delay::new(Instant::now() + Duration::from_secs(seconds));
+
+    future.then(|result| Ok(Some(result))).select2(delay).then(|result| match result {
+        Ok(Either::A((outcome, _))) => Ok(outcome),
+        Ok(Either::B(_)) => Ok(None),
+        Err(Either::A((err, _))) => Err(err),
+        Err(Either::B((err, _))) => Err(Error::from(err)),
+    })
+}
+
 fn configure_slog() -> slog::Logger {
     slog::Logger::root(slog::Discard, o!())
 }
@@ -164,17 +1817,11 @@ impl Drop for DatabasePath {
 }
 
 fn new_database() -> DatabasePath {
-    use nix::unistd::mkstemp;
-
-    let (fd, path) = mkstemp("/tmp/reviewist_test.db.XXXXXX").unwrap();
-    let db_path = DatabasePath {
-        fd,
-        path: path.to_string_lossy().into_owned(),
-    };
+    let db_path = new_unmigrated_database();
 
     Command::new("cargo")
         .env("DATABASE_URL", db_path.fd_path())
-        .args(&["run", "--release", "--bin", "reviewist_migrate"])
+        .args(&["run", "--release", "--bin", "reviewist", "--", "migrate"])
         .stdin(Stdio::null())
         .stdout(Stdio::null())
         .stderr(Stdio::null())
@@ -183,3 +1830,14 @@ fn new_database() -> DatabasePath {
 
     return db_path;
 }
+
+fn new_unmigrated_database() -> DatabasePath {
+    use nix::unistd::mkstemp;
+
+    let (fd, path) = mkstemp("/tmp/reviewist_test.db.XXXXXX").unwrap();
+
+    DatabasePath {
+        fd,
+        path: path.to_string_lossy().into_owned(),
+    }
+}